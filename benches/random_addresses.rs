@@ -0,0 +1,30 @@
+//! Generates 1000 addresses with the chain's shared secp256k1 context, and with a new
+//! context for each address, like `get_random_address` used to do. Run with `cargo bench`.
+
+use bitcoin::{secp256k1::Secp256k1, Address, Network, PrivateKey};
+use example_libfloresta::FlorestaChain;
+use std::time::Instant;
+
+const ADDRESSES: u32 = 1000;
+
+fn main() {
+    let chain = unsafe { FlorestaChain::new() };
+    let start = Instant::now();
+    for _ in 0..ADDRESSES {
+        unsafe { chain.get_random_address() }.unwrap();
+    }
+    let shared = start.elapsed();
+
+    let start = Instant::now();
+    for i in 0..ADDRESSES {
+        let secp = Secp256k1::new();
+        let mut key = [1u8; 32];
+        key[..4].copy_from_slice(&i.to_be_bytes());
+        let key = PrivateKey::from_slice(&key, Network::Signet).unwrap();
+        Address::p2wpkh(&key.public_key(&secp), Network::Signet).unwrap();
+    }
+    let fresh = start.elapsed();
+
+    println!("{ADDRESSES} addresses, shared context: {shared:?}");
+    println!("{ADDRESSES} addresses, new context each: {fresh:?}");
+}
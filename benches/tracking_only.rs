@@ -0,0 +1,119 @@
+//! Accepts the same blocks with a validating chain and a tracking only one, both built from
+//! a snapshot with an easy proof of work limit, and compares how long they take per block.
+//! Each block only has a coinbase with many outputs, so the validating chain adds a leaf
+//! for each of them. Run with `cargo bench`.
+
+use bitcoin::{
+    blockdata::{opcodes, script::Builder},
+    consensus::serialize,
+    hashes::Hash,
+    Block, BlockHash, BlockHeader, OutPoint, PackedLockTime, Sequence, Transaction, TxIn,
+    TxMerkleNode, TxOut, Witness,
+};
+use example_libfloresta::FlorestaChain;
+use std::time::{Duration, Instant};
+
+const BLOCKS: u32 = 50;
+const OUTPUTS: u32 = 2000;
+const SNAPSHOT_HEIGHT: u32 = 1000;
+const EASY_POW_LIMIT: &str = "7fffff0000000000000000000000000000000000000000000000000000000000";
+
+fn mine(header: &mut BlockHeader) {
+    while header.validate_pow(&header.target()).is_err() {
+        header.nonce += 1;
+    }
+}
+
+fn block(prev: &BlockHeader, height: u32) -> Block {
+    let coinbase = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(height as i64)
+                .push_int(0)
+                .into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: (0..OUTPUTS)
+            .map(|i| TxOut {
+                value: 0,
+                script_pubkey: Builder::new()
+                    .push_opcode(opcodes::OP_TRUE)
+                    .push_slice(&i.to_be_bytes())
+                    .into_script(),
+            })
+            .collect(),
+    };
+    let mut block = Block {
+        header: BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: prev.time + 600,
+            bits: prev.bits,
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    mine(&mut block.header);
+    block
+}
+
+fn run(tracking_only: bool, snapshot: &BlockHeader, blocks: &[String]) -> Duration {
+    let mut chain = unsafe {
+        FlorestaChain::build_chain_from(
+            snapshot.block_hash().to_string(),
+            SNAPSHOT_HEIGHT,
+            hex::encode(serialize(snapshot)),
+            None,
+            Some(format!(r#"{{"pow_limit": "{EASY_POW_LIMIT}"}}"#)),
+            Some(tracking_only),
+        )
+    }
+    .unwrap();
+    let start = Instant::now();
+    for block in blocks {
+        unsafe { chain.accept_block(block.clone(), None) }.unwrap();
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let mut snapshot = BlockHeader {
+        version: 0x2000_0000,
+        prev_blockhash: BlockHash::all_zeros(),
+        merkle_root: TxMerkleNode::all_zeros(),
+        time: 1_700_000_000,
+        bits: 0x207f_ffff,
+        nonce: 0,
+    };
+    mine(&mut snapshot);
+    let mut prev = snapshot;
+    let blocks = (1..=BLOCKS)
+        .map(|i| {
+            let block = block(&prev, SNAPSHOT_HEIGHT + i);
+            prev = block.header;
+            serde_json::json!({
+                "block": block,
+                "proof": { "targets": [], "hashes": [] },
+                "leaf_data": [],
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+
+    let validating = run(false, &snapshot, &blocks);
+    let tracking = run(true, &snapshot, &blocks);
+    println!(
+        "{BLOCKS} blocks with {OUTPUTS} outputs, validating: {:?} per block",
+        validating / BLOCKS
+    );
+    println!(
+        "{BLOCKS} blocks with {OUTPUTS} outputs, tracking only: {:?} per block",
+        tracking / BLOCKS
+    );
+}
@@ -0,0 +1,132 @@
+//! Rescans blocks with 4000 outputs each while watching 10k scripts, which only runs the
+//! wallet matching, no validation. A few outputs of every block pay to a watched script.
+//! The blocks are connected by a tracking only chain built from a snapshot with an easy proof
+//! of work limit, so setting up is cheap. Run with `cargo bench`.
+
+use bitcoin::{
+    blockdata::script::Builder, consensus::serialize, hashes::Hash, Address, Block, BlockHash,
+    BlockHeader, Network, OutPoint, PackedLockTime, Script, Sequence, Transaction, TxIn,
+    TxMerkleNode, TxOut, WPubkeyHash, Witness,
+};
+use example_libfloresta::FlorestaChain;
+use std::time::Instant;
+
+const BLOCKS: u32 = 20;
+const OUTPUTS: u32 = 4000;
+const WATCHED: u32 = 10_000;
+/// One output out of this many in each block is ours
+const MATCH_EVERY: u32 = 500;
+const SNAPSHOT_HEIGHT: u32 = 1000;
+const EASY_POW_LIMIT: &str = "7fffff0000000000000000000000000000000000000000000000000000000000";
+
+fn mine(header: &mut BlockHeader) {
+    while header.validate_pow(&header.target()).is_err() {
+        header.nonce += 1;
+    }
+}
+
+/// A distinct p2wpkh script for each seed
+fn script(seed: u32) -> Script {
+    Script::new_v0_p2wpkh(&WPubkeyHash::hash(&seed.to_be_bytes()))
+}
+
+fn block(prev: &BlockHeader, height: u32, first_seed: u32) -> Block {
+    let coinbase = Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input: vec![TxIn {
+            previous_output: OutPoint::null(),
+            script_sig: Builder::new()
+                .push_int(height as i64)
+                .push_int(0)
+                .into_script(),
+            sequence: Sequence::MAX,
+            witness: Witness::new(),
+        }],
+        output: (first_seed..first_seed + OUTPUTS)
+            .map(|seed| TxOut {
+                value: 1,
+                script_pubkey: script(seed),
+            })
+            .collect(),
+    };
+    let mut block = Block {
+        header: BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: prev.time + 600,
+            bits: prev.bits,
+            nonce: 0,
+        },
+        txdata: vec![coinbase],
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    mine(&mut block.header);
+    block
+}
+
+fn main() {
+    let mut snapshot = BlockHeader {
+        version: 0x2000_0000,
+        prev_blockhash: BlockHash::all_zeros(),
+        merkle_root: TxMerkleNode::all_zeros(),
+        time: 1_700_000_000,
+        bits: 0x207f_ffff,
+        nonce: 0,
+    };
+    mine(&mut snapshot);
+    let mut prev = snapshot;
+    let blocks = (0..BLOCKS)
+        .map(|i| {
+            let block = block(&prev, SNAPSHOT_HEIGHT + i + 1, i * OUTPUTS);
+            prev = block.header;
+            serde_json::json!({
+                "block": block,
+                "proof": { "targets": [], "hashes": [] },
+                "leaf_data": [],
+            })
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+
+    let mut chain = unsafe {
+        FlorestaChain::build_chain_from(
+            snapshot.block_hash().to_string(),
+            SNAPSHOT_HEIGHT,
+            hex::encode(serialize(&snapshot)),
+            None,
+            Some(format!(r#"{{"pow_limit": "{EASY_POW_LIMIT}"}}"#)),
+            Some(true),
+        )
+    }
+    .unwrap();
+    for block in blocks.iter() {
+        unsafe { chain.accept_block(block.clone(), None) }.unwrap();
+    }
+
+    // Seeds past the ones used by the blocks are never paid to
+    let ours = BLOCKS * OUTPUTS / MATCH_EVERY;
+    let addresses = (0..WATCHED)
+        .map(|i| match i < ours {
+            true => i * MATCH_EVERY,
+            false => BLOCKS * OUTPUTS + i,
+        })
+        .map(|seed| {
+            Address::from_script(&script(seed), Network::Signet)
+                .unwrap()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    unsafe { chain.add_addresses(serde_json::to_string(&addresses).unwrap()) }.unwrap();
+
+    let start = Instant::now();
+    for block in blocks {
+        unsafe { chain.rescan_block(block) }.unwrap();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{BLOCKS} blocks with {OUTPUTS} outputs, watching {WATCHED} scripts: {:?} per block",
+        elapsed / BLOCKS
+    );
+}
@@ -86,14 +86,17 @@ function add_random_address_to_wallet() {
 // Add a user provided address to the wallet
 function add_address_to_wallet() {
     const wallet = document.getElementById('wallet');
-    florestaChain.add_address(wallet.value);
+    // add_address returns false if we were already watching this address
+    if (!florestaChain.add_address(wallet.value)) {
+        alert("Address " + wallet.value + " is already in the wallet");
+    }
 
     wallet.value = "";
 }
 // Called when the user clicks the "Start" button
 async function sync_loop() {
     await update_tip()
-    florestaChain.toggle_ibd(); // We are done syncing, so we can tell our chain we are done
+    florestaChain.set_ibd(false); // We are done syncing, so we can tell our chain we are done
     try {
         update_ui();
     } catch (e) {
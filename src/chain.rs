@@ -0,0 +1,4784 @@
+// SPDX-License-Identifier: MIT
+
+//! The chain wrapper exported to JS, and the state it keeps besides the chain state.
+
+use super::*;
+
+#[wasm_bindgen]
+/// A wrapper around a the chain struct.
+pub struct FlorestaChain {
+    pub(crate) chain_state: ChainState<WasmStore>,
+    /// A handle to the same store used by `chain_state`, for maintenance work
+    pub(crate) store: WasmStore,
+    pub(crate) hashes: Vec<u8>,
+    pub(crate) wallet: Wallet,
+    pub(crate) metrics: Metrics,
+    /// Counters for the sync in progress, see `sync_report`
+    pub(crate) sync_progress: SyncProgress,
+    /// Called as `callback(stage, done, total)` during long running operations
+    pub(crate) progress_callback: Option<js_sys::Function>,
+    /// Called as `callback(ibd)` when we enter or leave IBD
+    pub(crate) ibd_callback: Option<js_sys::Function>,
+    /// Called as `callback(outpoint, value)` when one of our coinbase outputs matures
+    pub(crate) maturity_callback: Option<js_sys::Function>,
+    /// Called as `callback(txid, stale_block, reconfirmed)` when a reorg removes one of our
+    /// transactions from the chain
+    pub(crate) wallet_reorg_callback: Option<js_sys::Function>,
+    /// Read only views share a store with some other instance, and never write to it
+    pub(crate) read_only: bool,
+    /// Blocks are only scanned for our wallet, not validated, see `new_tracking_only`
+    pub(crate) tracking_only: bool,
+    /// Called as `callback(n)` to get n random bytes, we use getrandom if not set
+    pub(crate) entropy_source: Option<js_sys::Function>,
+    /// Creating a context is expensive, so we make one and use it everywhere
+    pub(crate) secp: Secp256k1<All>,
+    /// Changes to our best chain since the last `drain_new_headers`
+    pub(crate) header_events: HeaderEvents,
+    pub(crate) reorg_policy: ReorgPolicy,
+    /// How many leaves were ever added to the accumulator
+    pub(crate) acc_leaves: u64,
+    pub(crate) accumulator_deltas: AccumulatorDeltas,
+    /// The coinbases of the last blocks, if we are recording them
+    pub(crate) coinbases: Option<VecDeque<CoinbaseInfo>>,
+    /// Whether history entries come with privacy notes
+    pub(crate) privacy_analysis: bool,
+    /// The block a snapshot given to `build_chain_from` started at, None if we synced from
+    /// genesis. We only have headers below it if they were backfilled
+    pub(crate) assumed_base: Option<AssumedBase>,
+    /// After how many blocks on top of our tip a rejected block may be tried again
+    pub(crate) rejection_expiry: u32,
+    /// The network's tip height, as told by the application
+    pub(crate) target_height: Option<u32>,
+    /// How many bytes we may use, bulk inputs that would take us past it are refused
+    pub(crate) memory_budget: usize,
+    /// How long we keep header events and rejected blocks, see `set_event_retention`
+    pub(crate) event_retention: EventRetention,
+    /// Consensus parameters changed by `build_chain_from`, for custom signets
+    pub(crate) param_overrides: Option<ParamOverrides>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+/// See `assumed_base`
+pub(crate) struct AssumedBase {
+    pub(crate) height: u32,
+    pub(crate) hash: BlockHash,
+}
+
+#[derive(Debug, Serialize)]
+/// What `chain_params` returns
+pub(crate) struct ChainParamsInfo {
+    pub(crate) network: String,
+    pub(crate) pow_limit: String,
+    pub(crate) pow_target_spacing: u64,
+    pub(crate) pow_target_timespan: u64,
+    pub(crate) pow_allow_min_diff: bool,
+    pub(crate) pow_allow_no_retarget: bool,
+    pub(crate) subsidy_halving_interval: u64,
+    pub(crate) bip34_activation_height: u32,
+    pub(crate) bip65_activation_height: u32,
+    pub(crate) bip66_activation_height: u32,
+    pub(crate) csv_activation_height: u32,
+    pub(crate) segwit_activation_height: u32,
+    pub(crate) coinbase_maturity: u32,
+    pub(crate) max_block_weight: u32,
+    /// Only set for custom signets, in hex
+    pub(crate) signet_challenge: Option<String>,
+    /// If set, blocks aren't validated, see `new_tracking_only`
+    pub(crate) tracking_only: bool,
+}
+
+#[derive(Debug, Serialize)]
+/// What `utxo_set_info` returns
+pub(crate) struct UtxoSetInfo {
+    /// Every output ever added to the accumulator, spent or not. This is an upper bound for
+    /// the size of the UTXO set
+    pub(crate) leaves: u64,
+    pub(crate) roots: usize,
+    /// The height of each tree in the accumulator forest, from the biggest one
+    pub(crate) tree_heights: Vec<u8>,
+}
+
+/// The outputs connecting `block` adds to the accumulator: every output except OP_RETURNs
+/// and the ones spent in the same block. Also tells whether each one is from the coinbase.
+pub(crate) fn added_outputs(block: &Block) -> Vec<(OutPoint, &TxOut, bool)> {
+    let spent = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.input.iter().map(|input| input.previous_output))
+        .collect::<HashSet<_>>();
+    block
+        .txdata
+        .iter()
+        .enumerate()
+        .flat_map(|(i, tx)| {
+            let txid = tx.txid();
+            tx.output.iter().enumerate().map(move |(vout, out)| {
+                let outpoint = OutPoint {
+                    txid,
+                    vout: vout as u32,
+                };
+                (outpoint, out, i == 0)
+            })
+        })
+        .filter(|(outpoint, out, _)| !out.script_pubkey.is_op_return() && !spent.contains(outpoint))
+        .collect()
+}
+
+/// How many leaves connecting `block` adds to the accumulator
+pub(crate) fn added_leaves(block: &Block) -> u64 {
+    added_outputs(block).len() as u64
+}
+
+/// The hashes of the leaves connecting `block` at `height` adds to the accumulator
+pub(crate) fn added_leaf_hashes(block: &Block, height: u32) -> Vec<sha256::Hash> {
+    let block_hash = block.block_hash();
+    added_outputs(block)
+        .into_iter()
+        .map(|(prevout, utxo, is_coinbase)| {
+            floresta_chain::pruned_utreexo::udata::LeafData {
+                block_hash,
+                prevout,
+                header_code: height << 1 | is_coinbase as u32,
+                utxo: utxo.clone(),
+            }
+            ._get_leaf_hashes()
+        })
+        .collect()
+}
+
+/// The sha256 of the embedded hashes.bin, so a wrong file can be told apart from a bug
+pub(crate) const HASH_TABLE_SHA256: &str =
+    "0620cbdfec597c436d4e3e465a793f5bacbb4158315e1e1e3da03700f91a9d9c";
+
+#[derive(Debug, Default, Serialize)]
+/// What `verify_hash_table` returns
+pub(crate) struct HashTableReport {
+    pub(crate) entries: u32,
+    /// How many entries we compared with our headers
+    pub(crate) checked: u32,
+    /// Sampled entries we have no header to compare with, e.g. below our snapshot
+    pub(crate) unavailable: u32,
+    /// Heights where the table doesn't match our best chain
+    pub(crate) mismatched: Vec<u32>,
+    /// Whether the table is the one embedded in this build
+    pub(crate) matches_embedded: bool,
+}
+
+/// How many coinbases we keep, once recording is enabled
+pub(crate) const MAX_COINBASE_INFOS: usize = 144;
+
+#[derive(Debug, Clone, Serialize)]
+/// An output of a coinbase transaction
+pub(crate) struct CoinbaseOutput {
+    pub(crate) value: u64,
+    pub(crate) script_pubkey: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// What `coinbase_info` returns
+pub(crate) struct CoinbaseInfo {
+    pub(crate) hash: BlockHash,
+    pub(crate) height: u32,
+    pub(crate) script_sig: String,
+    /// The height pushed at the start of the scriptSig, as required by BIP34
+    pub(crate) bip34_height: Option<u32>,
+    /// Set if the BIP34 height is missing or doesn't match the block's height. Some old
+    /// blocks and test networks don't follow it, so this isn't an error
+    pub(crate) warning: Option<String>,
+    pub(crate) outputs: Vec<CoinbaseOutput>,
+    pub(crate) witness_commitment: Option<String>,
+}
+
+impl CoinbaseInfo {
+    pub(crate) fn new(block: &Block, height: u32) -> Option<Self> {
+        let coinbase = block.txdata.first()?;
+        let script_sig = &coinbase.input.first()?.script_sig;
+        let bip34_height = bip34_height(script_sig);
+        let warning = match bip34_height {
+            None => Some("No BIP34 height in the coinbase".to_string()),
+            Some(pushed) if pushed != height => Some(format!(
+                "Coinbase says height {pushed}, but the block is at {height}"
+            )),
+            Some(_) => None,
+        };
+        // The commitment is in the last output starting with this
+        let commitment_prefix = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        let witness_commitment = coinbase
+            .output
+            .iter()
+            .rev()
+            .map(|out| out.script_pubkey.as_bytes())
+            .find(|script| script.len() >= 38 && script.starts_with(&commitment_prefix))
+            .map(|script| hex::encode(&script[6..38]));
+        Some(CoinbaseInfo {
+            hash: block.block_hash(),
+            height,
+            script_sig: hex::encode(script_sig.as_bytes()),
+            bip34_height,
+            warning,
+            outputs: coinbase
+                .output
+                .iter()
+                .map(|out| CoinbaseOutput {
+                    value: out.value,
+                    script_pubkey: hex::encode(out.script_pubkey.as_bytes()),
+                })
+                .collect(),
+            witness_commitment,
+        })
+    }
+}
+
+/// Decodes the height BIP34 requires at the start of a coinbase's scriptSig
+pub(crate) fn bip34_height(script_sig: &Script) -> Option<u32> {
+    match script_sig.instructions().next()?.ok()? {
+        Instruction::PushBytes(bytes) if !bytes.is_empty() && bytes.len() <= 4 => {
+            let mut height = [0u8; 4];
+            height[..bytes.len()].copy_from_slice(bytes);
+            Some(u32::from_le_bytes(height))
+        }
+        Instruction::Op(op) => {
+            let op = op.to_u8();
+            match op {
+                0x00 => Some(0),
+                0x51..=0x60 => Some((op - 0x50) as u32),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// How many accumulator deltas we keep, once recording is enabled
+pub(crate) const MAX_ACCUMULATOR_DELTAS: usize = 144;
+
+#[derive(Debug, Clone, Serialize)]
+/// The changes connecting a block made to the accumulator
+pub(crate) struct AccumulatorDelta {
+    pub(crate) hash: BlockHash,
+    pub(crate) height: u32,
+    pub(crate) added: Vec<sha256::Hash>,
+    pub(crate) removed: Vec<sha256::Hash>,
+    pub(crate) roots_before: Vec<String>,
+    pub(crate) roots_after: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+/// The deltas for the last connected blocks, see `record_accumulator_deltas`
+pub(crate) struct AccumulatorDeltas {
+    pub(crate) enabled: bool,
+    pub(crate) deltas: VecDeque<AccumulatorDelta>,
+}
+
+impl AccumulatorDeltas {
+    pub(crate) fn push(&mut self, delta: AccumulatorDelta) {
+        if self.deltas.len() == MAX_ACCUMULATOR_DELTAS {
+            self.deltas.pop_front();
+        }
+        self.deltas.push_back(delta);
+    }
+}
+
+#[derive(Debug)]
+/// Which reorganizations we accept without the user's consent
+pub(crate) struct ReorgPolicy {
+    /// Reorgs replacing more than this many blocks are refused
+    pub(crate) max_depth: u32,
+    /// Blocks refused for reorging too deep, and their descendants
+    pub(crate) invalid: HashSet<BlockHash>,
+    /// Blocks the user allowed with `force_reorg`, even if they reorg too deep
+    pub(crate) forced: HashSet<BlockHash>,
+    /// Called as `callback(our_tip, their_block, depth)` when a deep reorg is refused
+    pub(crate) callback: Option<js_sys::Function>,
+}
+
+impl Default for ReorgPolicy {
+    fn default() -> Self {
+        ReorgPolicy {
+            max_depth: REORG_PROTECTION_DEPTH,
+            invalid: HashSet::new(),
+            forced: HashSet::new(),
+            callback: None,
+        }
+    }
+}
+
+/// How many header events we keep before dropping the oldest ones
+pub(crate) const MAX_HEADER_EVENTS: usize = 1024;
+
+#[derive(Debug, Clone, Serialize)]
+/// A header added to or removed from our best chain
+pub(crate) struct HeaderEvent {
+    pub(crate) hash: BlockHash,
+    pub(crate) height: u32,
+    pub(crate) time: u32,
+    /// True if this header was reorganized out of our best chain
+    pub(crate) removed: bool,
+    #[serde(skip)]
+    pub(crate) header: BlockHeader,
+}
+
+#[derive(Debug, Default, Serialize)]
+/// The header events returned by `drain_new_headers`
+pub(crate) struct HeaderEvents {
+    pub(crate) events: VecDeque<HeaderEvent>,
+    /// Set if we had to drop events because nobody drained them, consumers should resync
+    pub(crate) overflow: bool,
+}
+
+impl HeaderEvents {
+    /// Drops events for blocks below `min_height`, then the oldest ones past `max_entries`.
+    /// Like overflowing, this means consumers missed events and should resync.
+    pub(crate) fn prune(&mut self, min_height: u32, max_entries: usize) {
+        let before = self.events.len();
+        self.events.retain(|event| event.height >= min_height);
+        while self.events.len() > max_entries {
+            self.events.pop_front();
+        }
+        if self.events.len() < before {
+            self.overflow = true;
+        }
+    }
+    pub(crate) fn push(
+        &mut self,
+        hash: BlockHash,
+        header: &BlockHeader,
+        height: u32,
+        removed: bool,
+    ) {
+        if self.events.len() == MAX_HEADER_EVENTS {
+            self.events.pop_front();
+            self.overflow = true;
+        }
+        self.events.push_back(HeaderEvent {
+            hash,
+            height,
+            time: header.time,
+            removed,
+            header: *header,
+        });
+    }
+}
+
+impl Drop for FlorestaChain {
+    fn drop(&mut self) {
+        if !self.read_only {
+            self.store.has_writer.set(false);
+        }
+    }
+}
+
+#[wasm_bindgen]
+/// What the UI usually shows about our chain and wallet, all taken at the same instant by
+/// `snapshot_view`, so values can't come from different states
+pub struct ChainView {
+    pub(crate) height: u32,
+    pub(crate) tip: BlockHash,
+    pub(crate) ibd: bool,
+    pub(crate) tracking_only: bool,
+    pub(crate) roots: Vec<String>,
+    pub(crate) balance: u64,
+    pub(crate) immature_balance: u64,
+    pub(crate) utxo_count: usize,
+    pub(crate) tx_count: usize,
+}
+
+#[wasm_bindgen]
+impl ChainView {
+    #[wasm_bindgen(getter, js_name = "height")]
+    pub unsafe fn show_height(&self) -> u32 {
+        self.height
+    }
+    #[wasm_bindgen(getter, js_name = "tip")]
+    pub unsafe fn return_tip(&self) -> String {
+        self.tip.to_string()
+    }
+    #[wasm_bindgen(getter, js_name = "ibd")]
+    pub unsafe fn show_ibd(&self) -> bool {
+        self.ibd
+    }
+    /// Whether the chain doesn't validate blocks, see `FlorestaChain::new_tracking_only`
+    #[wasm_bindgen(getter, js_name = "tracking_only")]
+    pub unsafe fn is_tracking_only(&self) -> bool {
+        self.tracking_only
+    }
+    /// The accumulator roots, as a JSON array of hex strings
+    #[wasm_bindgen(getter, js_name = "roots")]
+    pub unsafe fn show_roots(&self) -> String {
+        serde_json::to_string(&self.roots).unwrap_or_default()
+    }
+    /// What we can spend now, without coinbase outputs that didn't mature yet
+    #[wasm_bindgen(getter, js_name = "balance")]
+    pub unsafe fn show_balance(&self) -> u64 {
+        self.balance
+    }
+    #[wasm_bindgen(getter, js_name = "immature_balance")]
+    pub unsafe fn show_immature_balance(&self) -> u64 {
+        self.immature_balance
+    }
+    #[wasm_bindgen(getter, js_name = "utxo_count")]
+    pub unsafe fn show_utxo_count(&self) -> usize {
+        self.utxo_count
+    }
+    #[wasm_bindgen(getter, js_name = "tx_count")]
+    pub unsafe fn show_tx_count(&self) -> usize {
+        self.tx_count
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+/// Counters about what this chain has been doing, returned by `get_metrics`
+pub(crate) struct Metrics {
+    pub(crate) blocks_connected: u64,
+    pub(crate) watched_scripts: usize,
+    /// How many watch set lookups the last block needed, one per input and output
+    pub(crate) last_block_filter_lookups: u64,
+    /// How many script bytes were hashed to filter the last block, this grows with the block,
+    /// while the number of watched scripts mostly affects memory
+    pub(crate) last_block_filter_bytes: u64,
+    pub(crate) store: StoreStats,
+    pub(crate) last_block_timings: BlockTimings,
+    /// Exponential moving average of the timings, giving more weight to recent blocks
+    pub(crate) average_block_timings: BlockTimings,
+    /// How many times the store and the hash table disagreed on a block hash
+    pub(crate) hash_source_discrepancies: u64,
+    /// The last of those disagreements, returned by `hash_source_discrepancies`
+    #[serde(skip)]
+    pub(crate) recent_hash_discrepancies: VecDeque<HashSourceDiscrepancy>,
+    /// Moving average of the serialized size of connected blocks, None before the first one
+    pub(crate) average_block_size: Option<f64>,
+    /// When the last blocks got connected, used to estimate how fast we are syncing
+    #[serde(skip)]
+    pub(crate) recent_connects: VecDeque<f64>,
+}
+
+/// How many connection times we keep to estimate the sync speed
+pub(crate) const MAX_RECENT_CONNECTS: usize = 100;
+/// Below this many connection times, we don't guess how long syncing will take
+pub(crate) const MIN_RATE_SAMPLES: usize = 10;
+
+#[derive(Debug, Serialize)]
+/// What `sync_remaining` returns. Every estimate is None until we have enough data for it
+pub(crate) struct SyncRemaining {
+    pub(crate) target_height: Option<u32>,
+    pub(crate) validated_height: u32,
+    pub(crate) blocks: Option<u32>,
+    pub(crate) bytes: Option<u64>,
+    pub(crate) eta_seconds: Option<f64>,
+}
+
+#[derive(Debug, Default)]
+/// What happened since we entered IBD, summarized in a `SyncReport` once we leave it
+pub(crate) struct SyncProgress {
+    /// When the first block of this sync got connected
+    pub(crate) started_ms: Option<f64>,
+    pub(crate) start_height: u32,
+    pub(crate) blocks: u64,
+    pub(crate) bytes: u64,
+    /// How long each block took to validate and connect, in milliseconds. At four bytes per
+    /// block, this is small compared to the store even for a full sync
+    pub(crate) block_ms: Vec<f32>,
+    pub(crate) wallet_matches: u64,
+    pub(crate) reorgs: u64,
+    pub(crate) rejected_blocks: u64,
+    pub(crate) retried_blocks: u64,
+    pub(crate) peak_state_bytes: usize,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+/// Validation time per block, in milliseconds
+pub(crate) struct BlockTimeSummary {
+    pub(crate) average: f64,
+    pub(crate) p50: f64,
+    pub(crate) p90: f64,
+    pub(crate) p99: f64,
+    pub(crate) max: f64,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+/// A summary of an initial sync, made when we leave IBD and returned by `sync_report`
+pub(crate) struct SyncReport {
+    pub(crate) start_height: u32,
+    pub(crate) end_height: u32,
+    pub(crate) wall_time_seconds: f64,
+    pub(crate) blocks: u64,
+    pub(crate) bytes: u64,
+    pub(crate) block_ms: Option<BlockTimeSummary>,
+    /// Our transactions found while syncing
+    pub(crate) wallet_matches: u64,
+    pub(crate) reorgs: u64,
+    /// Blocks we refused, e.g. because their proof didn't verify
+    pub(crate) rejected_blocks: u64,
+    /// Blocks accepted after being retried with `accept_block(block, true)`
+    pub(crate) retried_blocks: u64,
+    /// The most memory `estimated_state_size` reported while syncing
+    pub(crate) peak_state_bytes: usize,
+}
+
+impl SyncProgress {
+    pub(crate) fn report(&self, end_height: u32) -> SyncReport {
+        let mut block_ms = self.block_ms.clone();
+        block_ms.sort_by(|a, b| a.total_cmp(b));
+        let percentile = |p: usize| block_ms[(block_ms.len() - 1) * p / 100] as f64;
+        let summary = (!block_ms.is_empty()).then(|| BlockTimeSummary {
+            average: block_ms.iter().map(|ms| *ms as f64).sum::<f64>() / block_ms.len() as f64,
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: percentile(100),
+        });
+        SyncReport {
+            start_height: self.start_height,
+            end_height,
+            wall_time_seconds: self
+                .started_ms
+                .map_or(0.0, |started| (now_ms() - started) / 1000.0),
+            blocks: self.blocks,
+            bytes: self.bytes,
+            block_ms: summary,
+            wallet_matches: self.wallet_matches,
+            reorgs: self.reorgs,
+            rejected_blocks: self.rejected_blocks,
+            retried_blocks: self.retried_blocks,
+            peak_state_bytes: self.peak_state_bytes,
+        }
+    }
+}
+
+#[cfg(feature = "debug-assertions")]
+/// What we need to remember from before connecting a block to check our invariants after
+pub(crate) struct InvariantBaseline {
+    pub(crate) tip: BlockHash,
+    pub(crate) blocks_connected: u64,
+    pub(crate) store_gets: u64,
+    pub(crate) store_puts: u64,
+    pub(crate) acc_leaves: u64,
+    /// How many leaves the block being connected adds
+    pub(crate) added_leaves: u64,
+}
+
+#[cfg(feature = "debug-assertions")]
+#[derive(Debug, Serialize)]
+/// An invariant that didn't hold after connecting a block
+pub(crate) struct InvariantViolation {
+    pub(crate) invariant: &'static str,
+    pub(crate) details: String,
+}
+
+/// How many hash source discrepancies we keep the details for
+pub(crate) const MAX_HASH_DISCREPANCIES: usize = 100;
+
+#[derive(Debug, Clone, Serialize)]
+/// A height where the store and the hash table have different block hashes
+pub(crate) struct HashSourceDiscrepancy {
+    pub(crate) height: u32,
+    /// The hash we used
+    pub(crate) store: BlockHash,
+    pub(crate) table: BlockHash,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+/// How many milliseconds each stage of `accept_block` took
+pub(crate) struct BlockTimings {
+    pub(crate) parse_ms: f64,
+    pub(crate) accept_header_ms: f64,
+    pub(crate) leaf_reconstruction_ms: f64,
+    /// Proof verification happens inside floresta-chain's connect_block, so it's included here
+    pub(crate) connect_block_ms: f64,
+    pub(crate) wallet_ms: f64,
+    pub(crate) total_ms: f64,
+}
+
+impl BlockTimings {
+    /// How much the last block weights in the moving average
+    pub(crate) const SMOOTHING: f64 = 0.1;
+
+    pub(crate) fn update_average(&mut self, last: &BlockTimings) {
+        let average = |avg: f64, last: f64| avg + (last - avg) * Self::SMOOTHING;
+        self.parse_ms = average(self.parse_ms, last.parse_ms);
+        self.accept_header_ms = average(self.accept_header_ms, last.accept_header_ms);
+        self.leaf_reconstruction_ms =
+            average(self.leaf_reconstruction_ms, last.leaf_reconstruction_ms);
+        self.connect_block_ms = average(self.connect_block_ms, last.connect_block_ms);
+        self.wallet_ms = average(self.wallet_ms, last.wallet_ms);
+        self.total_ms = average(self.total_ms, last.total_ms);
+    }
+}
+
+/// Measures how long each stage of some work takes, with one timestamp per stage
+pub(crate) struct StageTimer {
+    pub(crate) start: f64,
+    pub(crate) last: f64,
+}
+
+impl StageTimer {
+    pub(crate) fn start() -> Self {
+        let now = now_ms();
+        Self {
+            start: now,
+            last: now,
+        }
+    }
+    /// Returns how long it's been since the last lap
+    pub(crate) fn lap(&mut self) -> f64 {
+        let now = now_ms();
+        let elapsed = now - self.last;
+        self.last = now;
+        elapsed
+    }
+    pub(crate) fn total(&self) -> f64 {
+        self.last - self.start
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+/// A monotonic timestamp in milliseconds, using `performance.now()` when available
+pub(crate) fn now_ms() -> f64 {
+    web_sys::window()
+        .and_then(|window| window.performance())
+        .map(|performance| performance.now())
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// A monotonic timestamp in milliseconds, since the first time this was called
+pub(crate) fn now_ms() -> f64 {
+    use std::{sync::OnceLock, time::Instant};
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_secs_f64() * 1000.0
+}
+
+#[cfg(target_arch = "wasm32")]
+/// The current unix time, in seconds
+pub(crate) fn unix_time() -> u64 {
+    (js_sys::Date::now() / 1000.0) as u64
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+/// The current unix time, in seconds
+pub(crate) fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Where the trusted state used by `build_chain_from` came from, kept in the store
+pub(crate) struct SnapshotProvenance {
+    pub(crate) tip: BlockHash,
+    pub(crate) height: u32,
+    /// Hash of the accumulator roots we started from, concatenated
+    pub(crate) roots_hash: sha256::Hash,
+    /// A label given by the caller, e.g. a URL or the name of whoever provided the snapshot
+    pub(crate) source: Option<String>,
+    /// Unix time at which we bootstrapped from this snapshot
+    pub(crate) created_at: u64,
+    /// Consensus parameters changed for a custom signet, if any
+    #[serde(default)]
+    pub(crate) param_overrides: Option<ParamOverrides>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// Consensus parameters a custom signet may change, see `build_chain_from`
+pub(crate) struct ParamOverrides {
+    pub(crate) pow_target_spacing: Option<u64>,
+    /// Only used by our wallet, floresta-chain still lets blocks spend younger coinbases
+    pub(crate) coinbase_maturity: Option<u32>,
+    /// As a 256 bits big endian number, in hex
+    pub(crate) pow_limit: Option<String>,
+    /// The script blocks must satisfy, in hex. Only reported, blocks aren't checked against it
+    pub(crate) signet_challenge: Option<String>,
+}
+
+impl ParamOverrides {
+    /// Makes sure every override makes sense, and that we aren't on mainnet
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if CHAIN_NETWORK == bitcoin::Network::Bitcoin {
+            return Err("Consensus parameters can't be changed on mainnet".into());
+        }
+        if self.pow_target_spacing == Some(0) {
+            return Err("The target spacing must be at least one second".into());
+        }
+        if let Some(pow_limit) = &self.pow_limit {
+            Self::parse_pow_limit(pow_limit)?;
+        }
+        if let Some(challenge) = &self.signet_challenge {
+            hex::decode(challenge).map_err(|_| "The signet challenge must be hex")?;
+        }
+        Ok(())
+    }
+    pub(crate) fn parse_pow_limit(pow_limit: &str) -> Result<Uint256, String> {
+        parse_uint256(pow_limit).ok_or_else(|| "The pow limit must be 32 bytes of hex".into())
+    }
+    /// Applies the overrides floresta-chain knows about to `params`. Coinbase maturity and
+    /// the signet challenge aren't part of them, see `coinbase_maturity`.
+    pub(crate) fn apply(&self, params: &mut ChainParams) {
+        if let Some(spacing) = self.pow_target_spacing {
+            params.pow_target_spacing = spacing;
+        }
+        if let Some(pow_limit) = self.pow_limit.as_deref() {
+            if let Ok(pow_limit) = Self::parse_pow_limit(pow_limit) {
+                params.pow_limit = pow_limit;
+            }
+        }
+    }
+}
+/// How many rejected blocks we remember
+pub(crate) const MAX_REJECTED_BLOCKS: usize = 100;
+/// After how many blocks a rejected block may be submitted again, by default
+pub(crate) const DEFAULT_REJECTION_EXPIRY: u32 = 6;
+
+#[cfg(target_arch = "wasm32")]
+/// How much memory we use by default. Mobile browsers may kill instances well below the
+/// 4GB a wasm32 memory can grow to
+pub(crate) const DEFAULT_MEMORY_BUDGET: usize = 256 * 1024 * 1024;
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) const DEFAULT_MEMORY_BUDGET: usize = usize::MAX;
+/// How many bytes of memory each byte of block JSON needs while parsing and validating it
+pub(crate) const BLOCK_JSON_EXPANSION: usize = 2;
+/// How many bytes of memory each byte of serialized headers needs: the copy we decode,
+/// the decoded headers and their index entries
+pub(crate) const HEADER_BATCH_EXPANSION: usize = 4;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A block `accept_block` refused, kept in the store
+pub(crate) struct RejectedBlock {
+    pub(crate) reason: String,
+    /// None if we didn't know its parent
+    pub(crate) height: Option<u32>,
+    /// Our tip height when it was last rejected, the entry expires as our tip moves on
+    pub(crate) tip_height: u32,
+    pub(crate) first_rejected_at: u64,
+    pub(crate) last_rejected_at: u64,
+    pub(crate) count: u32,
+}
+
+/// Drops the blocks rejected longest ago until at most `max` are left
+pub(crate) fn trim_rejections(rejected: &mut BTreeMap<BlockHash, RejectedBlock>, max: usize) {
+    while rejected.len() > max {
+        let oldest = rejected
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_rejected_at)
+            .map(|(hash, _)| *hash);
+        if let Some(oldest) = oldest {
+            rejected.remove(&oldest);
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// Limits applied to header events and the rejection log as blocks connect, on top of
+/// their own size limits
+pub(crate) struct EventRetention {
+    /// Entries for blocks this far below our tip are dropped
+    pub(crate) blocks: Option<u32>,
+    pub(crate) max_entries: Option<usize>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+/// How many entries our event buffers hold, reported by `estimated_state_size`
+pub(crate) struct EventOccupancy {
+    pub(crate) header_events: usize,
+    pub(crate) rejected_blocks: usize,
+    pub(crate) reorged_transactions: usize,
+}
+
+#[derive(Debug, Serialize)]
+/// What `rejection_log` returns for each block
+pub(crate) struct RejectionLogEntry {
+    pub(crate) hash: BlockHash,
+    #[serde(flatten)]
+    pub(crate) rejected: RejectedBlock,
+}
+
+/// How many headers we return at once, the same limit used by the p2p `headers` message
+pub(crate) const MAX_HEADERS_RESULTS: u32 = 2_000;
+
+#[derive(Debug, Serialize)]
+/// Where a remote chain forks from ours, returned by `locate_fork`
+pub(crate) struct ForkPoint {
+    pub(crate) height: u32,
+    pub(crate) hash: BlockHash,
+    /// The headers following the fork point in our best chain, serialized as hex
+    pub(crate) headers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+/// A decoded header, as returned by `get_headers`
+pub(crate) struct HeaderJson {
+    pub(crate) hash: BlockHash,
+    pub(crate) height: u32,
+    pub(crate) time: u32,
+    pub(crate) bits: u32,
+    pub(crate) nonce: u32,
+    pub(crate) prev: BlockHash,
+}
+
+#[derive(Debug, Serialize)]
+/// A range of headers, and which of the requested heights we don't have
+pub(crate) struct HeadersPage {
+    pub(crate) headers: Vec<HeaderJson>,
+    /// Requested heights below our snapshot, inclusive. We never had those headers
+    pub(crate) unavailable: Option<(u32, u32)>,
+}
+
+/// The version of the format written by `export_block_index` and `export_misc_state`. Bump
+/// it on any incompatible change, backups must not depend on how the store keeps things
+pub(crate) const BACKUP_FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+/// A header in our best chain, as written by `export_block_index`
+pub(crate) struct BlockIndexEntry {
+    pub(crate) height: u32,
+    pub(crate) hash: BlockHash,
+    /// The 80 byte serialized header, in hex
+    pub(crate) header: String,
+}
+
+#[derive(Debug, Serialize)]
+/// A chunk of our best chain, returned by `export_block_index`
+pub(crate) struct BlockIndexChunk {
+    pub(crate) version: u32,
+    pub(crate) entries: Vec<BlockIndexEntry>,
+    /// Where the next chunk starts, None if this one ends at our tip
+    pub(crate) next: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+/// Everything besides the block index needed to restore our chain, see `export_misc_state`
+pub(crate) struct MiscState {
+    pub(crate) version: u32,
+    pub(crate) network: String,
+    pub(crate) height: u32,
+    pub(crate) tip: BlockHash,
+    /// Accumulator roots, in hex
+    pub(crate) roots: Vec<String>,
+    pub(crate) leaves: u64,
+    pub(crate) snapshot_provenance: Option<SnapshotProvenance>,
+}
+
+#[wasm_bindgen]
+impl FlorestaChain {
+    /// Creates a new FlorestaChain object. This should be used with new FlorestaChain()
+    #[wasm_bindgen(constructor)]
+    pub unsafe fn new() -> Self {
+        let store = WasmStore::default();
+        store.has_writer.set(true);
+        let chain_state = ChainState::new(store.clone(), FLORESTA_NETWORK, None);
+        Self::with_chain_state(chain_state, store, Vec::new())
+    }
+    /// A writer over `chain_state` with an empty wallet, and everything else at its defaults
+    pub(crate) fn with_chain_state(
+        chain_state: ChainState<WasmStore>,
+        store: WasmStore,
+        hashes: Vec<u8>,
+    ) -> Self {
+        Self {
+            chain_state,
+            store,
+            wallet: Wallet::default(),
+            hashes,
+            metrics: Metrics::default(),
+            sync_progress: SyncProgress::default(),
+            progress_callback: None,
+            ibd_callback: None,
+            maturity_callback: None,
+            wallet_reorg_callback: None,
+            read_only: false,
+            tracking_only: false,
+            entropy_source: None,
+            secp: Secp256k1::new(),
+            header_events: HeaderEvents::default(),
+            accumulator_deltas: AccumulatorDeltas::default(),
+            coinbases: None,
+            privacy_analysis: false,
+            rejection_expiry: DEFAULT_REJECTION_EXPIRY,
+            memory_budget: DEFAULT_MEMORY_BUDGET,
+            event_retention: EventRetention::default(),
+            target_height: None,
+            param_overrides: None,
+            assumed_base: None,
+            reorg_policy: ReorgPolicy::default(),
+            acc_leaves: 0,
+        }
+    }
+    /// Returns a handle to our store, that can be given to `open_shared` to create other
+    /// instances over the same data
+    pub unsafe fn store_handle(&self) -> WasmStore {
+        self.store.clone()
+    }
+    /// Opens another chain over a store already used by some instance. Only one instance may
+    /// write to a store, so opening a writer fails while another writer is alive. Read only
+    /// views can't accept blocks or change the store, and only see data the writer has
+    /// flushed: headers and index entries show up after the writer's next flush, and the
+    /// tip only moves after calling `refresh`. Each instance has its own wallet.
+    ///
+    /// Both instances must live in the same wasm instance, since they share memory.
+    pub unsafe fn open_shared(store: &WasmStore, writer: bool) -> Result<FlorestaChain, String> {
+        if writer && store.has_writer.get() {
+            return Err("This store already has a writer".into());
+        }
+        let store = match writer {
+            // The handle may have come from a view, so make sure we write to the real map
+            true => WasmStore {
+                read_view: false,
+                ..store.clone()
+            },
+            false => store.read_view(),
+        };
+        let provenance = store.snapshot_provenance().map_err(|e| format!("{e:?}"))?;
+        let param_overrides = provenance
+            .as_ref()
+            .and_then(|provenance| provenance.param_overrides.clone());
+        let chain_state = Self::load_chain_state(&store, param_overrides.as_ref())
+            .map_err(|e| format!("Loading the chain state: {e}"))?;
+        if writer {
+            store.has_writer.set(true);
+        }
+        let hashes = include_bytes!("../hashes.bin");
+        let acc_leaves = store.stored_leaves();
+        let tracking_only = store
+            .get("tracking_only")
+            .map_err(|e| format!("{e:?}"))?
+            .is_some();
+        let assumed_base = provenance.as_ref().map(|provenance| AssumedBase {
+            height: provenance.height,
+            hash: provenance.tip,
+        });
+        let mut chain = Self::with_chain_state(chain_state, store, hashes.to_vec());
+        chain.acc_leaves = acc_leaves;
+        chain.read_only = !writer;
+        chain.tracking_only = tracking_only;
+        chain.param_overrides = param_overrides;
+        chain.assumed_base = assumed_base;
+        Ok(chain)
+    }
+    /// Reloads the chain state from the store, so a read only view catches up with the tip
+    /// saved by the writer
+    pub unsafe fn refresh(&mut self) -> Result<(), String> {
+        self.chain_state = Self::load_chain_state(&self.store, self.param_overrides.as_ref())
+            .map_err(|e| format!("Reloading the chain state: {e}"))?;
+        self.acc_leaves = self.store.stored_leaves();
+        Ok(())
+    }
+    /// Creates a chain that doesn't validate blocks, for apps relying on a full node they
+    /// trust to do it. `accept_block` only checks that the block's header connects to our
+    /// headers with valid proof of work, and that its transactions match the merkle root,
+    /// then scans it for our wallet. Proofs and leaf data may be empty, and the accumulator
+    /// is never touched. This is saved in the store, so shared views are tracking only too.
+    pub unsafe fn new_tracking_only() -> FlorestaChain {
+        let mut chain = Self::new();
+        chain.tracking_only = true;
+        chain.store.put("tracking_only".into(), &[1]);
+        chain
+    }
+    /// Whether this chain only tracks our wallet, without validating blocks. If so, nothing
+    /// it reports about the chain was verified by us.
+    #[wasm_bindgen(getter, js_name = "tracking_only")]
+    pub unsafe fn is_tracking_only(&self) -> bool {
+        self.tracking_only
+    }
+    /// Whether this is a read only view over a store shared with another instance
+    #[wasm_bindgen(getter, js_name = "read_only")]
+    pub unsafe fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+    /// Add a new address to the wallet. This will be used to filter transactions.
+    /// If `created_at` is given, rescans for this address start at that height instead of the
+    /// wallet birthday. Returns false if we were already watching this address. Addresses for
+    /// other networks are refused, unless `allow_foreign` is set to deliberately watch their
+    /// script.
+    pub unsafe fn add_address(
+        &self,
+        addr: String,
+        created_at: Option<u32>,
+        allow_foreign: Option<bool>,
+    ) -> Result<bool, String> {
+        let address = if allow_foreign.unwrap_or(false) {
+            parse_any_address(&addr)?
+        } else {
+            parse_address(&addr)?
+        };
+        let status = self.watch_address(&address, created_at)?;
+        Ok(matches!(status, AddressImportResult::Ok))
+    }
+    /// Imports a private key in WIF, watching the addresses for it. `watch_types` is a JSON
+    /// array with any of `p2pkh`, `p2wpkh` and `p2sh-p2wpkh`. Coins sent to those addresses
+    /// become spendable, see `list_spendable`. The key is only kept in memory. Returns the
+    /// watched addresses as a JSON array.
+    pub unsafe fn import_privkey(
+        &self,
+        wif: String,
+        watch_types: String,
+    ) -> Result<String, String> {
+        let watch_types: Vec<String> =
+            serde_json::from_str(&watch_types).map_err(|e| e.to_string())?;
+        let addresses = self
+            .watch_key(&wif, &watch_types)?
+            .iter()
+            .map(|address| address.to_string())
+            .collect::<Vec<_>>();
+        serde_json::to_string(&addresses).map_err(|e| e.to_string())
+    }
+    /// Parses a WIF for our network, and watches its addresses of the given types, remembering
+    /// the key so we can spend from them
+    pub(crate) fn watch_key(
+        &self,
+        wif: &str,
+        watch_types: &[String],
+    ) -> Result<Vec<Address>, String> {
+        let key = parse_wif(wif)?;
+        let addresses = self.key_addresses(&key, watch_types)?;
+        let mut address_set = self.wallet.address_set.borrow_mut();
+        let new_scripts = addresses
+            .iter()
+            .filter(|address| !address_set.contains(&address.script_pubkey()))
+            .count();
+        self.wallet
+            .check_watch_capacity(address_set.len(), new_scripts)?;
+        let mut keys = self.wallet.keys.borrow_mut();
+        for address in addresses.iter() {
+            self.wallet.watch(&mut address_set, address.script_pubkey());
+            keys.insert(address.script_pubkey(), key);
+        }
+        Ok(addresses)
+    }
+    /// The addresses of the given types for `key`
+    pub(crate) fn key_addresses(
+        &self,
+        key: &PrivateKey,
+        watch_types: &[String],
+    ) -> Result<Vec<Address>, String> {
+        let public_key = key.public_key(&self.secp);
+        let network = CHAIN_NETWORK;
+        let addresses = watch_types
+            .iter()
+            .map(|watch_type| match watch_type.as_str() {
+                "p2pkh" => Ok(Address::p2pkh(&public_key, network)),
+                "p2wpkh" => Address::p2wpkh(&public_key, network).map_err(|e| e.to_string()),
+                "p2sh-p2wpkh" => Address::p2shwpkh(&public_key, network).map_err(|e| e.to_string()),
+                other => Err(format!("Unknown address type {other}")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(addresses)
+    }
+    /// Sends every confirmed coin we know for this key's p2pkh, p2wpkh and p2sh-p2wpkh
+    /// addresses, or only p2pkh for uncompressed keys, to `destination`, paying `fee_rate`
+    /// sat/vB. Coins reserved with
+    /// `mark_reserved` are left alone. This doesn't import the key, so we only find coins
+    /// for addresses we already watch: for a new key, `import_privkey` it and rescan first.
+    /// Returns the signed transaction, in hex, along with the amount sent and the fee, as JSON.
+    pub unsafe fn sweep_key(
+        &self,
+        wif: String,
+        destination: String,
+        fee_rate: u64,
+    ) -> Result<String, String> {
+        let destination = parse_address(&destination)?;
+        let key = parse_wif(&wif)?;
+        // Segwit needs compressed keys, old paper wallets only have p2pkh coins
+        let watch_types = match key.compressed {
+            true => vec!["p2pkh", "p2wpkh", "p2sh-p2wpkh"],
+            false => vec!["p2pkh"],
+        };
+        let watch_types = watch_types
+            .into_iter()
+            .map(String::from)
+            .collect::<Vec<_>>();
+        let addresses = self.key_addresses(&key, &watch_types)?;
+        let scripts = addresses
+            .iter()
+            .map(|address| address.script_pubkey())
+            .collect::<HashSet<_>>();
+        let reserved = self.reserved_outpoints()?;
+        let immature = self.immature_coins();
+        let coins = self
+            .wallet
+            .utxos
+            .borrow()
+            .iter()
+            .filter(|(outpoint, _)| !reserved.contains(*outpoint))
+            .filter(|(outpoint, _)| !immature.contains_key(*outpoint))
+            .filter(|(_, out)| scripts.contains(&out.script_pubkey))
+            .map(|(outpoint, out)| (*outpoint, out.clone()))
+            .collect::<Vec<_>>();
+        if coins.is_empty() {
+            return Err("No confirmed coins found for this key, import it and rescan first".into());
+        }
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: coins
+                .iter()
+                .map(|(outpoint, _)| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: destination.script_pubkey(),
+            }],
+        };
+        let total = coins.iter().map(|(_, out)| out.value).sum::<u64>();
+        let fee = estimate_vsize(&tx, coins.iter().map(|(_, out)| out)) * fee_rate;
+        let amount = total.saturating_sub(fee);
+        if amount < destination.script_pubkey().dust_value().to_sat() {
+            return Err(format!(
+                "Sweeping {total} sats with a {fee} sats fee would leave a dust output"
+            ));
+        }
+        tx.output[0].value = amount;
+        let prevouts = coins.into_iter().map(|(_, out)| out).collect::<Vec<_>>();
+        self.sign_inputs(&mut tx, &prevouts, &key)?;
+
+        let sweep = SweepResult {
+            tx: hex::encode(consensus::serialize(&tx)),
+            amount,
+            fee,
+            vsize: TxSizeInfo::new(&tx).vsize,
+        };
+        serde_json::to_string(&sweep).map_err(|e| e.to_string())
+    }
+    /// Builds a transaction merging up to `max_inputs` of our smallest spendable coins into a
+    /// single output to `destination`, paying `fee_rate` sat/vB. Coins worth less than what
+    /// it costs to spend them are skipped and reported as uneconomical. Returns the unsigned
+    /// transaction, in hex, with its fee and how many coins it saves, as JSON.
+    pub unsafe fn build_consolidation(
+        &self,
+        max_inputs: u32,
+        fee_rate: u64,
+        destination: String,
+    ) -> Result<String, String> {
+        let destination = parse_address(&destination)?;
+        let reserved = self.reserved_outpoints()?;
+        let immature = self.immature_coins();
+        let mut coins = self
+            .wallet
+            .utxos
+            .borrow()
+            .iter()
+            .filter(|(outpoint, _)| !reserved.contains(*outpoint))
+            .filter(|(outpoint, _)| !immature.contains_key(*outpoint))
+            .filter(|(_, out)| self.wallet.signing_key(&out.script_pubkey).is_some())
+            .map(|(outpoint, out)| (*outpoint, out.clone()))
+            .collect::<Vec<_>>();
+        coins.sort_by_key(|(outpoint, out)| (out.value, *outpoint));
+
+        let mut selected = Vec::new();
+        let mut uneconomical = Vec::new();
+        for (outpoint, out) in coins {
+            if out.value <= input_vsize(&out.script_pubkey) * fee_rate {
+                uneconomical.push(outpoint);
+            } else if selected.len() < max_inputs as usize {
+                selected.push((outpoint, out));
+            }
+        }
+        if selected.len() < 2 {
+            return Err("Not enough economical coins to consolidate".into());
+        }
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: selected
+                .iter()
+                .map(|(outpoint, _)| TxIn {
+                    previous_output: *outpoint,
+                    script_sig: Script::new(),
+                    sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                    witness: Witness::new(),
+                })
+                .collect(),
+            output: vec![TxOut {
+                value: 0,
+                script_pubkey: destination.script_pubkey(),
+            }],
+        };
+        let total = selected.iter().map(|(_, out)| out.value).sum::<u64>();
+        let fee = estimate_vsize(&tx, selected.iter().map(|(_, out)| out)) * fee_rate;
+        let amount = total.saturating_sub(fee);
+        if amount < destination.script_pubkey().dust_value().to_sat() {
+            return Err(format!(
+                "Consolidating {total} sats with a {fee} sats fee would leave a dust output"
+            ));
+        }
+        tx.output[0].value = amount;
+
+        let consolidation = Consolidation {
+            tx: hex::encode(consensus::serialize(&tx)),
+            fee,
+            amount,
+            utxo_reduction: selected.len() as u32 - 1,
+            uneconomical,
+        };
+        serde_json::to_string(&consolidation).map_err(|e| e.to_string())
+    }
+    /// Disassembles a script, given in hex, like the `asm` fields of Bitcoin Core. Parts that
+    /// can't be parsed are shown as `[error]` instead of failing.
+    pub unsafe fn script_asm(script_hex: String) -> Result<String, String> {
+        let script = Script::from(hex::decode(script_hex).map_err(|_| "Invalid hex")?);
+        Ok(script_to_asm(&script))
+    }
+    /// Checks whether nodes with default settings will relay a transaction, given in hex.
+    /// `prevouts` is a JSON array with the output spent by each input, as `{"value": sats,
+    /// "script_pubkey": "<hex>"}`. Returns a JSON array with every rule broken, each with a
+    /// `rule` name and a `detail`, empty if the transaction is standard.
+    pub unsafe fn check_standardness(tx: String, prevouts: String) -> Result<String, String> {
+        let tx: Transaction =
+            deserialize(&hex::decode(tx).map_err(|_| "Invalid hex")?).map_err(|e| e.to_string())?;
+        let prevouts: Vec<TxOut> = serde_json::from_str(&prevouts).map_err(|e| e.to_string())?;
+        serde_json::to_string(&check_standardness(&tx, &prevouts)).map_err(|e| e.to_string())
+    }
+    /// Tells how the script spent by an input, given as a serialized TxIn in hex, is recovered
+    /// from a leaf with type `spk_ty`, as in the block JSON. Returns JSON with a `kind`:
+    /// `derived` if it's rebuilt from the input, `copied` if the leaf carries the whole script
+    /// (an `Other` leaf, which can't be checked), or `impossible` with a `reason`.
+    pub unsafe fn is_reconstructible(spk_ty: String, input: String) -> Result<String, String> {
+        let spk_ty: ScriptPubkeyType = serde_json::from_str(&spk_ty).map_err(|e| e.to_string())?;
+        let input: TxIn = deserialize(&hex::decode(input).map_err(|_| "Invalid hex")?)
+            .map_err(|e| e.to_string())?;
+        serde_json::to_string(&spk_ty.reconstruction(&input)).map_err(|e| e.to_string())
+    }
+    /// Goes through a block, in the same JSON as `accept_block`, and returns every input whose
+    /// spent script isn't derived from the input itself: the ones that rely on copying an
+    /// `Other` script, and the ones that can't be reconstructed at all. Bridge operators can
+    /// use this to find blocks that would fail under strict verification.
+    pub unsafe fn check_leaf_reconstruction(block: String) -> Result<String, String> {
+        let block: WasmBlock = serde_json::from_str(&block).map_err(|e| e.to_string())?;
+        let unprovable = leaf_spending_inputs(&block.block.txdata)
+            .into_iter()
+            .zip(block.leaf_data.iter())
+            .filter_map(
+                |((txid, vin, input), leaf)| match leaf.spk_ty.reconstruction(input) {
+                    LeafReconstruction::Derived => None,
+                    reconstruction => Some(UnprovableInput {
+                        txid,
+                        vin,
+                        reconstruction,
+                    }),
+                },
+            )
+            .collect::<Vec<_>>();
+        serde_json::to_string(&unprovable).map_err(|e| e.to_string())
+    }
+    /// Returns the size, stripped size, weight and virtual size of a transaction, given in
+    /// hex, as JSON
+    pub unsafe fn tx_size_info(tx: String) -> Result<String, String> {
+        let tx: Transaction =
+            deserialize(&hex::decode(tx).map_err(|_| "Invalid hex")?).map_err(|e| e.to_string())?;
+        serde_json::to_string(&TxSizeInfo::new(&tx)).map_err(|e| e.to_string())
+    }
+    /// Returns the fee rate, in sat/vB, of a transaction given in hex that pays `fee` sats
+    pub unsafe fn fee_rate(tx: String, fee: u64) -> Result<f64, String> {
+        let tx: Transaction =
+            deserialize(&hex::decode(tx).map_err(|_| "Invalid hex")?).map_err(|e| e.to_string())?;
+        Ok(TxSizeInfo::new(&tx).fee_rate(fee))
+    }
+    /// Signs every input of `tx` with `key`. `prevouts` are the outputs spent by each input,
+    /// which must be p2pkh, p2wpkh or p2sh-p2wpkh.
+    pub(crate) fn sign_inputs(
+        &self,
+        tx: &mut Transaction,
+        prevouts: &[TxOut],
+        key: &PrivateKey,
+    ) -> Result<(), String> {
+        let unsigned = tx.clone();
+        let mut cache = SighashCache::new(&unsigned);
+        for (vin, prevout) in prevouts.iter().enumerate() {
+            let public_key = key.public_key(&self.secp);
+            let script = &prevout.script_pubkey;
+            let sighash = if script.is_p2pkh() {
+                cache
+                    .legacy_signature_hash(vin, script, EcdsaSighashType::All.to_u32())
+                    .map_err(|e| e.to_string())?
+            } else {
+                let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+                cache
+                    .segwit_signature_hash(vin, &script_code, prevout.value, EcdsaSighashType::All)
+                    .map_err(|e| e.to_string())?
+            };
+            let message = Message::from_slice(&sighash[..]).map_err(|e| e.to_string())?;
+            let signature = EcdsaSig::sighash_all(self.secp.sign_ecdsa(&message, &key.inner));
+
+            let input = &mut tx.input[vin];
+            if script.is_p2pkh() {
+                input.script_sig = Builder::new()
+                    .push_slice(&signature.to_vec())
+                    .push_key(&public_key)
+                    .into_script();
+            } else {
+                if script.is_p2sh() {
+                    let wpubkey_hash = public_key
+                        .wpubkey_hash()
+                        .ok_or("Uncompressed keys can't be used with segwit")?;
+                    let redeem_script = Script::new_v0_p2wpkh(&wpubkey_hash);
+                    input.script_sig = Builder::new()
+                        .push_slice(redeem_script.as_bytes())
+                        .into_script();
+                }
+                input.witness = Witness::from_vec(vec![signature.to_vec(), public_key.to_bytes()]);
+            }
+        }
+        Ok(())
+    }
+    /// Labels a transaction, address, outpoint, public key or xpub. `kind` is one of the BIP329
+    /// types: "tx", "addr", "pubkey", "input", "output" or "xpub". `reference` is the txid,
+    /// address, outpoint as `txid:vout`, or key being labeled. An empty label removes it.
+    /// We don't need to know about the thing being labeled, e.g. before a rescan finds it.
+    pub unsafe fn set_label(
+        &self,
+        kind: String,
+        reference: String,
+        label: String,
+    ) -> Result<(), String> {
+        let kind: LabelKind =
+            serde_json::from_value(kind.into()).map_err(|_| "Unknown label kind")?;
+        let reference = kind.normalize(&reference)?;
+        let mut labels = self.wallet.labels.borrow_mut();
+        if label.is_empty() {
+            labels.remove(&(kind, reference));
+            return Ok(());
+        }
+        labels.insert(
+            (kind, reference.clone()),
+            Bip329Label {
+                kind,
+                reference,
+                label,
+                origin: None,
+                spendable: None,
+            },
+        );
+        Ok(())
+    }
+    /// Returns the label set for something with `set_label` or `import_labels`, if any
+    pub unsafe fn get_label(
+        &self,
+        kind: String,
+        reference: String,
+    ) -> Result<Option<String>, String> {
+        let kind: LabelKind =
+            serde_json::from_value(kind.into()).map_err(|_| "Unknown label kind")?;
+        Ok(self.wallet.label(kind, &kind.normalize(&reference)?))
+    }
+    /// Returns every label in the BIP329 format: one JSON object per line
+    pub unsafe fn export_labels(&self) -> Result<String, String> {
+        self.wallet
+            .labels
+            .borrow()
+            .values()
+            .map(|label| serde_json::to_string(label).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, _>>()
+            .map(|lines| lines.join("\n"))
+    }
+    /// Imports labels in the BIP329 format, replacing the ones we have for the same things.
+    /// If any line is invalid, nothing is imported. Returns how many labels were imported.
+    pub unsafe fn import_labels(&self, jsonl: String) -> Result<u32, String> {
+        let labels = jsonl
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                let mut label: Bip329Label =
+                    serde_json::from_str(line).map_err(|e| format!("Line {}: {e}", i + 1))?;
+                label.reference = label
+                    .kind
+                    .normalize(&label.reference)
+                    .map_err(|e| format!("Line {}: {e}", i + 1))?;
+                Ok(label)
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let count = labels.len() as u32;
+        let mut stored = self.wallet.labels.borrow_mut();
+        for label in labels {
+            stored.insert((label.kind, label.reference.clone()), label);
+        }
+        Ok(count)
+    }
+    /// Returns the coins we can spend, because we imported their private keys, as a JSON array.
+    /// Coinbase outputs are only listed once they mature.
+    pub unsafe fn list_spendable(&self) -> Result<String, String> {
+        let immature = self.immature_coins();
+        let utxos = self
+            .wallet
+            .utxos
+            .borrow()
+            .iter()
+            .filter(|(outpoint, _)| !immature.contains_key(*outpoint))
+            .filter(|(_, out)| self.wallet.signing_key(&out.script_pubkey).is_some())
+            .filter_map(|(outpoint, out)| {
+                let address = Address::from_script(&out.script_pubkey, CHAIN_NETWORK)?;
+                let label = self
+                    .wallet
+                    .label(LabelKind::Output, &outpoint.to_string())
+                    .or_else(|| self.wallet.label(LabelKind::Addr, &address.to_string()));
+                Some(SpendableUtxo {
+                    outpoint: *outpoint,
+                    value: out.value,
+                    address: address.to_string(),
+                    asm: script_to_asm(&out.script_pubkey),
+                    label,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&utxos).map_err(|e| e.to_string())
+    }
+    /// Adds many addresses at once. `addresses` is a JSON array of address strings, returns a
+    /// JSON array with one result per entry, in the same order: `ok` if the address was added,
+    /// `duplicate` if we were already watching it, or `error` with the reason it's invalid.
+    /// Invalid entries don't prevent the other ones from being added.
+    pub unsafe fn add_addresses(&self, addresses: String) -> Result<String, String> {
+        let addresses: Vec<String> = serde_json::from_str(&addresses).map_err(|e| e.to_string())?;
+        let mut address_set = self.wallet.address_set.borrow_mut();
+        let results = addresses
+            .iter()
+            .map(|address| match parse_address(address) {
+                Ok(address) if address_set.contains(&address.script_pubkey()) => {
+                    AddressImportResult::Duplicate
+                }
+                Ok(address) => match self.wallet.check_watch_capacity(address_set.len(), 1) {
+                    Ok(()) => {
+                        self.wallet.watch(&mut address_set, address.script_pubkey());
+                        AddressImportResult::Ok
+                    }
+                    Err(error) => AddressImportResult::Error { error },
+                },
+                Err(error) => AddressImportResult::Error { error },
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&results).map_err(|e| e.to_string())
+    }
+    /// Imports the descriptors from Bitcoin Core's `listdescriptors`, either its whole output
+    /// or the `descriptors` array. `wsh(sortedmulti(...))` descriptors are watched like
+    /// `add_multisig_descriptor`, up to the end of their range, and `addr(...)` like
+    /// `add_address`. Other descriptor types are reported as errors. Each entry's timestamp
+    /// sets where rescans for its scripts start. Returns a JSON array with one result per
+    /// descriptor, in the same order, like `add_addresses`.
+    pub unsafe fn import_core_descriptors(&self, descriptors: String) -> Result<String, String> {
+        let descriptors = match serde_json::from_str(&descriptors).map_err(|e| e.to_string())? {
+            CoreDescriptors::Wallet { descriptors } | CoreDescriptors::List(descriptors) => {
+                descriptors
+            }
+        };
+        let results = descriptors
+            .into_iter()
+            .map(|descriptor| {
+                let created_at = match &descriptor.timestamp {
+                    Some(serde_json::Value::Number(time)) => time
+                        .as_u64()
+                        .map(|time| self.height_at_time(time.saturating_sub(TIMESTAMP_WINDOW))),
+                    Some(serde_json::Value::String(now)) if now == "now" => {
+                        Some(self.best_header().0)
+                    }
+                    _ => None,
+                };
+                let (status, descriptor_id) =
+                    match self.import_core_descriptor(&descriptor, created_at) {
+                        Ok((status, id)) => (status, id),
+                        Err(error) => (AddressImportResult::Error { error }, None),
+                    };
+                CoreImportResult {
+                    entry: descriptor.desc,
+                    status,
+                    descriptor_id,
+                    created_at,
+                    key_imported: false,
+                }
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&results).map_err(|e| e.to_string())
+    }
+    /// Watches one descriptor from `import_core_descriptors`, returning the new descriptor's
+    /// id if it was a multisig one
+    pub(crate) fn import_core_descriptor(
+        &self,
+        descriptor: &CoreDescriptor,
+        created_at: Option<u32>,
+    ) -> Result<(AddressImportResult, Option<u32>), String> {
+        let desc = descriptor.desc.split('#').next().unwrap_or_default().trim();
+        if let Some(address) = desc.strip_prefix("addr(").and_then(|a| a.strip_suffix(')')) {
+            let address = parse_address(address)?;
+            return Ok((self.watch_address(&address, created_at)?, None));
+        }
+        let multisig = MultisigDescriptor::parse(desc, Vec::new()).map_err(|_| {
+            "Only wsh(sortedmulti(...)) and addr(...) descriptors can be imported".to_string()
+        })?;
+        let (first, _) = multisig.derive(&self.secp, 0)?;
+        let first = Script::new_v0_p2wsh(&first.wscript_hash());
+        if self.wallet.multisig_scripts.borrow().contains_key(&first) {
+            return Ok((AddressImportResult::Duplicate, None));
+        }
+        let end = match &descriptor.range {
+            Some(serde_json::Value::Number(end)) => end.as_u64(),
+            Some(serde_json::Value::Array(range)) => range.get(1).and_then(|end| end.as_u64()),
+            _ => Some(GAP_LIMIT as u64 - 1),
+        }
+        .and_then(|end| u32::try_from(end).ok())
+        .ok_or("Invalid range")?;
+        let id = self.wallet.descriptors.borrow().len() as u32;
+        self.wallet.descriptors.borrow_mut().push(multisig);
+        if let Err(error) = self
+            .wallet
+            .derive_multisig(&self.secp, id, end.saturating_add(1))
+        {
+            self.wallet.descriptors.borrow_mut().pop();
+            return Err(error);
+        }
+        if let Some(created_at) = created_at {
+            let mut created = self.wallet.created_at.borrow_mut();
+            for (script, derived) in self.wallet.multisig_scripts.borrow().iter() {
+                if derived.descriptor == id {
+                    created.entry(script.clone()).or_insert(created_at);
+                }
+            }
+        }
+        Ok((AddressImportResult::Ok, Some(id)))
+    }
+    /// Imports the addresses in a legacy Bitcoin Core `dumpwallet` file, along with their
+    /// labels and creation times. Private keys are ignored unless `import_keys` is set, in
+    /// which case they are imported like `import_privkey`, for the address types listed.
+    /// HD seeds are always skipped. Returns a JSON array with one result per address.
+    pub unsafe fn import_core_dump(
+        &self,
+        dump: String,
+        import_keys: Option<bool>,
+    ) -> Result<String, String> {
+        let mut results = Vec::new();
+        for line in dump.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // `<key or script> <time> <flags...> # addr=<addresses> [hdkeypath=...]`
+            let (fields, comment) = line.split_once('#').unwrap_or((line, ""));
+            let fields = fields.split_whitespace().collect::<Vec<_>>();
+            let flags = fields.get(2..).unwrap_or_default();
+            if flags
+                .iter()
+                .any(|flag| flag.starts_with("hdseed=") || flag.starts_with("inactivehdseed="))
+            {
+                continue;
+            }
+            let is_script = flags.iter().any(|flag| flag.starts_with("script="));
+            let label = flags
+                .iter()
+                .find_map(|flag| flag.strip_prefix("label="))
+                .map(percent_decode)
+                .filter(|label| !label.is_empty());
+            let created_at = fields
+                .get(1)
+                .and_then(|time| parse_iso8601(time))
+                .map(|time| self.height_at_time(time.saturating_sub(TIMESTAMP_WINDOW)));
+            let addresses = comment
+                .split_whitespace()
+                .find_map(|item| item.strip_prefix("addr="))
+                .unwrap_or_default();
+            for entry in addresses.split(',').filter(|entry| !entry.is_empty()) {
+                let mut key_imported = false;
+                let status = match parse_address(entry) {
+                    Err(error) => AddressImportResult::Error { error },
+                    Ok(address) => {
+                        let watch_type = match address.address_type() {
+                            Some(bitcoin::AddressType::P2pkh) => Some("p2pkh"),
+                            Some(bitcoin::AddressType::P2wpkh) => Some("p2wpkh"),
+                            Some(bitcoin::AddressType::P2sh) => Some("p2sh-p2wpkh"),
+                            _ => None,
+                        };
+                        let status = self
+                            .watch_address(&address, created_at)
+                            .unwrap_or_else(|error| AddressImportResult::Error { error });
+                        if let (true, false, Some(watch_type), Some(key)) = (
+                            import_keys.unwrap_or(false),
+                            is_script,
+                            watch_type,
+                            fields.first(),
+                        ) {
+                            key_imported = self.watch_key(key, &[watch_type.into()]).is_ok();
+                        }
+                        if let Some(label) = &label {
+                            self.set_label("addr".into(), entry.into(), label.clone())?;
+                        }
+                        status
+                    }
+                };
+                results.push(CoreImportResult {
+                    entry: entry.into(),
+                    status,
+                    descriptor_id: None,
+                    created_at,
+                    key_imported,
+                });
+            }
+        }
+        serde_json::to_string(&results).map_err(|e| e.to_string())
+    }
+    /// Watches one address, starting rescans for it at `created_at` if given
+    pub(crate) fn watch_address(
+        &self,
+        address: &Address,
+        created_at: Option<u32>,
+    ) -> Result<AddressImportResult, String> {
+        let mut address_set = self.wallet.address_set.borrow_mut();
+        let script = address.script_pubkey();
+        if address_set.contains(&script) {
+            return Ok(AddressImportResult::Duplicate);
+        }
+        self.wallet.check_watch_capacity(address_set.len(), 1)?;
+        self.wallet.watch(&mut address_set, script.clone());
+        if let Some(created_at) = created_at {
+            self.wallet
+                .created_at
+                .borrow_mut()
+                .insert(script, created_at);
+        }
+        Ok(AddressImportResult::Ok)
+    }
+    /// The first height in our best chain with a block timestamped at or after `time`. Block
+    /// times aren't monotonic, callers should leave some margin. Heights below the headers we
+    /// have can't be told apart, so the lowest one we have is returned for those.
+    pub(crate) fn height_at_time(&self, time: u64) -> u32 {
+        let time = u32::try_from(time).unwrap_or(u32::MAX);
+        let (mut low, mut high) = (self.store.lowest_indexed_height(), self.best_header().0);
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let header = self
+                .chain_state
+                .get_block_hash(mid)
+                .and_then(|hash| self.chain_state.get_block_header(&hash));
+            match header {
+                Ok(header) if header.time >= time => high = mid,
+                Ok(_) => low = mid + 1,
+                Err(_) => return low,
+            }
+        }
+        low
+    }
+    /// Limits how many scripts the wallet may watch, since every watched script makes
+    /// filtering blocks more expensive. Adding addresses beyond this limit fails with a
+    /// "watch list full" error. Pass undefined to remove the limit.
+    pub unsafe fn set_max_watched_scripts(&self, max: Option<u32>) {
+        *self.wallet.max_watched_scripts.borrow_mut() = max.map(|max| max as usize);
+    }
+    /// How many scripts the wallet is watching
+    pub unsafe fn watch_set_size(&self) -> u32 {
+        self.wallet.address_set.borrow().len() as u32
+    }
+    /// Removes data the store doesn't need anymore: headers for stale branches forking more
+    /// than 100 blocks below the tip, and index entries above the tip left behind by reorgs.
+    /// Returns a JSON report with how many entries were removed and bytes freed. This is safe
+    /// to call at any time between blocks.
+    pub unsafe fn compact_store(&self) -> Result<String, String> {
+        self.check_writable()?;
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let report = self
+            .store
+            .compact(tip_height)
+            .map_err(|e| format!("{e:?}"))?;
+        serde_json::to_string(&report).map_err(|e| e.to_string())
+    }
+    /// Encrypts everything this chain stores with ChaCha20-Poly1305, using the given 32-byte
+    /// key. Data already in the store gets encrypted too. Values that fail authentication,
+    /// because the key is wrong or they were tampered with, come back as database errors.
+    pub unsafe fn set_store_cipher(&self, key: Vec<u8>) -> Result<(), String> {
+        self.check_writable()?;
+        let mut counter = [0; 8];
+        self.fill_random(&mut counter)?;
+        self.store.set_cipher(&key, u64::from_le_bytes(counter))
+    }
+    /// Checks that the best chain in our store is consistent: every height up to the tip has
+    /// an index entry, whose header exists, hashes to the indexed value and links to its
+    /// parent. Returns a JSON report with the last consistent height and the problems found.
+    pub unsafe fn verify_chainstate(&self) -> Result<String, String> {
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let report = self.store.verify_chain(tip_height);
+        serde_json::to_string(&report).map_err(|e| e.to_string())
+    }
+    /// Brings the chain back to a consistent state after `verify_chainstate` found problems,
+    /// by truncating the best chain to the validated tip and dropping wallet data above it.
+    /// Headers above the validated tip will be downloaded again. If the inconsistency is below
+    /// the validated tip we can't roll the accumulator back, so this fails and the chain must
+    /// be rebuilt from a trusted snapshot. Returns the height we truncated to.
+    pub unsafe fn repair_chainstate(&mut self) -> Result<u32, String> {
+        self.check_writable()?;
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let validated_height = self
+            .chain_state
+            .get_validation_index()
+            .map_err(|e| format!("{e:?}"))?;
+        let report = self.store.verify_chain(tip_height);
+        match report.last_consistent_height {
+            Some(height) if height >= validated_height => {}
+            height => {
+                return Err(format!(
+                    "The chain is only consistent up to {height:?}, but we validated up to \
+                     {validated_height}. Rebuild it from a trusted snapshot"
+                ))
+            }
+        }
+
+        let validated_hash = self
+            .store
+            .get_decoded::<BlockHash>(&format!("index{validated_height}"))
+            .map_err(|e| format!("{e:?}"))?
+            .ok_or("Missing index entry for the validated tip")?;
+        let stale_index = self
+            .store
+            .store
+            .borrow()
+            .keys()
+            .filter(|key| {
+                key.strip_prefix("index")
+                    .and_then(|height| height.parse::<u32>().ok())
+                    .map_or(false, |height| height > validated_height)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in stale_index {
+            self.store.remove(&key);
+        }
+        let best_chain = floresta_chain::BestChain::from((validated_hash, validated_height));
+        self.store
+            .save_height(&best_chain)
+            .map_err(|e| format!("{e:?}"))?;
+
+        self.chain_state = Self::load_chain_state(&self.store, self.param_overrides.as_ref())
+            .map_err(|e| format!("Reloading the chain state: {e}"))?;
+        self.acc_leaves = self.store.stored_leaves();
+        self.check_tip()?;
+        self.wallet.disconnect_above(validated_height);
+        Ok(validated_height)
+    }
+    /// Rebuilds the height index from the headers in our store, for when index entries got lost
+    /// or corrupted but headers survived. We follow the headers back from our tip, or from the
+    /// tip with most work among the stored headers if our tip's header is gone, and rewrite
+    /// the index for that chain. Progress is reported through the progress callback, with
+    /// the "reindex" stage. Returns the new tip height.
+    pub unsafe fn reindex(&mut self) -> Result<u32, String> {
+        self.check_writable()?;
+        let keys = self
+            .store
+            .store
+            .borrow()
+            .keys()
+            .filter(|key| key.len() == 64)
+            .cloned()
+            .collect::<Vec<_>>();
+        let total = keys.len() as u32;
+        let mut headers = HashMap::new();
+        for (done, key) in keys.iter().enumerate() {
+            let Ok(hash) = BlockHash::from_str(key) else {
+                continue;
+            };
+            if let Ok(Some(header)) = self
+                .store
+                .get_decoded::<floresta_chain::DiskBlockHeader>(key)
+            {
+                headers.insert(hash, header);
+            }
+            if done % 1000 == 0 {
+                self.report_progress("reindex", done as u32, total);
+            }
+        }
+
+        let recorded_tip = self.chain_state.get_best_block().ok();
+        let (tip, tip_height) = match recorded_tip {
+            Some((height, hash)) if headers.contains_key(&hash) => (hash, height),
+            _ => {
+                // Our tip is gone, so pick the stored chain with most work
+                let parents = headers
+                    .values()
+                    .map(|header| header.prev_blockhash)
+                    .collect::<HashSet<_>>();
+                let tip = headers
+                    .keys()
+                    .filter(|hash| !parents.contains(hash))
+                    .max_by_key(|hash| {
+                        let mut work = Uint256::from_u64(0).expect("zero fits in a u64");
+                        let mut current = headers.get(*hash);
+                        while let Some(header) = current {
+                            work = work + header.work();
+                            current = headers.get(&header.prev_blockhash);
+                        }
+                        work
+                    })
+                    .copied()
+                    .ok_or("There are no headers in our store")?;
+                let height = headers[&tip]
+                    .height()
+                    .ok_or("Can't tell the height of the best stored tip")?;
+                (tip, height)
+            }
+        };
+
+        // Walk back from the tip, as far as we have headers
+        let mut chain = Vec::new();
+        let mut current = Some(tip);
+        while let Some(hash) = current {
+            let Some(header) = headers.get(&hash) else {
+                break;
+            };
+            chain.push(hash);
+            current = Some(header.prev_blockhash);
+        }
+
+        let old_index = self
+            .store
+            .store
+            .borrow()
+            .keys()
+            .filter(|key| key.starts_with("index"))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in old_index {
+            self.store.remove(&key);
+        }
+        let base_height = tip_height + 1 - chain.len() as u32;
+        for (i, hash) in chain.iter().rev().enumerate() {
+            let height = base_height + i as u32;
+            self.store
+                .update_block_index(height, *hash)
+                .map_err(|e| format!("{e:?}"))?;
+            if i % 1000 == 0 {
+                self.report_progress("reindex", i as u32, chain.len() as u32);
+            }
+        }
+
+        if recorded_tip.map(|(_, hash)| hash) != Some(tip) {
+            let best_chain = floresta_chain::BestChain::from((tip, tip_height));
+            self.store
+                .save_height(&best_chain)
+                .map_err(|e| format!("{e:?}"))?;
+            self.chain_state = Self::load_chain_state(&self.store, self.param_overrides.as_ref())
+                .map_err(|e| format!("Reloading the chain state: {e}"))?;
+            self.acc_leaves = self.store.stored_leaves();
+        }
+        self.report_progress("reindex", chain.len() as u32, chain.len() as u32);
+        self.check_tip()?;
+        Ok(tip_height)
+    }
+    /// Sets a function called as `callback(stage, done, total)` to report progress during
+    /// long running operations, like `reindex`.
+    pub unsafe fn set_progress_callback(&mut self, callback: js_sys::Function) {
+        self.progress_callback = Some(callback);
+    }
+    /// Sets a function called as `callback(n)` that must return a Uint8Array with n random
+    /// bytes, e.g. from `crypto.getRandomValues`. Every key and nonce we generate uses it.
+    /// Without one, we use the platform's random number generator.
+    pub unsafe fn set_entropy_source(&mut self, callback: Option<js_sys::Function>) {
+        self.entropy_source = callback;
+    }
+    /// Fills `buf` with random bytes, from the entropy source if we have one
+    pub(crate) fn fill_random(&self, buf: &mut [u8]) -> Result<(), String> {
+        let Some(source) = &self.entropy_source else {
+            return getrandom::getrandom(buf).map_err(|e| e.to_string());
+        };
+        let bytes = source
+            .call1(&wasm_bindgen::JsValue::NULL, &(buf.len() as u32).into())
+            .map_err(|e| format!("Entropy source failed: {e:?}"))?;
+        let bytes = js_sys::Uint8Array::new(&bytes);
+        if bytes.length() as usize != buf.len() {
+            return Err(format!(
+                "Entropy source returned {} bytes, expected {}",
+                bytes.length(),
+                buf.len()
+            ));
+        }
+        bytes.copy_to(buf);
+        Ok(())
+    }
+    pub(crate) fn report_progress(&self, stage: &str, done: u32, total: u32) {
+        if let Some(callback) = &self.progress_callback {
+            let _ = callback.call3(
+                &wasm_bindgen::JsValue::NULL,
+                &stage.into(),
+                &done.into(),
+                &total.into(),
+            );
+        }
+    }
+    /// Given a block locator from someone else, as a JSON array of block hashes ordered from
+    /// their tip backwards, finds the most recent entry that is in our best chain. Returns
+    /// that height and hash, along with up to 2000 of our headers after it, as JSON. If
+    /// nothing in the locator is known, we start from genesis, or our snapshot.
+    pub unsafe fn locate_fork(&self, locator: String) -> Result<String, String> {
+        let locator: Vec<BlockHash> = serde_json::from_str(&locator).map_err(|e| e.to_string())?;
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+
+        let fork_height = locator
+            .iter()
+            .find_map(|hash| self.best_chain_height(hash))
+            .unwrap_or_else(|| self.store.lowest_indexed_height());
+        let fork_hash = self
+            .chain_state
+            .get_block_hash(fork_height)
+            .map_err(|e| format!("{e:?}"))?;
+
+        let last = tip_height.min(fork_height + MAX_HEADERS_RESULTS);
+        let headers = (fork_height + 1..=last)
+            .map(|height| {
+                let hash = self
+                    .chain_state
+                    .get_block_hash(height)
+                    .map_err(|e| format!("{e:?}"))?;
+                let header = self
+                    .chain_state
+                    .get_block_header(&hash)
+                    .map_err(|e| format!("{e:?}"))?;
+                Ok(hex::encode(consensus::serialize(&header)))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let fork = ForkPoint {
+            height: fork_height,
+            hash: fork_hash,
+            headers,
+        };
+        serde_json::to_string(&fork).map_err(|e| e.to_string())
+    }
+    /// The lowest height we have a header for. Headers from here up to our tip are linked to
+    /// each other, for chains built from a snapshot this goes down as `backfill_headers` is
+    /// called, until it reaches genesis.
+    #[wasm_bindgen(getter, js_name = "verified_from_height")]
+    pub unsafe fn verified_from_height(&self) -> u32 {
+        self.store.lowest_indexed_height()
+    }
+    /// Adds headers below the snapshot this chain was built from, so we can verify the header
+    /// chain back to genesis. `data` is a batch of serialized 80 byte headers, the highest of
+    /// which must be the parent of the header at `verified_from_height`. They may be in
+    /// ascending or descending order, but must be contiguous.
+    /// Every header must have valid proof of work and link to the one before it, otherwise the
+    /// whole batch is rejected. Returns the new `verified_from_height`.
+    pub unsafe fn backfill_headers(&mut self, data: js_sys::Uint8Array) -> Result<u32, String> {
+        self.check_writable()?;
+        if let Err(fits) = self.check_memory_budget(data.length() as usize, HEADER_BATCH_EXPANSION)
+        {
+            return Err(format!(
+                "{} bytes of headers exceed the memory budget, split them in batches of at most {} headers",
+                data.length(),
+                fits / 80
+            ));
+        }
+        let data = data.to_vec();
+        if data.is_empty() || data.len() % 80 != 0 {
+            return Err("Data must be a non-empty list of 80 byte headers".into());
+        }
+        let lowest_height = self.store.lowest_indexed_height();
+        let count = (data.len() / 80) as u32;
+        if count > lowest_height {
+            return Err(format!("We only miss {lowest_height} headers, got {count}"));
+        }
+        let lowest_hash = self
+            .chain_state
+            .get_block_hash(lowest_height)
+            .map_err(|e| format!("{e:?}"))?;
+        let lowest_header = self
+            .chain_state
+            .get_block_header(&lowest_hash)
+            .map_err(|e| format!("{e:?}"))?;
+
+        let params = self.params();
+        let start_height = lowest_height - count;
+        let mut headers = data
+            .chunks(80)
+            .map(|header| deserialize::<BlockHeader>(header).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<_>, String>>()?;
+        // Some sources walk back from their tip, sending the newest header first
+        let descending = headers.len() >= 2 && headers[0].prev_blockhash == headers[1].block_hash();
+        if descending {
+            let gap = headers
+                .windows(2)
+                .position(|pair| pair[0].prev_blockhash != pair[1].block_hash());
+            if let Some(i) = gap {
+                return Err(format!(
+                    "Headers {i} and {} of the batch aren't contiguous",
+                    i + 1
+                ));
+            }
+            headers.reverse();
+        }
+        if start_height == 0 && headers[0].block_hash() != params.genesis.block_hash() {
+            return Err("The first header isn't our genesis".into());
+        }
+        for (i, header) in headers.iter().enumerate() {
+            let height = start_height + i as u32;
+            let target = header.target();
+            if target > params.pow_limit || header.validate_pow(&target).is_err() {
+                return Err(format!("Invalid proof of work at height {height}"));
+            }
+            let next = headers.get(i + 1).unwrap_or(&lowest_header);
+            if next.prev_blockhash != header.block_hash() {
+                return Err(format!(
+                    "Header at height {} doesn't build on the one at {height}",
+                    height + 1
+                ));
+            }
+        }
+
+        for (i, header) in headers.into_iter().enumerate() {
+            let height = start_height + i as u32;
+            self.store
+                .save_header(&floresta_chain::DiskBlockHeader::HeadersOnly(
+                    header, height,
+                ))
+                .map_err(|e| format!("{e:?}"))?;
+            self.store
+                .update_block_index(height, header.block_hash())
+                .map_err(|e| format!("{e:?}"))?;
+        }
+        Ok(start_height)
+    }
+    /// Sets how often the store is flushed while blocks get accepted: at most once every
+    /// `every_n_blocks` blocks, or once `max_ms` milliseconds passed since the last flush,
+    /// whichever comes first. 0 disables either limit. Call `flush_store` after a batch of
+    /// blocks, so nothing is left unflushed. Exports always flush first.
+    pub unsafe fn set_flush_policy(&self, every_n_blocks: u32, max_ms: f64) {
+        let mut policy = self.store.flush_policy.get();
+        policy.every_n_blocks = every_n_blocks;
+        policy.max_ms = max_ms;
+        self.store.flush_policy.set(policy);
+    }
+    /// Writes our chain state to the store and flushes it, ignoring the flush policy
+    pub unsafe fn flush_store(&self) -> Result<(), String> {
+        self.check_writable()?;
+        self.chain_state.flush().map_err(|e| format!("{e:?}"))?;
+        self.store.flush_now().map_err(|e| format!("{e:?}"))
+    }
+    /// Exports our best chain for backups, in chunks of up to 2000 headers starting at `start`.
+    /// Returns JSON with the format `version`, the `entries` as `{height, hash, header}` with
+    /// the header in hex, and the height to pass as `start` for the `next` chunk, or null once
+    /// we reach the tip. Heights below our snapshot are skipped. This format doesn't depend on
+    /// how the store keeps things, and only changes along with `version`.
+    pub unsafe fn export_block_index(&self, start: u32) -> Result<String, String> {
+        // Read only views have nothing of their own to flush
+        if !self.read_only {
+            self.flush_store()?;
+        }
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let start = start.max(self.store.lowest_indexed_height());
+        let end = tip_height.min(start.saturating_add(MAX_HEADERS_RESULTS - 1));
+        let entries = (start..=end)
+            .map(|height| {
+                let hash = self
+                    .chain_state
+                    .get_block_hash(height)
+                    .map_err(|e| format!("{e:?}"))?;
+                let header = self
+                    .chain_state
+                    .get_block_header(&hash)
+                    .map_err(|e| format!("{e:?}"))?;
+                Ok(BlockIndexEntry {
+                    height,
+                    hash,
+                    header: hex::encode(consensus::serialize(&header)),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let chunk = BlockIndexChunk {
+            version: BACKUP_FORMAT_VERSION,
+            entries,
+            next: (end < tip_height).then_some(end + 1),
+        };
+        serde_json::to_string(&chunk).map_err(|e| e.to_string())
+    }
+    /// Exports the rest of our chain state for backups, as JSON: the format `version`, our
+    /// network, tip and height, the accumulator roots and leaf count, and where our snapshot
+    /// came from, if we started from one. See `export_block_index` for the headers.
+    pub unsafe fn export_misc_state(&self) -> Result<String, String> {
+        // Read only views have nothing of their own to flush
+        if !self.read_only {
+            self.flush_store()?;
+        }
+        let (height, tip) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let state = MiscState {
+            version: BACKUP_FORMAT_VERSION,
+            network: self.show_network(),
+            height,
+            tip,
+            roots: self
+                .chain_state
+                .get_root_hashes()
+                .iter()
+                .map(|root| root.to_string())
+                .collect(),
+            leaves: self.acc_leaves,
+            snapshot_provenance: self
+                .store
+                .snapshot_provenance()
+                .map_err(|e| format!("{e:?}"))?,
+        };
+        serde_json::to_string(&state).map_err(|e| e.to_string())
+    }
+    /// Returns up to `count` headers from our best chain, starting at `start`, as JSON. At most
+    /// 2000 headers are returned at once, and requests going past the tip are truncated. If
+    /// part of the range is below our snapshot, those heights are listed as unavailable.
+    pub unsafe fn get_headers(&self, start: u32, count: u32) -> Result<String, String> {
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let base = self.store.lowest_indexed_height();
+        let count = count.min(MAX_HEADERS_RESULTS);
+        let end = start.saturating_add(count).min(tip_height + 1);
+
+        let unavailable = (start < base && count > 0).then(|| (start, base.min(end) - 1));
+        let headers = (start.max(base)..end)
+            .map(|height| {
+                let hash = self
+                    .chain_state
+                    .get_block_hash(height)
+                    .map_err(|e| format!("{e:?}"))?;
+                let header = self
+                    .chain_state
+                    .get_block_header(&hash)
+                    .map_err(|e| format!("{e:?}"))?;
+                Ok(HeaderJson {
+                    hash,
+                    height,
+                    time: header.time,
+                    bits: header.bits,
+                    nonce: header.nonce,
+                    prev: header.prev_blockhash,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let page = HeadersPage {
+            headers,
+            unavailable,
+        };
+        serde_json::to_string(&page).map_err(|e| e.to_string())
+    }
+    /// Returns the hash of the block after `hash` in our best chain, or null if `hash` is our
+    /// tip. Fails if `hash` is unknown or in a stale branch, since there's no meaningful next
+    /// block in that case.
+    pub unsafe fn next_block_hash(&self, hash: String) -> Result<Option<String>, String> {
+        Ok(self.walk_best_chain(&hash, 1)?.pop())
+    }
+    /// Returns up to `n` hashes following `hash` in our best chain, as a JSON array. Fails if
+    /// `hash` is unknown or in a stale branch.
+    pub unsafe fn walk(&self, hash: String, n: u32) -> Result<String, String> {
+        let hashes = self.walk_best_chain(&hash, n)?;
+        serde_json::to_string(&hashes).map_err(|e| e.to_string())
+    }
+    pub(crate) fn walk_best_chain(&self, hash: &str, n: u32) -> Result<Vec<String>, String> {
+        let hash = BlockHash::from_str(hash).map_err(|_| "Invalid block hash")?;
+        let height = match self.best_chain_height(&hash) {
+            Some(height) => height,
+            None if self.chain_state.get_block_header(&hash).is_ok() => {
+                return Err(format!("Block {hash} is not in our best chain"))
+            }
+            None => return Err(format!("Unknown block {hash}")),
+        };
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+
+        (height + 1..=tip_height.min(height.saturating_add(n)))
+            .map(|height| {
+                self.chain_state
+                    .get_block_hash(height)
+                    .map(|hash| hash.to_string())
+                    .map_err(|e| format!("{e:?}"))
+            })
+            .collect()
+    }
+    /// Returns how many confirmations a block has: 1 for the tip, 2 for its parent and so on.
+    /// Blocks we know about that are in a stale branch return -1, unknown blocks are an error.
+    pub unsafe fn block_confirmations(&self, hash: String) -> Result<i32, String> {
+        let hash = BlockHash::from_str(&hash).map_err(|_| "Invalid block hash")?;
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        match self.best_chain_height(&hash) {
+            Some(height) => Ok((tip_height - height + 1) as i32),
+            None if self.chain_state.get_block_header(&hash).is_ok() => Ok(-1),
+            None => Err(format!("Unknown block {hash}")),
+        }
+    }
+    /// Returns how many confirmations a wallet transaction has, 0 if it's still pending. A
+    /// pending transaction conflicting with a confirmed one returns -1, since it will never
+    /// confirm. Transactions we don't know about are an error.
+    pub unsafe fn tx_confirmations(&self, txid: String) -> Result<i32, String> {
+        let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid")?;
+        if let Some(height) = self.wallet.confirmation_height(&txid) {
+            let (tip_height, _) = self
+                .chain_state
+                .get_best_block()
+                .map_err(|e| format!("{e:?}"))?;
+            return Ok((tip_height.saturating_sub(height) + 1) as i32);
+        }
+        if self.wallet.is_conflicted(&txid) {
+            return Ok(-1);
+        }
+        if self.wallet.pending.borrow().contains_key(&txid) {
+            return Ok(0);
+        }
+        Err(format!("Unknown transaction {txid}"))
+    }
+    /// Returns the leaf one of our confirmed coins is in the accumulator as, so a bridge can
+    /// be asked for its proof. The JSON has the same `header_code`, `amount` and `spk_ty` as
+    /// the leaf data in blocks, along with the creating `block_hash`, the full
+    /// `script_pubkey` and the `leaf_hash`. Below our snapshot the block hash comes from the
+    /// hash table, `hash_source` tells which one was used.
+    pub unsafe fn utxo_leaf_data(&self, txid: String, vout: u32) -> Result<String, String> {
+        let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid")?;
+        let leaf = self.leaf_data(OutPoint { txid, vout })?;
+        serde_json::to_string(&leaf).map_err(|e| e.to_string())
+    }
+    /// See `utxo_leaf_data`
+    pub(crate) fn leaf_data(&self, prevout: OutPoint) -> Result<UtxoLeafData, String> {
+        let txid = prevout.txid;
+        let utxo = self
+            .wallet
+            .utxos
+            .borrow()
+            .get(&prevout)
+            .cloned()
+            .ok_or("Not one of our coins")?;
+        let key = *self
+            .wallet
+            .tx_index
+            .borrow()
+            .get(&txid)
+            .ok_or("This coin isn't confirmed yet")?;
+        let coinbase = self
+            .wallet
+            .history
+            .borrow()
+            .get(&key)
+            .ok_or("This coin's transaction is missing from the history")?
+            .coinbase;
+        let height = key.0;
+        let (block_hash, hash_source) = match self.chain_state.get_block_hash(height) {
+            Ok(hash) => (hash, "store"),
+            Err(_) => (
+                self.get_block_hash(height)
+                    .ok_or_else(|| format!("We don't know the block at height {height}"))?,
+                "hash_table",
+            ),
+        };
+        let header_code = height << 1 | coinbase as u32;
+        let leaf_hash = floresta_chain::pruned_utreexo::udata::LeafData {
+            block_hash,
+            prevout,
+            header_code,
+            utxo: utxo.clone(),
+        }
+        ._get_leaf_hashes();
+        Ok(UtxoLeafData {
+            leaf: CompLeafData {
+                header_code,
+                amount: utxo.value,
+                spk_ty: ScriptPubkeyType::from_script(&utxo.script_pubkey),
+            },
+            block_hash,
+            hash_source,
+            script_pubkey: hex::encode(utxo.script_pubkey.as_bytes()),
+            leaf_hash,
+        })
+    }
+    /// Returns our confirmed coins that can be spent now, for coin selection done somewhere
+    /// else, as `{"version": 1, "height": tip, "utxos": [...]}`. Each coin has its
+    /// `outpoint`, `amount`, `script_pubkey`, `confirmations`, the `leaf` a bridge needs to
+    /// prove it, as in `utxo_leaf_data`, and for coins of a multisig descriptor, the
+    /// `derivation` of each key. Coins reserved with `mark_reserved` and immature coinbases
+    /// are left out.
+    pub unsafe fn export_utxos_psbt_ready(&self) -> Result<String, String> {
+        let (height, _) = self.best_header();
+        let reserved = self.reserved_outpoints()?;
+        let maturity = self.coinbase_maturity();
+        let mut coins = self
+            .wallet
+            .utxos
+            .borrow()
+            .iter()
+            .filter(|(outpoint, _)| !reserved.contains(*outpoint))
+            .map(|(outpoint, out)| (*outpoint, out.clone()))
+            .collect::<Vec<_>>();
+        coins.sort_by_key(|(outpoint, _)| *outpoint);
+
+        let mut utxos = Vec::new();
+        for (outpoint, out) in coins {
+            // Unconfirmed coins have no leaf yet
+            let Ok(leaf) = self.leaf_data(outpoint) else {
+                continue;
+            };
+            let created = leaf.leaf.header_code >> 1;
+            let confirmations = height.saturating_sub(created) + 1;
+            if leaf.leaf.header_code & 1 == 1 && confirmations < maturity {
+                continue;
+            }
+            let derivation = self
+                .wallet
+                .multisig_scripts
+                .borrow()
+                .get(&out.script_pubkey)
+                .map(|derived| self.wallet.utxo_derivation(derived));
+            utxos.push(ExportedUtxo {
+                outpoint,
+                amount: out.value,
+                script_pubkey: hex::encode(out.script_pubkey.as_bytes()),
+                confirmations,
+                derivation,
+                leaf,
+            });
+        }
+        let export = UtxoExport {
+            version: UTXO_EXPORT_VERSION,
+            height,
+            utxos,
+        };
+        serde_json::to_string(&export).map_err(|e| e.to_string())
+    }
+    /// Reserves some of our coins for `ttl_blocks` blocks, so we don't select them ourselves,
+    /// e.g. because they were picked by coin selection running somewhere else. `outpoints` is
+    /// a JSON array of `txid:vout` strings. Reserving a coin again replaces its expiry.
+    /// Reservations are saved in the store, and end once they expire or the coin is spent.
+    /// Returns how many coins are reserved now.
+    pub unsafe fn mark_reserved(&self, outpoints: String, ttl_blocks: u32) -> Result<u32, String> {
+        self.check_writable()?;
+        let outpoints = serde_json::from_str::<Vec<String>>(&outpoints)
+            .map_err(|e| e.to_string())?
+            .iter()
+            .map(|outpoint| {
+                OutPoint::from_str(outpoint).map_err(|_| format!("Invalid outpoint {outpoint}"))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let utxos = self.wallet.utxos.borrow();
+        if let Some(outpoint) = outpoints
+            .iter()
+            .find(|outpoint| !utxos.contains_key(*outpoint))
+        {
+            return Err(format!("{outpoint} isn't one of our coins"));
+        }
+        let (height, _) = self.best_header();
+        let mut reservations = self
+            .store
+            .utxo_reservations()
+            .map_err(|e| format!("{e:?}"))?;
+        reservations
+            .retain(|outpoint, expires_at| *expires_at > height && utxos.contains_key(outpoint));
+        for outpoint in outpoints {
+            reservations.insert(outpoint, height.saturating_add(ttl_blocks));
+        }
+        self.store.set_utxo_reservations(&reservations);
+        Ok(reservations.len() as u32)
+    }
+    /// Coins reserved with `mark_reserved` that we shouldn't select
+    pub(crate) fn reserved_outpoints(&self) -> Result<HashSet<OutPoint>, String> {
+        let (height, _) = self.best_header();
+        let reservations = self
+            .store
+            .utxo_reservations()
+            .map_err(|e| format!("{e:?}"))?;
+        Ok(reservations
+            .into_iter()
+            .filter(|(_, expires_at)| *expires_at > height)
+            .map(|(outpoint, _)| outpoint)
+            .collect())
+    }
+    /// Same as `tx_confirmations`, for a JSON array of up to 500 txids. Returns an array with
+    /// the `txid` and `status` of each: `unknown`, `pending`, `conflicted`, or `confirmed`
+    /// with its `height` and `confirmations`. Txids that don't parse are `invalid`, without
+    /// failing the others.
+    pub unsafe fn tx_statuses(&self, txids: String) -> Result<String, String> {
+        let txids: Vec<String> = serde_json::from_str(&txids).map_err(|e| e.to_string())?;
+        if txids.len() > MAX_TX_STATUSES {
+            return Err(format!(
+                "At most {MAX_TX_STATUSES} txids can be asked at once"
+            ));
+        }
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let statuses = txids
+            .into_iter()
+            .map(|given| {
+                let status = match Txid::from_str(&given) {
+                    Err(_) => TxStatus::Invalid,
+                    Ok(txid) => match self.wallet.confirmation_height(&txid) {
+                        Some(height) => TxStatus::Confirmed {
+                            height,
+                            confirmations: tip_height.saturating_sub(height) + 1,
+                        },
+                        None if self.wallet.is_conflicted(&txid) => TxStatus::Conflicted,
+                        None if self.wallet.pending.borrow().contains_key(&txid) => {
+                            TxStatus::Pending
+                        }
+                        None => TxStatus::Unknown,
+                    },
+                };
+                TxStatusEntry {
+                    txid: given,
+                    status,
+                }
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&statuses).map_err(|e| e.to_string())
+    }
+    /// Returns the height of a block if it is in our best chain, or None if we don't know it
+    /// or it's in a stale branch.
+    pub(crate) fn best_chain_height(&self, hash: &BlockHash) -> Option<u32> {
+        let height = self.chain_state.get_block_height(hash).ok().flatten()?;
+        match self.chain_state.get_block_hash(height) {
+            Ok(indexed) if indexed == *hash => Some(height),
+            _ => None,
+        }
+    }
+    /// Whether the last block `session` scanned is still in our best chain
+    pub(crate) fn rescan_resumable(&self, session: &RescanSession) -> bool {
+        let Some(last) = session.last_scanned else {
+            return true;
+        };
+        matches!(
+            self.chain_state.get_block_hash(session.next_height - 1),
+            Ok(hash) if hash == last
+        )
+    }
+    /// Read only views must not change the store they share with a writer
+    pub(crate) fn check_writable(&self) -> Result<(), String> {
+        check_poisoned()?;
+        if self.read_only {
+            return Err("This is a read only view of the store".into());
+        }
+        Ok(())
+    }
+    /// A cheap consistency check, making sure our tip has a header and an index entry
+    /// pointing to it. Used instead of letting a broken store panic somewhere else.
+    pub(crate) fn check_tip(&self) -> Result<(), String> {
+        let (height, hash) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("Can't load the tip: {e:?}"))?;
+        self.chain_state
+            .get_block_header(&hash)
+            .map_err(|_| format!("Missing header for the tip {hash}"))?;
+        match self
+            .store
+            .get_decoded::<BlockHash>(&format!("index{height}"))
+        {
+            Ok(Some(indexed)) if indexed == hash => Ok(()),
+            _ => Err(format!(
+                "Index entry at height {height} doesn't match the tip"
+            )),
+        }
+    }
+    /// Returns counters about what this chain has been doing, as JSON
+    pub unsafe fn get_metrics(&self) -> Result<String, String> {
+        let mut metrics = self.metrics.clone();
+        metrics.watched_scripts = self.wallet.address_set.borrow().len();
+        metrics.store = self.store.stats.borrow().clone();
+        serde_json::to_string(&metrics).map_err(|e| e.to_string())
+    }
+    /// Estimates how many bytes the store, wallet and caches are using, as JSON. The numbers
+    /// are kept up to date as data comes and goes, so this is cheap to call.
+    pub unsafe fn estimated_state_size(&self) -> Result<String, String> {
+        let mut size = self.state_size();
+        size.events = Some(EventOccupancy {
+            header_events: self.header_events.events.len(),
+            rejected_blocks: self
+                .store
+                .rejected_blocks()
+                .map_err(|e| format!("{e:?}"))?
+                .len(),
+            reorged_transactions: self.wallet.reorged.borrow().len(),
+        });
+        serde_json::to_string(&size).map_err(|e| e.to_string())
+    }
+    pub(crate) fn state_size(&self) -> StateSize {
+        let store = self.store.bytes_stored.get();
+        let wallet = *self.wallet.size.borrow();
+        let header_cache = self.store.header_cache.borrow().len() * CACHED_HEADER_SIZE;
+        StateSize {
+            store,
+            wallet,
+            header_cache,
+            total: store + wallet.scripts + wallet.transactions + wallet.utxos + header_cache,
+            budget: self.memory_budget,
+            events: None,
+        }
+    }
+    /// Sets how many bytes we may use. Bulk inputs, like blocks and header batches, that
+    /// would need more than what's left of it are refused with a suggested size to split
+    /// them into, instead of failing to allocate. It's reported by `estimated_state_size`.
+    pub unsafe fn set_memory_budget(&mut self, bytes: usize) {
+        self.memory_budget = bytes;
+    }
+    /// Checks whether we can take an input of `len` bytes that needs `expansion` bytes of
+    /// memory per byte, returning the biggest input we could take otherwise
+    pub(crate) fn check_memory_budget(&self, len: usize, expansion: usize) -> Result<(), usize> {
+        let available = self.memory_budget.saturating_sub(self.state_size().total);
+        match len.checked_mul(expansion) {
+            Some(needed) if needed <= available => Ok(()),
+            _ => Err(available / expansion),
+        }
+    }
+    /// Sets every counter returned by `get_metrics` back to zero
+    pub unsafe fn reset_metrics(&mut self) {
+        self.metrics = Metrics::default();
+        *self.store.stats.borrow_mut() = StoreStats::default();
+    }
+    /// Returns the first height a watched address received something at, or undefined if it
+    /// was never used.
+    pub unsafe fn address_first_seen(&self, addr: String) -> Result<Option<u32>, String> {
+        let address = parse_address(&addr)?;
+        Ok(self
+            .wallet
+            .first_seen
+            .borrow()
+            .get(&address.script_pubkey())
+            .copied())
+    }
+    /// Returns what we know about a watched address as JSON
+    pub unsafe fn address_info(&self, addr: String) -> Result<String, String> {
+        let address = parse_address(&addr)?;
+        let script = address.script_pubkey();
+        if !self.wallet.address_set.borrow().contains(&script) {
+            return Err("Address is not being watched".into());
+        }
+        let info = AddressInfo {
+            address: address.to_string(),
+            script_pubkey: hex::encode(script.as_bytes()),
+            first_seen: self.wallet.first_seen.borrow().get(&script).copied(),
+            created_at: self.wallet.created_at.borrow().get(&script).copied(),
+            receive_count: self
+                .wallet
+                .stats
+                .borrow()
+                .receive_counts
+                .get(&script)
+                .copied()
+                .unwrap_or(0),
+        };
+        serde_json::to_string(&info).map_err(|e| e.to_string())
+    }
+    /// Sets a function called as `callback(txid, vout, script_hex, amount)` for every output
+    /// in a scanned block that doesn't pay to one of our scripts. If it returns something
+    /// truthy, the output is treated as ours, e.g. for silent payments scanning done in JS.
+    /// If it throws too many times in a row it's dropped. Pass nothing to remove it.
+    pub unsafe fn set_script_classifier(&self, callback: Option<js_sys::Function>) {
+        *self.wallet.script_classifier.borrow_mut() = callback.map(|callback| ScriptClassifier {
+            callback,
+            failures: 0,
+        });
+    }
+    /// Sets the height rescans start at, for addresses added without a creation height
+    pub unsafe fn set_wallet_birthday(&self, height: u32) {
+        *self.wallet.birthday.borrow_mut() = height;
+    }
+    /// Returns the height a rescan should start at to find every transaction for our
+    /// addresses. Each address starts at its creation height if we know it, or the wallet
+    /// birthday otherwise. Addresses imported from a snapshot start right after it.
+    pub unsafe fn rescan_from(&self) -> u32 {
+        let birthday = *self.wallet.birthday.borrow();
+        let created_at = self.wallet.created_at.borrow();
+        let synced_to = self.wallet.synced_to.borrow();
+        self.wallet
+            .address_set
+            .borrow()
+            .iter()
+            .map(|script| {
+                let start = created_at.get(script).copied().unwrap_or(birthday);
+                match synced_to.get(script) {
+                    Some(synced) => start.max(synced + 1),
+                    None => start,
+                }
+            })
+            .min()
+            .unwrap_or(birthday)
+    }
+    /// Restores a wallet from a snapshot taken by another instance at `height`, so we don't
+    /// need to rescan from the birthday. `snapshot` is a JSON object with the `addresses` to
+    /// watch and every `transactions` touching them up to `height`, as `{"tx": "<hex>",
+    /// "height": n}` objects. After importing, `rescan_from` returns `height + 1` for those
+    /// addresses, and only the blocks after it need to go through `rescan_block`. Only works
+    /// on an empty wallet.
+    pub unsafe fn import_wallet_at_height(
+        &self,
+        snapshot: String,
+        height: u32,
+    ) -> Result<(), String> {
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        if height > tip_height {
+            return Err(format!(
+                "Snapshot height {height} is above our tip at {tip_height}"
+            ));
+        }
+        if !self.wallet.history.borrow().is_empty() || !self.wallet.utxos.borrow().is_empty() {
+            return Err("Can only import a snapshot into an empty wallet".into());
+        }
+        let snapshot: WalletSnapshot =
+            serde_json::from_str(&snapshot).map_err(|e| e.to_string())?;
+        let scripts = snapshot
+            .addresses
+            .iter()
+            .map(|address| parse_address(address).map(|address| address.script_pubkey()))
+            .collect::<Result<HashSet<_>, _>>()?;
+        let mut transactions = snapshot
+            .transactions
+            .into_iter()
+            .map(|SnapshotTx { tx, height }| {
+                let tx: Transaction = deserialize(&hex::decode(tx).map_err(|_| "Invalid hex")?)
+                    .map_err(|e| e.to_string())?;
+                Ok((height, tx))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        if let Some((tx_height, tx)) = transactions
+            .iter()
+            .find(|(tx_height, _)| *tx_height > height)
+        {
+            return Err(format!(
+                "Transaction {} at height {tx_height} is above the snapshot height",
+                tx.txid()
+            ));
+        }
+
+        let mut address_set = self.wallet.address_set.borrow_mut();
+        let new_scripts = scripts
+            .iter()
+            .filter(|script| !address_set.contains(script))
+            .count();
+        self.wallet
+            .check_watch_capacity(address_set.len(), new_scripts)?;
+        let mut synced_to = self.wallet.synced_to.borrow_mut();
+        for script in scripts {
+            synced_to.insert(script.clone(), height);
+            self.wallet.watch(&mut address_set, script);
+        }
+        drop(address_set);
+
+        // Replay the history in chain order, so spends find the coins they spend
+        transactions.sort_by_key(|(height, _)| *height);
+        for (position, (height, tx)) in transactions.iter().enumerate() {
+            let spent_coins = {
+                let utxos = self.wallet.utxos.borrow();
+                tx.input
+                    .iter()
+                    .filter_map(|input| {
+                        let coin = utxos.get(&input.previous_output)?;
+                        Some((input.previous_output, coin.clone()))
+                    })
+                    .collect::<HashMap<_, _>>()
+            };
+            self.wallet.scan_transaction(
+                &self.secp,
+                tx,
+                &ScriptKey::outputs(tx),
+                *height,
+                // We don't know the real position, but this keeps the snapshot order
+                position as u32,
+                &spent_coins,
+                &mut FilterCost::default(),
+            );
+        }
+        Ok(())
+    }
+    /// Scans a block we already have for wallet transactions, without validating it again.
+    /// This takes the same JSON as `accept_block`, and is used to rescan blocks after adding
+    /// new addresses, starting at `rescan_from`. Blocks should be given in ascending order,
+    /// so spends of coins found during the rescan are picked up. The leaf data is used to
+    /// recover the spent coins, so fees come out the same as if the block was just connected.
+    /// While a rescan started with `start_rescan` is going on, only its next block is accepted.
+    pub unsafe fn rescan_block(&mut self, block: String) -> Result<(), String> {
+        check_poisoned()?;
+        let block: WasmBlock = serde_json::from_str(&block).map_err(|e| e.to_string())?;
+        let hash = block.block.block_hash();
+        let height = self
+            .chain_state
+            .get_block_height(&hash)
+            .ok()
+            .flatten()
+            .ok_or("Block is not in our chain")?;
+        let session = match self.read_only {
+            true => None,
+            false => self.store.rescan_session().map_err(|e| format!("{e:?}"))?,
+        };
+        if let Some(session) = &session {
+            if height != session.next_height {
+                return Err(format!(
+                    "The rescan expects block {}, got {height}",
+                    session.next_height
+                ));
+            }
+        }
+        let (_, inputs) = self
+            .process_proof(block.leaf_data, &block.block.txdata, height)
+            .map_err(|e| format!("Process Proof: {e:?}"))?;
+        self.wallet
+            .scan_block_for_wallet(&self.secp, &block.block, height, &inputs);
+        if let Some(mut session) = session {
+            session.next_height = height + 1;
+            session.last_scanned = Some(hash);
+            let done = session.next_height > session.end;
+            self.store.set_rescan_session((!done).then_some(&session));
+        }
+        Ok(())
+    }
+    /// Starts a rescan from `rescan_from` up to `to`, or our tip. Its progress is saved in
+    /// the store after every block, so it can be picked up with `resume_rescan` after a
+    /// restart. Replaces any rescan in progress. Returns the first height to scan.
+    pub unsafe fn start_rescan(&mut self, to: Option<u32>) -> Result<u32, String> {
+        self.check_writable()?;
+        let start = self.rescan_from();
+        let tip = self.best_header().0;
+        let end = to.unwrap_or(tip);
+        if end > tip {
+            return Err(format!("Can't rescan past our tip at {tip}"));
+        }
+        if start > end {
+            return Err(format!("Nothing to rescan, we start at {start}"));
+        }
+        self.store.set_rescan_session(Some(&RescanSession {
+            start,
+            end,
+            next_height: start,
+            last_scanned: None,
+            started_at: unix_time(),
+        }));
+        Ok(start)
+    }
+    /// Returns the rescan in progress as JSON, with its `start` and `end` heights, the
+    /// `next_height` to scan, how many blocks were `scanned` and are `remaining`, and whether
+    /// it's `resumable`. Returns null if there's no rescan going on.
+    pub unsafe fn rescan_status(&self) -> Result<String, String> {
+        let Some(session) = self.store.rescan_session().map_err(|e| format!("{e:?}"))? else {
+            return Ok("null".into());
+        };
+        let status = RescanStatus {
+            scanned: session.next_height - session.start,
+            remaining: session.end + 1 - session.next_height,
+            resumable: self.rescan_resumable(&session),
+            session: &session,
+        };
+        serde_json::to_string(&status).map_err(|e| e.to_string())
+    }
+    /// Picks up the rescan saved in the store, returning the height of the next block to pass
+    /// to `rescan_block`. Fails if there's none, or if the last block it scanned was reorged
+    /// out, in which case it should be started again.
+    pub unsafe fn resume_rescan(&self) -> Result<u32, String> {
+        let session = self
+            .store
+            .rescan_session()
+            .map_err(|e| format!("{e:?}"))?
+            .ok_or("There's no rescan to resume")?;
+        if !self.rescan_resumable(&session) {
+            return Err("The last block rescanned is no longer in our chain, start over".into());
+        }
+        Ok(session.next_height)
+    }
+    /// Watches a taproot output, given its internal key and script tree. `leaves` is a JSON array
+    /// of `{"depth": n, "script": "<hex>"}` objects, in the same DFS order they appear in a `tr()`
+    /// descriptor. A `leaf_version` may also be given, the default is tapscript (0xc0). Returns
+    /// the address for this output.
+    pub unsafe fn add_taproot_output(
+        &self,
+        internal_key: String,
+        leaves: String,
+    ) -> Result<String, String> {
+        let internal_key =
+            XOnlyPublicKey::from_str(&internal_key).map_err(|_| "Invalid internal key")?;
+        let leaves: Vec<TapLeafJson> = serde_json::from_str(&leaves).map_err(|e| e.to_string())?;
+
+        let mut builder = TaprootBuilder::new();
+        let mut watched_leaves = Vec::new();
+        for leaf in leaves {
+            let script = Script::from(hex::decode(leaf.script).map_err(|_| "Invalid leaf script")?);
+            let version = LeafVersion::from_consensus(leaf.leaf_version.unwrap_or(0xc0))
+                .map_err(|_| "Invalid leaf version")?;
+            builder = builder
+                .add_leaf_with_ver(leaf.depth, script.clone(), version)
+                .map_err(|e| e.to_string())?;
+            watched_leaves.push((script, version));
+        }
+        let spend_info = builder
+            .finalize(&self.secp, internal_key)
+            .map_err(|_| "Incomplete script tree")?;
+        let output_key: TweakedPublicKey = spend_info.output_key();
+        let address = Address::p2tr_tweaked(output_key, CHAIN_NETWORK);
+
+        let mut address_set = self.wallet.address_set.borrow_mut();
+        if !address_set.contains(&address.script_pubkey()) {
+            self.wallet.check_watch_capacity(address_set.len(), 1)?;
+        }
+        self.wallet.watch(&mut address_set, address.script_pubkey());
+        self.wallet.taproot_outputs.borrow_mut().insert(
+            address.script_pubkey(),
+            TaprootWatch {
+                output_key: output_key.to_inner(),
+                leaves: watched_leaves,
+            },
+        );
+        Ok(address.to_string())
+    }
+    /// Watches a `wsh(sortedmulti(k, xpub/.../*, ...))` descriptor, deriving the scripts for
+    /// indexes 0 up to `range`. `labels` is a JSON array naming each cosigner, in the same
+    /// order the keys appear in the descriptor. When one of the coins is spent, the history
+    /// tells which cosigners signed it. Returns an id for this descriptor.
+    pub unsafe fn add_multisig_descriptor(
+        &self,
+        descriptor: String,
+        labels: String,
+        range: u32,
+    ) -> Result<u32, String> {
+        let labels: Vec<String> = serde_json::from_str(&labels).map_err(|e| e.to_string())?;
+        let descriptor = MultisigDescriptor::parse(&descriptor, labels)?;
+        let id = self.wallet.descriptors.borrow().len() as u32;
+        self.wallet.descriptors.borrow_mut().push(descriptor);
+        if let Err(error) = self.wallet.derive_multisig(&self.secp, id, range) {
+            self.wallet.descriptors.borrow_mut().pop();
+            return Err(error);
+        }
+        // A cheap check that what we have still adds up, `audit_wallet` does the full one
+        let audit = self.wallet.audit(&self.secp, false, false);
+        if !audit.is_clean() {
+            log(format!(
+                "Wallet audit after importing descriptor {id}: {} orphaned transactions, {} unwatched coins",
+                audit.orphaned.len(),
+                audit.unwatched_utxos.len()
+            ));
+        }
+        Ok(id)
+    }
+    /// Derives the scripts of every multisig descriptor again, and checks that each history
+    /// entry and coin still belongs to a script we watch. Returns what doesn't as JSON. If
+    /// `prune` is set, those scripts, entries and coins are dropped and stats recomputed.
+    pub unsafe fn audit_wallet(&self, prune: bool) -> Result<String, String> {
+        let audit = self.wallet.audit(&self.secp, true, prune);
+        serde_json::to_string(&audit).map_err(|e| e.to_string())
+    }
+    /// Returns the first address of a multisig descriptor that never received anything after
+    /// the last used one. Calling this again returns the same address until it gets funds.
+    /// More addresses are derived and watched as needed, so there are always 20 unused ones
+    /// after the last used address.
+    pub unsafe fn next_unused_address(&self, descriptor_id: u32) -> Result<String, String> {
+        let next = self
+            .wallet
+            .highest_used_index(descriptor_id)?
+            .map_or(0, |used| used + 1);
+        self.wallet
+            .derive_multisig(&self.secp, descriptor_id, next + GAP_LIMIT)?;
+        let (witness_script, _) =
+            self.wallet.descriptors.borrow()[descriptor_id as usize].derive(&self.secp, next)?;
+        Ok(Address::p2wsh(&witness_script, CHAIN_NETWORK).to_string())
+    }
+    /// Returns, for each multisig descriptor, the highest index we derived, the highest one
+    /// that received something, and how many unused addresses come after it, as JSON
+    pub unsafe fn derivation_status(&self) -> Result<String, String> {
+        let count = self.wallet.descriptors.borrow().len() as u32;
+        let status = (0..count)
+            .map(|id| {
+                let derived = self.wallet.descriptors.borrow()[id as usize].derived;
+                let highest_used = self.wallet.highest_used_index(id)?;
+                Ok(DerivationStatus {
+                    id,
+                    highest_derived: derived.checked_sub(1),
+                    highest_used,
+                    gap: derived - highest_used.map_or(0, |used| used + 1),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        serde_json::to_string(&status).map_err(|e| e.to_string())
+    }
+    /// Tells the wallet about an unconfirmed transaction that pays to us or spends our coins.
+    /// `prevouts` is a JSON array with the value, in satoshis, of the output spent by each
+    /// input, or null if unknown. Values for coins the wallet already knows are filled in
+    /// automatically, if we can't find all of them, the fee for this transaction is unknown.
+    pub unsafe fn add_pending_transaction(
+        &self,
+        tx: String,
+        prevouts: String,
+    ) -> Result<(), String> {
+        check_poisoned()?;
+        let tx: Transaction =
+            deserialize(&hex::decode(tx).map_err(|_| "Invalid hex")?).map_err(|e| e.to_string())?;
+        let prevouts: Vec<Option<u64>> =
+            serde_json::from_str(&prevouts).map_err(|e| e.to_string())?;
+        if !self.wallet.is_relevant(&tx) {
+            return Err("Transaction doesn't touch our wallet".into());
+        }
+
+        let mut input_value = Some(0_u64);
+        for (vin, input) in tx.input.iter().enumerate() {
+            let outpoint = input.previous_output;
+            let value = prevouts
+                .get(vin)
+                .copied()
+                .flatten()
+                .or_else(|| {
+                    self.wallet
+                        .utxos
+                        .borrow()
+                        .get(&outpoint)
+                        .map(|out| out.value)
+                })
+                .or_else(|| {
+                    let pending = self.wallet.pending.borrow();
+                    let parent = pending.get(&outpoint.txid)?;
+                    parent
+                        .tx
+                        .output
+                        .get(outpoint.vout as usize)
+                        .map(|out| out.value)
+                });
+            input_value = input_value.zip(value).map(|(total, value)| total + value);
+        }
+        let output_value = tx.output.iter().map(|out| out.value).sum::<u64>();
+        let fee = input_value.and_then(|input_value| input_value.checked_sub(output_value));
+
+        self.wallet
+            .pending
+            .borrow_mut()
+            .insert(tx.txid(), PendingTx { tx, fee });
+        Ok(())
+    }
+    /// Returns the fee rate for the package of unconfirmed wallet transactions containing
+    /// `txid`, and how much a child would need to pay to bring the package to
+    /// `target_fee_rate` (in sat/vB). Only transactions the wallet knows about are considered,
+    /// if some ancestor or fee is unknown, the result is flagged as incomplete.
+    pub unsafe fn package_fee_info(
+        &self,
+        txid: String,
+        target_fee_rate: f64,
+    ) -> Result<String, String> {
+        let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid")?;
+        let info = self.wallet.package_fee_info(txid, target_fee_rate)?;
+        serde_json::to_string(&info).map_err(|e| e.to_string())
+    }
+    /// Returns the details of a wallet transaction as JSON, decoding what its nLockTime and
+    /// the nSequence of its inputs mean, and whether they are satisfied given the current tip.
+    /// `spendable_at` tells the earliest height and median time past at which this transaction
+    /// may be mined. Each input has its `witness` items in hex, annotated with what they seem
+    /// to be, see `annotate_witness`. If its raw data was pruned, only `txid`, `height` and
+    /// `pruned` are set.
+    pub unsafe fn get_transaction(&self, txid: String) -> Result<String, String> {
+        let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid")?;
+        if self.wallet.is_pruned(&txid) {
+            let entry = PrunedTransaction {
+                txid,
+                height: self.wallet.confirmation_height(&txid),
+                pruned: true,
+            };
+            return serde_json::to_string(&entry).map_err(|e| e.to_string());
+        }
+        let (tx, height) = self
+            .wallet
+            .get_transaction(&txid)
+            .ok_or("Unknown transaction")?;
+
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let tip_mtp = self.median_time_past(tip_height);
+        let mut spendable_at = SpendableAt {
+            satisfied: Some(true),
+            ..Default::default()
+        };
+
+        let all_final = tx.input.iter().all(|input| input.sequence.0 == u32::MAX);
+        let lock_time = tx.lock_time.0;
+        let locktime = match lock_time {
+            _ if lock_time == 0 || all_final => LockTimeInfo::Disabled,
+            value if value < LOCKTIME_THRESHOLD => {
+                spendable_at.height = Some(value + 1);
+                LockTimeInfo::Height {
+                    value,
+                    satisfied: value <= tip_height,
+                }
+            }
+            value => {
+                spendable_at.time = Some(value + 1);
+                LockTimeInfo::Time {
+                    value,
+                    satisfied: tip_mtp.map(|mtp| value < mtp),
+                }
+            }
+        };
+        Self::merge_satisfied(&mut spendable_at, locktime.satisfied());
+
+        let mut inputs = Vec::new();
+        for input in tx.input.iter() {
+            let sequence = input.sequence.0;
+            // The height the coin we are spending got confirmed at, if we know it
+            let coin_height = self.wallet.confirmation_height(&input.previous_output.txid);
+            let relative_locktime = if tx.version < 2 || sequence & SEQUENCE_DISABLE_FLAG != 0 {
+                RelativeLockTimeInfo::Disabled
+            } else if sequence & SEQUENCE_TYPE_FLAG != 0 {
+                let seconds = (sequence & SEQUENCE_MASK) << 9;
+                // The clock starts at the median time past of the block before the coin's
+                let unlocks_at = coin_height
+                    .and_then(|height| self.median_time_past(height.checked_sub(1)?))
+                    .map(|mtp| mtp + seconds);
+                if let Some(unlocks_at) = unlocks_at {
+                    spendable_at.time = spendable_at.time.max(Some(unlocks_at));
+                }
+                RelativeLockTimeInfo::Time {
+                    seconds,
+                    satisfied: unlocks_at.zip(tip_mtp).map(|(at, mtp)| at <= mtp),
+                }
+            } else {
+                let value = (sequence & SEQUENCE_MASK) as u16;
+                let unlocks_at = coin_height.map(|height| height + value as u32);
+                if let Some(unlocks_at) = unlocks_at {
+                    spendable_at.height = spendable_at.height.max(Some(unlocks_at));
+                }
+                RelativeLockTimeInfo::Blocks {
+                    value,
+                    satisfied: unlocks_at.map(|at| at <= tip_height + 1),
+                }
+            };
+            Self::merge_satisfied(&mut spendable_at, relative_locktime.satisfied());
+            inputs.push(InputDetails {
+                prevout: input.previous_output,
+                script_sig_asm: script_to_asm(&input.script_sig),
+                sequence,
+                relative_locktime,
+                witness: annotate_witness(&input.witness),
+            });
+        }
+
+        let details = TransactionDetails {
+            txid,
+            height,
+            version: tx.version,
+            locktime,
+            inputs,
+            outputs: tx
+                .output
+                .iter()
+                .map(|out| OutputDetails {
+                    value: out.value,
+                    script_pubkey: hex::encode(out.script_pubkey.as_bytes()),
+                    asm: script_to_asm(&out.script_pubkey),
+                })
+                .collect(),
+            spendable_at,
+            op_returns: op_return_payloads(&tx).iter().map(hex::encode).collect(),
+        };
+        serde_json::to_string(&details).map_err(|e| e.to_string())
+    }
+    /// Exports a proof that one of our transactions was confirmed, that anyone can check with
+    /// `verify_spv_proof` without a chain. It has the transaction, its merkle branch, the
+    /// header of its block and up to 2000 headers built on top of it, as versioned JSON.
+    pub unsafe fn export_spv_proof(&self, txid: String) -> Result<String, String> {
+        let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid")?;
+        let key = *self
+            .wallet
+            .tx_index
+            .borrow()
+            .get(&txid)
+            .ok_or("Not a confirmed wallet transaction")?;
+        let height = key.0;
+        let history = self.wallet.history.borrow();
+        let entry = history
+            .get(&key)
+            .ok_or("Not a confirmed wallet transaction")?;
+        let (position, branch) = entry
+            .merkle_proof
+            .clone()
+            .ok_or("We don't have the merkle branch for this transaction, rescan its block")?;
+
+        let (tip_height, _) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let last = tip_height.min(height + MAX_HEADERS_RESULTS);
+        let mut headers = Vec::new();
+        for height in height..=last {
+            let hash = self
+                .chain_state
+                .get_block_hash(height)
+                .map_err(|e| format!("{e:?}"))?;
+            let header = self
+                .chain_state
+                .get_block_header(&hash)
+                .map_err(|e| format!("{e:?}"))?;
+            headers.push(hex::encode(consensus::serialize(&header)));
+        }
+        let header = headers.remove(0);
+
+        let proof = SpvProof {
+            version: SPV_PROOF_VERSION,
+            network: CHAIN_NETWORK.to_string(),
+            tx: hex::encode(consensus::serialize(
+                entry.tx.as_ref().ok_or("The raw transaction was pruned")?,
+            )),
+            height,
+            position,
+            branch,
+            header,
+            headers,
+        };
+        serde_json::to_string(&proof).map_err(|e| e.to_string())
+    }
+    /// Checks a proof made by `export_spv_proof`: that the transaction is committed to by
+    /// the header, and that every header has valid proof of work for `expected_network` and
+    /// builds on the previous one.
+    ///
+    /// Headers at the pow limit are cheap to mine, so the proof must also be tied to
+    /// something the caller trusts. `anchor` is a JSON object with the `hash` and `height`
+    /// of a block we know is in the best chain, that must be one of the proof's headers.
+    /// `min_work` is the least work, as 32 bytes of big endian hex, the headers must add up
+    /// to. At least one of them must be given. Returns the txid, block hash, how many
+    /// confirmations and how much work the proof shows, and the height if it reaches the
+    /// anchor, as JSON, or an error saying what's wrong.
+    pub unsafe fn verify_spv_proof(
+        proof: String,
+        expected_network: String,
+        anchor: Option<String>,
+        min_work: Option<String>,
+    ) -> Result<String, String> {
+        let anchor = anchor
+            .map(|anchor| serde_json::from_str::<SpvAnchor>(&anchor))
+            .transpose()
+            .map_err(|e| format!("Invalid anchor: {e}"))?;
+        let min_work = min_work
+            .map(|work| parse_uint256(&work).ok_or("The minimum work must be 32 bytes of hex"))
+            .transpose()?;
+        if anchor.is_none() && min_work.is_none() {
+            return Err("An anchor or a minimum work is needed to trust the headers".into());
+        }
+        let proof: SpvProof = serde_json::from_str(&proof).map_err(|e| e.to_string())?;
+        if proof.version != SPV_PROOF_VERSION {
+            return Err(format!("Unsupported proof version {}", proof.version));
+        }
+        if !proof.network.eq_ignore_ascii_case(&expected_network) {
+            return Err(format!("Proof is for {}", proof.network));
+        }
+        let network = match proof.network.to_lowercase().as_str() {
+            "signet" => bitcoin::Network::Signet,
+            "testnet" => bitcoin::Network::Testnet,
+            "bitcoin" | "mainnet" => bitcoin::Network::Bitcoin,
+            "regtest" => bitcoin::Network::Regtest,
+            other => return Err(format!("Unknown network {other}")),
+        };
+        let pow_limit = bitcoin::consensus::params::Params::new(network).pow_limit;
+        let tx: Transaction = deserialize(&hex::decode(&proof.tx).map_err(|_| "Invalid hex")?)
+            .map_err(|e| e.to_string())?;
+        let txid = tx.txid();
+
+        let mut prev_hash: Option<BlockHash> = None;
+        let mut block_hash = None;
+        let mut work = Uint256::from_u64(0).expect("zero fits in a u64");
+        let mut height = None;
+        for (offset, header) in std::iter::once(&proof.header)
+            .chain(proof.headers.iter())
+            .enumerate()
+        {
+            let header: BlockHeader = deserialize(&hex::decode(header).map_err(|_| "Invalid hex")?)
+                .map_err(|e| e.to_string())?;
+            let target = header.target();
+            if target > pow_limit || header.validate_pow(&target).is_err() {
+                return Err(format!(
+                    "Header {} has invalid proof of work",
+                    header.block_hash()
+                ));
+            }
+            match prev_hash {
+                None => {
+                    let root = merkle_root_from_branch(
+                        TxMerkleNode::from_inner(txid.into_inner()),
+                        &proof.branch,
+                        proof.position,
+                    );
+                    if root != header.merkle_root {
+                        return Err("The transaction isn't committed to by the header".into());
+                    }
+                    block_hash = Some(header.block_hash());
+                }
+                Some(prev_hash) if header.prev_blockhash != prev_hash => {
+                    return Err(format!("Header {} doesn't connect", header.block_hash()));
+                }
+                Some(_) => {}
+            }
+            work = work + header.work();
+            prev_hash = Some(header.block_hash());
+            let reached = anchor
+                .as_ref()
+                .filter(|anchor| anchor.hash == header.block_hash());
+            if let Some(anchor) = reached {
+                if proof.height.checked_add(offset as u32) != Some(anchor.height) {
+                    return Err(format!(
+                        "The anchor is at height {}, but the proof puts it at {}",
+                        anchor.height,
+                        proof.height as u64 + offset as u64
+                    ));
+                }
+                height = Some(proof.height);
+            }
+        }
+        if anchor.is_some() && height.is_none() {
+            return Err("The headers don't reach the anchor".into());
+        }
+        if let Some(min_work) = min_work {
+            if work < min_work {
+                return Err(format!(
+                    "The headers only have {work} of work, {min_work} is needed"
+                ));
+            }
+        }
+
+        let result = SpvProofResult {
+            txid,
+            block_hash: block_hash.expect("the first header is always checked"),
+            height,
+            confirmations: proof.headers.len() as u32 + 1,
+            work: work.to_string(),
+        };
+        serde_json::to_string(&result).map_err(|e| e.to_string())
+    }
+    /// Sets which raw wallet transactions we keep: "full" keeps all of them,
+    /// "metadata-after-N-confs" (e.g. "metadata-after-6-confs") drops them once they have N
+    /// confirmations, and "metadata-only" drops them as soon as they confirm. Txids, amounts,
+    /// fees and heights are always kept. Pruning happens as blocks get connected, and pruned
+    /// transactions are returned by `get_transaction` with `pruned` set.
+    pub unsafe fn set_tx_retention(&self, mode: String) -> Result<(), String> {
+        *self.wallet.tx_retention.borrow_mut() = mode.parse()?;
+        Ok(())
+    }
+    /// Returns the data in each OP_RETURN output of a transaction, as a JSON array of hex
+    /// strings. Works for our transactions and for the ones recorded because of a watched
+    /// OP_RETURN prefix.
+    pub unsafe fn get_op_returns(&self, txid: String) -> Result<String, String> {
+        let txid = Txid::from_str(&txid).map_err(|_| "Invalid txid")?;
+        let payloads = match self.wallet.op_returns.borrow().get(&txid) {
+            Some(record) => record.payloads.clone(),
+            // OP_RETURNs of our transactions are recorded when they confirm, so if it was
+            // pruned, it had none
+            None if self.wallet.is_pruned(&txid) => Vec::new(),
+            None => {
+                let (tx, _) = self
+                    .wallet
+                    .get_transaction(&txid)
+                    .ok_or("Unknown transaction")?;
+                op_return_payloads(&tx)
+            }
+        };
+        let payloads = payloads.iter().map(hex::encode).collect::<Vec<_>>();
+        serde_json::to_string(&payloads).map_err(|e| e.to_string())
+    }
+    /// Records every transaction with an OP_RETURN output starting with `prefix`, given in
+    /// hex, as blocks get accepted. Those don't need to touch our addresses, and their data
+    /// is returned by `get_op_returns`.
+    pub unsafe fn watch_op_return_prefix(&self, prefix: String) -> Result<(), String> {
+        let prefix = hex::decode(prefix).map_err(|_| "Invalid hex")?;
+        if prefix.is_empty() || prefix.len() > MAX_OP_RETURN_RELAY {
+            return Err("The prefix must have between 1 and 83 bytes".into());
+        }
+        let mut prefixes = self.wallet.op_return_prefixes.borrow_mut();
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+        Ok(())
+    }
+    /// Returns aggregated numbers about our wallet as JSON: totals received and sent, the
+    /// current balance, fees paid, transaction and utxo counts, how many watched addresses
+    /// were used, and the heights of the first and last wallet activity.
+    pub unsafe fn wallet_stats(&self) -> Result<String, String> {
+        let immature_balance = self.immature_balance();
+        let stats = self.wallet.stats.borrow();
+        let used_addresses = stats.receive_counts.len();
+        let stats = WalletStatsJson {
+            total_received: stats.total_received,
+            total_sent: stats.total_sent,
+            balance: stats.total_received - stats.total_sent - immature_balance,
+            immature_balance,
+            total_fees: stats.total_fees,
+            tx_count: stats.tx_count,
+            utxo_count: self.wallet.utxos.borrow().len(),
+            used_addresses,
+            unused_addresses: self.wallet.address_set.borrow().len() - used_addresses,
+            first_activity: stats.first_activity,
+            last_activity: stats.last_activity,
+        };
+        serde_json::to_string(&stats).map_err(|e| e.to_string())
+    }
+    /// Returns the number of leaves in the accumulator, how many roots it has and the height
+    /// of each of its trees, as JSON. Leaves are never removed from the count, so this only
+    /// grows, and is an upper bound for the size of the UTXO set.
+    pub unsafe fn utxo_set_info(&self) -> Result<String, String> {
+        let tree_heights = (0..64u8)
+            .rev()
+            .filter(|height| self.acc_leaves & (1 << height) != 0)
+            .collect::<Vec<_>>();
+        let info = UtxoSetInfo {
+            leaves: self.acc_leaves,
+            roots: self.chain_state.get_root_hashes().len(),
+            tree_heights,
+        };
+        serde_json::to_string(&info).map_err(|e| e.to_string())
+    }
+    /// Verifies several utreexo proofs against our current accumulator, e.g. for a burst of
+    /// unconfirmed transactions. `entries` is a JSON array of `{del_hashes, proof}` objects,
+    /// and the result is a JSON array with whether each one is `valid`, or an `error`.
+    pub unsafe fn verify_proofs_batch(&self, entries: String) -> Result<String, String> {
+        let entries: Vec<ProofBatchEntry> =
+            serde_json::from_str(&entries).map_err(|e| e.to_string())?;
+        // Every proof is checked against the same roots, so we only build the stump once
+        let stump = rustreexo::accumulator::stump::Stump {
+            leaves: self.acc_leaves,
+            roots: self.chain_state.get_root_hashes(),
+        };
+        let results = entries
+            .into_iter()
+            .map(|entry| match entry.verify(&stump) {
+                Ok(valid) => ProofBatchResult { valid, error: None },
+                Err(error) => ProofBatchResult {
+                    valid: false,
+                    error: Some(error),
+                },
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&results).map_err(|e| e.to_string())
+    }
+    /// Captures the tip, height, roots and a summary of the wallet at this instant. The view
+    /// has the same getter names as this object and never changes, so reading several values
+    /// from it gives a consistent picture even if blocks get connected in between.
+    pub unsafe fn snapshot_view(&self) -> Result<ChainView, String> {
+        let (height, tip) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        let immature_balance = self.immature_balance();
+        let stats = self.wallet.stats.borrow();
+        Ok(ChainView {
+            height,
+            tip,
+            ibd: self.chain_state.is_in_idb(),
+            tracking_only: self.tracking_only,
+            roots: self
+                .chain_state
+                .get_root_hashes()
+                .iter()
+                .map(|root| root.to_string())
+                .collect(),
+            balance: stats.total_received - stats.total_sent - immature_balance,
+            immature_balance,
+            utxo_count: self.wallet.utxos.borrow().len(),
+            tx_count: stats.tx_count,
+        })
+    }
+    /// Returns every transaction that pays to us or spends one of our coins, as a JSON array
+    /// in chain order. For taproot coins we know the script tree for, each spend
+    /// tells which leaf was used. `filter` is an optional JSON object with any of `address`,
+    /// `min_height`, `max_height`, `direction` ("incoming" or "outgoing"), `min_amount`, and
+    /// `offset`/`limit` for pagination.
+    pub unsafe fn get_wallet_history(&self, filter: Option<String>) -> Result<String, String> {
+        let filter: HistoryFilter = match filter {
+            Some(filter) => serde_json::from_str(&filter).map_err(|e| e.to_string())?,
+            None => HistoryFilter::default(),
+        };
+        let script = match filter.address {
+            Some(ref address) => Some(parse_address(address)?.script_pubkey()),
+            None => None,
+        };
+
+        let history = self.wallet.history.borrow();
+        let min_height = filter.min_height.unwrap_or(0);
+        let max_height = filter.max_height.unwrap_or(u32::MAX);
+        if min_height > max_height {
+            return Ok("[]".into());
+        }
+        // History is already in chain order, we only need to scan the heights asked
+        let entries = history
+            .range((min_height, 0)..)
+            .take_while(|((height, _), _)| *height <= max_height)
+            .map(|(_, entry)| entry)
+            .filter(|entry| {
+                script
+                    .as_ref()
+                    .map_or(true, |script| entry.scripts.contains(script))
+                    && filter
+                        .direction
+                        .map_or(true, |dir| entry.direction() == dir)
+                    && filter
+                        .min_amount
+                        .map_or(true, |min| entry.net_amount() >= min)
+            })
+            .skip(filter.offset)
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .map(|entry| HistoryEntryJson {
+                entry,
+                label: self.wallet.label(LabelKind::Tx, &entry.txid.to_string()),
+                privacy_notes: self
+                    .privacy_analysis
+                    .then(|| self.wallet.privacy_notes(entry)),
+            })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&entries).map_err(|e| e.to_string())
+    }
+    /// Turns on or off the `privacy_notes` in `get_wallet_history`, telling for each entry
+    /// which addresses were reused and whether our change is easy to spot
+    pub unsafe fn set_privacy_analysis(&mut self, enabled: bool) {
+        self.privacy_analysis = enabled;
+    }
+    /// Returns every address of ours that received more than once and how much each received,
+    /// as JSON. All coins sent to the same address can be linked together by anyone.
+    pub unsafe fn privacy_report(&self) -> Result<String, String> {
+        let stats = self.wallet.stats.borrow();
+        let mut received = HashMap::<&Script, u64>::new();
+        let history = self.wallet.history.borrow();
+        for (_, out) in history
+            .values()
+            .flat_map(|entry| entry.received_outputs.iter())
+        {
+            if stats
+                .receive_counts
+                .get(&out.script_pubkey)
+                .copied()
+                .unwrap_or(0)
+                > 1
+            {
+                *received.entry(&out.script_pubkey).or_default() += out.value;
+            }
+        }
+        let mut reused_addresses = received
+            .into_iter()
+            .map(|(script, total_received)| ReusedAddress {
+                address: Address::from_script(script, CHAIN_NETWORK)
+                    .map_or_else(|_| hex::encode(script.as_bytes()), |a| a.to_string()),
+                receive_count: stats.receive_counts[script],
+                total_received,
+            })
+            .collect::<Vec<_>>();
+        reused_addresses.sort_by(|a, b| b.total_received.cmp(&a.total_received));
+        let report = PrivacyReport {
+            total_exposure: reused_addresses.iter().map(|a| a.total_received).sum(),
+            reused_addresses,
+        };
+        serde_json::to_string(&report).map_err(|e| e.to_string())
+    }
+    /// Builds a chain from the given roots and tip. This is used to initialize the chain from
+    /// a trusted source. `source` is an optional label for where the snapshot came from, it's
+    /// saved with the snapshot's details and can be read with `snapshot_provenance`.
+    ///
+    /// For custom signets, `param_overrides` is a JSON object that may set the
+    /// `pow_target_spacing`, `coinbase_maturity`, `pow_limit` and `signet_challenge`. They
+    /// are saved with the provenance too, and used whenever the chain is reloaded. Only the
+    /// target spacing and pow limit are enforced when validating blocks: the coinbase
+    /// maturity only changes which of our coins are immature, and the signet challenge is
+    /// only reported by `chain_params`. This is refused on mainnet.
+    ///
+    /// If `tracking_only` is set, the chain doesn't validate blocks, see `new_tracking_only`.
+    pub unsafe fn build_chain_from(
+        tip: String,
+        height: u32,
+        header: String,
+        source: Option<String>,
+        param_overrides: Option<String>,
+        tracking_only: Option<bool>,
+    ) -> Result<FlorestaChain, String> {
+        let param_overrides = param_overrides
+            .map(|overrides| serde_json::from_str::<ParamOverrides>(&overrides))
+            .transpose()
+            .map_err(|e| e.to_string())?;
+        if let Some(overrides) = &param_overrides {
+            overrides.validate()?;
+        }
+        let mut params: ChainParams = FLORESTA_NETWORK.into();
+        if let Some(overrides) = &param_overrides {
+            overrides.apply(&mut params);
+        }
+        let tip: BlockHash = tip.parse().map_err(|_| "Invalid tip hash")?;
+        if height == 0 {
+            return Err("The snapshot can't be at genesis".into());
+        }
+        let header: BlockHeader =
+            deserialize(&hex::decode(header).map_err(|_| "Invalid header hex")?)
+                .map_err(|e| format!("Invalid header: {e}"))?;
+        // A header from the wrong block would only show up when the next block fails to connect
+        if header.block_hash() != tip {
+            return Err(format!(
+                "The header hashes to {}, but the tip is {tip}",
+                header.block_hash()
+            ));
+        }
+        let target = header.target();
+        if target > params.pow_limit || header.validate_pow(&target).is_err() {
+            return Err(format!("The header for {tip} has invalid proof of work"));
+        }
+        // let roots = roots
+        //     .into_iter()
+        //     .map(|x| x.as_string().unwrap().parse().unwrap())
+        //     .collect();
+        let leaves = 3589788;
+        let roots = [
+            [
+                125_u8, 164, 105, 220, 149, 130, 211, 189, 195, 241, 42, 166, 124, 109, 143, 231,
+                66, 116, 44, 102, 182, 140, 175, 152, 195, 63, 44, 56, 34, 5, 255, 116,
+            ],
+            [
+                1, 67, 238, 54, 53, 30, 130, 100, 184, 211, 253, 100, 228, 90, 19, 118, 187, 212,
+                89, 240, 173, 210, 49, 245, 59, 248, 23, 225, 101, 183, 197, 205,
+            ],
+            [
+                140, 164, 48, 221, 81, 98, 92, 204, 237, 93, 223, 31, 206, 233, 165, 235, 68, 80,
+                150, 195, 194, 249, 102, 75, 215, 241, 79, 123, 80, 151, 226, 152,
+            ],
+            [
+                253, 169, 247, 218, 192, 182, 116, 7, 69, 248, 218, 57, 72, 214, 124, 228, 148,
+                110, 198, 98, 83, 50, 251, 172, 183, 236, 220, 12, 67, 108, 4, 126,
+            ],
+            [
+                55, 235, 54, 1, 65, 192, 18, 60, 196, 66, 122, 160, 171, 47, 250, 208, 33, 182,
+                224, 54, 129, 126, 25, 137, 109, 195, 96, 7, 98, 121, 111, 193,
+            ],
+            [
+                7, 8, 193, 215, 236, 171, 71, 178, 73, 249, 185, 93, 215, 77, 196, 8, 131, 131, 40,
+                129, 199, 52, 215, 212, 155, 116, 21, 20, 163, 250, 13, 248,
+            ],
+            [
+                78, 204, 53, 177, 253, 216, 178, 84, 183, 19, 75, 225, 36, 234, 85, 194, 13, 202,
+                144, 183, 6, 18, 227, 33, 54, 197, 49, 39, 100, 183, 87, 195,
+            ],
+            [
+                86, 155, 134, 165, 70, 86, 55, 121, 0, 240, 230, 199, 115, 151, 216, 161, 0, 106,
+                78, 80, 159, 216, 88, 1, 185, 42, 39, 184, 201, 165, 102, 253,
+            ],
+            [
+                16, 185, 24, 187, 173, 239, 240, 149, 54, 203, 104, 236, 117, 87, 25, 114, 108,
+                234, 225, 149, 152, 0, 181, 73, 219, 62, 99, 165, 16, 57, 210, 156,
+            ],
+            [
+                97, 82, 82, 234, 178, 94, 99, 110, 150, 238, 211, 11, 51, 13, 192, 204, 91, 255,
+                232, 172, 22, 63, 236, 224, 248, 220, 9, 93, 14, 139, 71, 250,
+            ],
+            [
+                49, 117, 230, 111, 51, 175, 131, 200, 226, 153, 209, 85, 192, 203, 165, 66, 59, 16,
+                185, 224, 191, 97, 240, 98, 70, 222, 33, 12, 9, 242, 8, 150,
+            ],
+            [
+                99, 42, 17, 79, 148, 114, 95, 130, 116, 31, 102, 136, 239, 74, 118, 208, 151, 160,
+                222, 132, 129, 12, 173, 226, 17, 163, 235, 94, 78, 32, 147, 209,
+            ],
+        ]
+        .into_iter()
+        .map(|x| x.into())
+        .collect::<Vec<_>>();
+
+        let tracking_only = tracking_only.unwrap_or(false);
+        let store = WasmStore::default();
+        store.has_writer.set(true);
+        if tracking_only {
+            store.put("tracking_only".into(), &[1]);
+        }
+        let mut roots_engine = sha256::Hash::engine();
+        for root in roots.iter() {
+            roots_engine.input(root.as_ref());
+        }
+        let provenance = SnapshotProvenance {
+            tip,
+            height,
+            roots_hash: sha256::Hash::from_engine(roots_engine),
+            source,
+            created_at: unix_time(),
+            param_overrides: param_overrides.clone(),
+        };
+        store.put(
+            "snapshot_provenance".into(),
+            &serde_json::to_vec(&provenance).map_err(|e| e.to_string())?,
+        );
+        let chain_state = ChainStateBuilder::new()
+            .with_tip((tip, height), header)
+            .assume_utreexo(rustreexo::accumulator::stump::Stump { leaves, roots })
+            .with_chainstore(store.clone())
+            .with_chain_params(params)
+            .build()
+            .map_err(|e| format!("{:?}", e))?;
+        let hashes = include_bytes!("../hashes.bin");
+
+        let mut chain = Self::with_chain_state(chain_state, store, hashes.to_vec());
+        chain.acc_leaves = leaves;
+        chain.tracking_only = tracking_only;
+        chain.param_overrides = provenance.param_overrides;
+        chain.assumed_base = Some(AssumedBase {
+            height: provenance.height,
+            hash: provenance.tip,
+        });
+        chain.check_tip()?;
+        Ok(chain)
+    }
+    /// Returns the current height of the chain
+    #[wasm_bindgen(getter, js_name = "height")]
+    pub unsafe fn show_height(&self) -> u32 {
+        self.best_header().0
+    }
+    /// Whether the chain is currently in IBD (Initial Block Download) mode. This is true when the
+    /// chain is still syncing with the network.
+    #[wasm_bindgen(getter, js_name = "ibd")]
+    pub unsafe fn show_ibd(&self) -> bool {
+        self.chain_state.is_in_idb()
+    }
+    /// A string representing the network we are on, like "Signet"
+    #[wasm_bindgen(getter, js_name = "network")]
+    pub unsafe fn show_network(&self) -> String {
+        format!("{CHAIN_NETWORK:?}")
+    }
+    /// Returns where the snapshot this chain was built from came from, as JSON with its `tip`,
+    /// `height`, `roots_hash`, `source` label and `created_at` time, or null if this chain
+    /// was synced from genesis.
+    pub unsafe fn snapshot_provenance(&self) -> Result<String, String> {
+        let provenance = self
+            .store
+            .snapshot_provenance()
+            .map_err(|e| format!("{e:?}"))?;
+        serde_json::to_string(&provenance).map_err(|e| e.to_string())
+    }
+    /// Blocks below this height were assumed valid from a snapshot instead of verified by us,
+    /// undefined if we verified the whole chain
+    #[wasm_bindgen(getter, js_name = "assumed_valid_below")]
+    pub unsafe fn assumed_valid_below(&self) -> Option<u32> {
+        Some(self.assumed_base?.height + 1)
+    }
+    /// Returns the block the snapshot this chain was built from starts at, as JSON with its
+    /// `height` and `hash`, or null if this chain was synced from genesis. Unlike
+    /// `verified_from_height`, this doesn't move when headers are backfilled.
+    pub unsafe fn assumed_base(&self) -> Result<String, String> {
+        serde_json::to_string(&self.assumed_base).map_err(|e| e.to_string())
+    }
+    /// The consensus parameters for the network we are on, with the overrides given to
+    /// `build_chain_from`
+    pub(crate) fn params(&self) -> ChainParams {
+        let mut params = FLORESTA_NETWORK.into();
+        if let Some(overrides) = &self.param_overrides {
+            overrides.apply(&mut params);
+        }
+        params
+    }
+    /// Loads the chain state saved in `store`. floresta-chain only loads the default
+    /// parameters for a network, so if a custom signet overrides them we rebuild the chain
+    /// state at the validated tip with `overrides` applied. Headers past that tip must be
+    /// accepted again.
+    pub(crate) fn load_chain_state(
+        store: &WasmStore,
+        overrides: Option<&ParamOverrides>,
+    ) -> Result<ChainState<WasmStore>, String> {
+        let chain_state = ChainState::load_chain_state(store.clone(), FLORESTA_NETWORK, None)
+            .map_err(|e| format!("{e:?}"))?;
+        let overrides = match overrides {
+            Some(overrides) => overrides,
+            None => return Ok(chain_state),
+        };
+        let height = chain_state
+            .get_validation_index()
+            .map_err(|e| format!("{e:?}"))?;
+        let hash = chain_state
+            .get_block_hash(height)
+            .map_err(|e| format!("{e:?}"))?;
+        let header = chain_state
+            .get_block_header(&hash)
+            .map_err(|e| format!("{e:?}"))?;
+        let mut params = FLORESTA_NETWORK.into();
+        overrides.apply(&mut params);
+        let ibd = chain_state.is_in_idb();
+        let chain_state = ChainStateBuilder::new()
+            .with_tip((hash, height), header)
+            .assume_utreexo(rustreexo::accumulator::stump::Stump {
+                leaves: store.stored_leaves(),
+                roots: chain_state.get_root_hashes(),
+            })
+            .with_chainstore(store.clone())
+            .with_chain_params(params)
+            .build()
+            .map_err(|e| format!("{e:?}"))?;
+        chain_state.toggle_ibd(ibd);
+        Ok(chain_state)
+    }
+    /// How many confirmations a coinbase needs before it can be spent
+    pub(crate) fn coinbase_maturity(&self) -> u32 {
+        self.param_overrides
+            .as_ref()
+            .and_then(|overrides| overrides.coinbase_maturity)
+            .unwrap_or(bitcoin::blockdata::constants::COINBASE_MATURITY)
+    }
+    /// Our coinbase outputs that can't be spent yet, see `Wallet::immature_coins`
+    pub(crate) fn immature_coins(&self) -> HashMap<OutPoint, u32> {
+        self.wallet
+            .immature_coins(self.best_header().0, self.coinbase_maturity())
+    }
+    pub(crate) fn immature_balance(&self) -> u64 {
+        let utxos = self.wallet.utxos.borrow();
+        self.immature_coins()
+            .keys()
+            .filter_map(|outpoint| utxos.get(outpoint))
+            .map(|out| out.value)
+            .sum()
+    }
+    /// Calls the maturity callback for our coinbase outputs that the block after `height`
+    /// may spend, and couldn't before
+    pub(crate) fn notify_matured_coins(&self, height: u32) {
+        let Some(callback) = &self.maturity_callback else {
+            return;
+        };
+        let Some(created) = (height + 1).checked_sub(self.coinbase_maturity()) else {
+            return;
+        };
+        let txid = match self.wallet.history.borrow().get(&(created, 0)) {
+            Some(entry) if entry.coinbase => entry.txid,
+            _ => return,
+        };
+        let matured = self
+            .wallet
+            .utxos
+            .borrow()
+            .iter()
+            .filter(|(outpoint, _)| outpoint.txid == txid)
+            .map(|(outpoint, out)| (*outpoint, out.value))
+            .collect::<Vec<_>>();
+        for (outpoint, value) in matured {
+            let _ = callback.call2(
+                &wasm_bindgen::JsValue::NULL,
+                &outpoint.to_string().into(),
+                &(value as f64).into(),
+            );
+        }
+    }
+    /// Sets a function called as `callback(outpoint, value)` when one of our coinbase outputs
+    /// matures, meaning the next block may spend it. Until then, it's left out of our balance
+    /// and of the coins we spend.
+    pub unsafe fn set_maturity_callback(&mut self, callback: Option<js_sys::Function>) {
+        self.maturity_callback = callback;
+    }
+    /// Sets the height of the network's tip, learned from somewhere else, so `sync_remaining`
+    /// can tell how far we are from it. Update it as the network advances.
+    pub unsafe fn set_target_height(&mut self, height: u32) {
+        self.target_height = Some(height);
+    }
+    /// Returns how much is left to sync up to the height given to `set_target_height`, as
+    /// JSON with the `target_height`, our `validated_height`, how many `blocks` and `bytes`
+    /// are left, and an `eta_seconds` based on how fast the last blocks were connected. The
+    /// estimates are null until we have a target and have connected enough blocks.
+    pub unsafe fn sync_remaining(&self) -> Result<String, String> {
+        let validated_height = self
+            .chain_state
+            .get_validation_index()
+            .map_err(|e| format!("{e:?}"))?;
+        let blocks = self
+            .target_height
+            .map(|target| target.saturating_sub(validated_height));
+        let bytes = blocks
+            .zip(self.metrics.average_block_size)
+            .map(|(blocks, size)| (blocks as f64 * size) as u64);
+        let recent = &self.metrics.recent_connects;
+        let rate = match (recent.front(), recent.back()) {
+            (Some(first), Some(last)) if recent.len() >= MIN_RATE_SAMPLES && last > first => {
+                Some((recent.len() - 1) as f64 / ((last - first) / 1000.0))
+            }
+            _ => None,
+        };
+        let eta_seconds = blocks.zip(rate).map(|(blocks, rate)| blocks as f64 / rate);
+        let remaining = SyncRemaining {
+            target_height: self.target_height,
+            validated_height,
+            blocks,
+            bytes,
+            eta_seconds,
+        };
+        serde_json::to_string(&remaining).map_err(|e| e.to_string())
+    }
+    /// Returns the consensus constants for our network as a JSON object, like the proof of
+    /// work limit, retarget spacing and timespan, halving interval and soft fork activations.
+    /// `tracking_only` tells if we enforce them at all.
+    pub unsafe fn chain_params(&self) -> Result<String, String> {
+        let params = self.params();
+        serde_json::to_string(&ChainParamsInfo {
+            network: self.show_network(),
+            pow_limit: params.pow_limit.to_string(),
+            pow_target_spacing: params.pow_target_spacing,
+            pow_target_timespan: params.pow_target_timespan,
+            pow_allow_min_diff: params.pow_allow_min_diff,
+            pow_allow_no_retarget: params.pow_allow_no_retarget,
+            subsidy_halving_interval: params.subsidy_halving_interval,
+            bip34_activation_height: params.bip34_activation_height,
+            bip65_activation_height: params.bip65_activation_height,
+            bip66_activation_height: params.bip66_activation_height,
+            csv_activation_height: params.csv_activation_height,
+            segwit_activation_height: params.segwit_activation_height,
+            coinbase_maturity: self.coinbase_maturity(),
+            max_block_weight: bitcoin::blockdata::constants::MAX_BLOCK_WEIGHT,
+            signet_challenge: self
+                .param_overrides
+                .as_ref()
+                .and_then(|overrides| overrides.signet_challenge.clone()),
+            tracking_only: self.tracking_only,
+        })
+        .map_err(|e| e.to_string())
+    }
+    /// Returns the current difficulty of the last block. This is a number that represents the
+    /// amount of hashes that must be computed to find a valid block, on average. The returned value
+    /// is a multiple of the minimum difficulty, which is different for each network.
+    #[wasm_bindgen(getter, js_name = "difficulty")]
+    pub unsafe fn show_difficulty(&self) -> u64 {
+        let (_, header) = self.best_header();
+        (self.params().pow_limit / header.target()).low_u64()
+    }
+    // The target is the uint256 number that sets the difficulty of the block. A valid solution
+    // must be less than the target
+    #[wasm_bindgen(getter, js_name = "target")]
+    pub unsafe fn show_target(&self) -> String {
+        let (_, header) = self.best_header();
+        header.target().to_string()
+    }
+
+    /// Returns the best block hash
+    #[wasm_bindgen(getter, js_name = "tip")]
+    pub unsafe fn return_tip(&self) -> String {
+        self.best_header().1.block_hash().to_string()
+    }
+    /// Our best block's height and header. Getters use this so they never panic: if we can't
+    /// read the tip, e.g. on an empty store, we fall back to genesis.
+    pub(crate) fn best_header(&self) -> (u32, BlockHeader) {
+        self.chain_state
+            .get_best_block()
+            .ok()
+            .and_then(|(height, hash)| {
+                let header = self.chain_state.get_block_header(&hash).ok()?;
+                Some((height, header))
+            })
+            .unwrap_or_else(|| (0, self.params().genesis.header))
+    }
+    /// Returns a random address. You shouldn't use this for anything other than testing
+    pub unsafe fn get_random_address(&self) -> Result<String, String> {
+        let mut key = [0u8; 32];
+        self.fill_random(&mut key)?;
+        let key = PrivateKey::from_slice(&key, CHAIN_NETWORK).unwrap();
+        let pk = key.public_key(&self.secp);
+        let address = Address::p2wpkh(&pk, CHAIN_NETWORK).map_err(|e| e.to_string())?;
+        Ok(address.to_string())
+    }
+    #[wasm_bindgen(getter, js_name = "our_txs")]
+    pub unsafe fn get_our_transactions(&self) -> String {
+        self.wallet
+            .history
+            .borrow()
+            .values()
+            .map(|entry| entry.txid.to_string())
+            .reduce(|a, b| format!("{}\n {}", a, b))
+            .unwrap_or("".into())
+    }
+
+    /// Accepts a new block to our chain. Validates the block and connects it to the chain
+    /// if it is valid. Returns an error if the block is invalid. Errors from floresta-chain
+    /// are JSON objects with a stable `code`, see `ChainError`.
+    ///
+    /// Rejected blocks are remembered, and submitting one again fails right away with the
+    /// same reason, until our tip moves `set_rejection_expiry` blocks past it. Pass `retry`
+    /// to try it again anyway, e.g. after replacing the hash table.
+    pub unsafe fn accept_block(
+        &mut self,
+        block: String,
+        retry: Option<bool>,
+    ) -> Result<(), String> {
+        self.check_writable()?;
+        let timer = StageTimer::start();
+        if block.len() > MAX_BLOCK_JSON_SIZE {
+            return Err(format!(
+                "Invalid block: {:?}",
+                BlockValidationError::PayloadTooBig(block.len())
+            ));
+        }
+        if let Err(fits) = self.check_memory_budget(block.len(), BLOCK_JSON_EXPANSION) {
+            return Err(format!(
+                "A {} byte block exceeds the memory budget, only {fits} bytes of JSON fit",
+                block.len()
+            ));
+        }
+        let parsed = serde_json::from_slice::<WasmBlock>(block.as_bytes());
+        // For big blocks the JSON is several times larger than the block itself, don't keep
+        // it around while validating
+        drop(block);
+        let block = parsed.map_err(|e| e.to_string())?;
+        let hash = block.block.block_hash();
+        let retry = retry.unwrap_or(false) || self.reorg_policy.forced.contains(&hash);
+        if let (false, Some(rejected)) = (retry, self.cached_rejection(&hash)?) {
+            self.record_rejection(hash, rejected.height, &rejected.reason)?;
+            return Err(format!(
+                "Block {hash} was rejected before: {}",
+                rejected.reason
+            ));
+        }
+        let height = self
+            .chain_state
+            .get_block_height(&block.block.header.prev_blockhash)
+            .ok()
+            .flatten()
+            .map(|parent| parent + 1);
+        let result = match self.tracking_only {
+            true => self.track_block(block),
+            false => self.accept_parsed_block(block, timer),
+        };
+        match result {
+            Ok(()) if retry => {
+                self.sync_progress.retried_blocks += 1;
+                self.forget_rejection(&hash)
+            }
+            Ok(()) => Ok(()),
+            Err(reason) => {
+                self.sync_progress.rejected_blocks += 1;
+                self.record_rejection(hash, height, &reason)?;
+                Err(reason)
+            }
+        }
+    }
+    /// Accepts a block without validating it, see `new_tracking_only`. Blocks that don't end up
+    /// in our best chain only add their header.
+    pub(crate) fn track_block(&mut self, block: WasmBlock) -> Result<(), String> {
+        block
+            .validate()
+            .map_err(|e| format!("Invalid block: {e:?}"))?;
+        let block = block.block;
+        let hash = block.block_hash();
+        self.check_reorg_depth(&block.header)?;
+        let replaced = self.replaced_by(&block.header);
+        // Headers from the node's header chain were checked when they got to us
+        if !matches!(self.chain_state.get_block_height(&hash), Ok(Some(_))) {
+            self.chain_state
+                .accept_header(block.header)
+                .map_err(ChainError::json("accept_header"))?;
+        }
+        // Blocks on a stale branch don't change our wallet, only their header is kept
+        let Some(height) = self.best_chain_height(&hash) else {
+            return Ok(());
+        };
+        // If we already have wallet data at this height, this block replaces a stale one
+        let reorged = self.wallet.disconnect_above(height.saturating_sub(1));
+        let stale_blocks = replaced
+            .iter()
+            .map(|(height, hash, _)| (*height, *hash))
+            .collect::<HashMap<_, _>>();
+        self.wallet.mark_reorged(&reorged, &stale_blocks);
+        // Without leaf data, the only spent coins we know are ours
+        let spent_coins = {
+            let utxos = self.wallet.utxos.borrow();
+            block
+                .txdata
+                .iter()
+                .flat_map(|tx| tx.input.iter())
+                .filter_map(|input| {
+                    let coin = utxos.get(&input.previous_output)?;
+                    Some((input.previous_output, coin.clone()))
+                })
+                .collect::<HashMap<_, _>>()
+        };
+        for (height, hash, header) in replaced.into_iter().rev() {
+            self.header_events.push(hash, &header, height, true);
+        }
+        self.header_events.push(hash, &block.header, height, false);
+        self.wallet
+            .scan_block_for_wallet(&self.secp, &block, height, &spent_coins);
+        self.wallet.apply_tx_retention(height);
+        let requeued = self.wallet.requeue_reorged(reorged, &stale_blocks);
+        self.notify_wallet_reorgs(requeued);
+        self.notify_matured_coins(height);
+        self.metrics.blocks_connected += 1;
+        Ok(())
+    }
+    /// Calls the wallet reorg callback for each transaction `Wallet::requeue_reorged` returned
+    pub(crate) fn notify_wallet_reorgs(&self, requeued: Vec<(Txid, BlockHash, bool)>) {
+        let Some(callback) = &self.wallet_reorg_callback else {
+            return;
+        };
+        for (txid, stale_block, reconfirmed) in requeued {
+            let _ = callback.call3(
+                &wasm_bindgen::JsValue::NULL,
+                &txid.to_string().into(),
+                &stale_block.to_string().into(),
+                &reconfirmed.into(),
+            );
+        }
+    }
+    /// Validates and connects a block that was just parsed, see `accept_block`
+    pub(crate) fn accept_parsed_block(
+        &mut self,
+        block: WasmBlock,
+        mut timer: StageTimer,
+    ) -> Result<(), String> {
+        block
+            .validate()
+            .map_err(|e| format!("Invalid block: {e:?}"))?;
+        let mut timings = BlockTimings {
+            parse_ms: timer.lap(),
+            ..Default::default()
+        };
+        self.check_reorg_depth(&block.block.header)?;
+        #[cfg(feature = "debug-assertions")]
+        let baseline = self.invariant_baseline(&block.block);
+        let prepared = self.prepare_block(block, &mut timer, &mut timings)?;
+        self.connect_prepared(prepared, &mut timer, &mut timings)?;
+
+        timings.total_ms = timer.total();
+        self.sync_progress.block_ms.push(timings.total_ms as f32);
+        self.metrics.last_block_timings = timings;
+        self.metrics.average_block_timings.update_average(&timings);
+        #[cfg(feature = "debug-assertions")]
+        self.check_invariants(&baseline)?;
+        Ok(())
+    }
+    #[cfg(feature = "debug-assertions")]
+    pub(crate) fn invariant_baseline(&self, block: &Block) -> InvariantBaseline {
+        let stats = self.store.stats.borrow();
+        InvariantBaseline {
+            tip: self.best_header().1.block_hash(),
+            blocks_connected: self.metrics.blocks_connected,
+            store_gets: stats.gets,
+            store_puts: stats.puts,
+            acc_leaves: self.acc_leaves,
+            added_leaves: added_leaves(block),
+        }
+    }
+    #[cfg(feature = "debug-assertions")]
+    /// Checks that the chain, store, wallet and metrics are still consistent after connecting
+    /// a block. Natively this panics with every violation, in wasm they are returned as a
+    /// JSON error, `{"invariant_violations": [{"invariant": ..., "details": ...}]}`.
+    pub(crate) fn check_invariants(&self, baseline: &InvariantBaseline) -> Result<(), String> {
+        let mut violations = Vec::new();
+        let mut violation =
+            |invariant, details: String| violations.push(InvariantViolation { invariant, details });
+        let (height, tip) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        match self.store.get_header(&tip) {
+            Ok(Some(header)) if height > 0 => {
+                let parent = self.chain_state.get_block_hash(height - 1);
+                if parent.as_ref().ok() != Some(&header.prev_blockhash) {
+                    violation(
+                        "tip_links_to_parent",
+                        format!(
+                            "Tip {tip} builds on {}, but we have {parent:?} at height {}",
+                            header.prev_blockhash,
+                            height - 1
+                        ),
+                    );
+                }
+            }
+            Ok(Some(_)) => {}
+            other => violation("tip_header_stored", format!("Tip {tip}: {other:?}")),
+        }
+        let indexed = self.chain_state.get_block_hash(height);
+        if indexed.as_ref().ok() != Some(&tip) {
+            violation(
+                "height_index_at_tip",
+                format!("Tip {tip} is at {height}, but the index has {indexed:?}"),
+            );
+        }
+
+        let balance = {
+            let stats = self.wallet.stats.borrow();
+            stats.total_received - stats.total_sent
+        };
+        let utxo_sum = self
+            .wallet
+            .utxos
+            .borrow()
+            .values()
+            .map(|utxo| utxo.value)
+            .sum::<u64>();
+        if balance != utxo_sum {
+            violation(
+                "wallet_balance",
+                format!("Balance is {balance}, but our coins add up to {utxo_sum}"),
+            );
+        }
+
+        // Copied, the header lookups below update those stats
+        let store_stats = self.store.stats.borrow().clone();
+        if self.metrics.blocks_connected < baseline.blocks_connected
+            || store_stats.gets < baseline.store_gets
+            || store_stats.puts < baseline.store_puts
+        {
+            violation(
+                "monotonic_metrics",
+                format!(
+                    "Counters went from {} blocks, {} gets and {} puts to {}, {} and {}",
+                    baseline.blocks_connected,
+                    baseline.store_gets,
+                    baseline.store_puts,
+                    self.metrics.blocks_connected,
+                    store_stats.gets,
+                    store_stats.puts
+                ),
+            );
+        }
+
+        // There's one root for each bit set in the number of leaves
+        let roots = self.chain_state.get_root_hashes().len() as u32;
+        if roots != self.acc_leaves.count_ones() {
+            violation(
+                "accumulator_roots",
+                format!("{roots} roots for {} leaves", self.acc_leaves),
+            );
+        }
+        // Leaves are never removed from the count, spent ones are only zeroed. After a reorg
+        // the count is reloaded from the store, so it must match the one saved there
+        let extends_tip = self
+            .store
+            .get_header(&tip)
+            .ok()
+            .flatten()
+            .map_or(false, |header| header.prev_blockhash == baseline.tip);
+        let expected_leaves = if tip == baseline.tip {
+            baseline.acc_leaves
+        } else if extends_tip {
+            baseline.acc_leaves + baseline.added_leaves
+        } else {
+            self.store.stored_leaves()
+        };
+        if self.acc_leaves != expected_leaves {
+            violation(
+                "accumulator_leaves",
+                format!(
+                    "Leaf count went from {} to {}, expected {expected_leaves}",
+                    baseline.acc_leaves, self.acc_leaves
+                ),
+            );
+        }
+
+        if violations.is_empty() {
+            return Ok(());
+        }
+        let report = serde_json::json!({ "invariant_violations": violations }).to_string();
+        if cfg!(target_arch = "wasm32") {
+            return Err(report);
+        }
+        panic!("Invariants broken after connecting {tip} at {height}: {report}");
+    }
+    /// Returns what we know about a block we rejected, unless it expired
+    pub(crate) fn cached_rejection(
+        &self,
+        hash: &BlockHash,
+    ) -> Result<Option<RejectedBlock>, String> {
+        let tip_height = self.best_header().0;
+        let rejected = self
+            .store
+            .rejected_blocks()
+            .map_err(|e| format!("{e:?}"))?
+            .remove(hash)
+            .filter(|rejected| {
+                tip_height < rejected.tip_height.saturating_add(self.rejection_expiry)
+            });
+        Ok(rejected)
+    }
+    /// Adds a rejection to the log, dropping expired entries and the oldest ones if it's full
+    pub(crate) fn record_rejection(
+        &self,
+        hash: BlockHash,
+        height: Option<u32>,
+        reason: &str,
+    ) -> Result<(), String> {
+        let tip_height = self.best_header().0;
+        let now = unix_time();
+        let mut rejected = self.store.rejected_blocks().map_err(|e| format!("{e:?}"))?;
+        rejected
+            .retain(|_, entry| tip_height < entry.tip_height.saturating_add(self.rejection_expiry));
+        let entry = rejected.entry(hash).or_insert_with(|| RejectedBlock {
+            reason: reason.into(),
+            height,
+            tip_height,
+            first_rejected_at: now,
+            last_rejected_at: now,
+            count: 0,
+        });
+        entry.reason = reason.into();
+        entry.tip_height = tip_height;
+        entry.last_rejected_at = now;
+        entry.count += 1;
+        trim_rejections(&mut rejected, MAX_REJECTED_BLOCKS);
+        self.store.set_rejected_blocks(&rejected);
+        Ok(())
+    }
+    /// Removes a block from the rejection log, after it got accepted
+    pub(crate) fn forget_rejection(&self, hash: &BlockHash) -> Result<(), String> {
+        let mut rejected = self.store.rejected_blocks().map_err(|e| format!("{e:?}"))?;
+        if rejected.remove(hash).is_some() {
+            self.store.set_rejected_blocks(&rejected);
+        }
+        Ok(())
+    }
+    /// Returns the blocks we rejected recently as a JSON array, each with its `hash`, the
+    /// `reason`, its `height` if we knew its parent, our `tip_height` when it was last
+    /// rejected, when it was first and last rejected, and how many times.
+    pub unsafe fn rejection_log(&self) -> Result<String, String> {
+        let log = self
+            .store
+            .rejected_blocks()
+            .map_err(|e| format!("{e:?}"))?
+            .into_iter()
+            .map(|(hash, rejected)| RejectionLogEntry { hash, rejected })
+            .collect::<Vec<_>>();
+        serde_json::to_string(&log).map_err(|e| e.to_string())
+    }
+    /// Limits how long we keep undrained header events and rejected blocks, for long running
+    /// instances. As blocks connect, entries for blocks more than `blocks` below our tip are
+    /// dropped, then the oldest ones until at most `max_entries` of each are left. Either
+    /// can be undefined for no limit. These only lower the built-in limits, of 1024 header
+    /// events and 100 rejected blocks. Dropped header events set the overflow flag of
+    /// `drain_new_headers`, as if they overflowed.
+    pub unsafe fn set_event_retention(&mut self, blocks: Option<u32>, max_entries: Option<u32>) {
+        self.event_retention = EventRetention {
+            blocks,
+            max_entries: max_entries.map(|max| max as usize),
+        };
+    }
+    /// Applies `set_event_retention` after connecting a block at `height`
+    pub(crate) fn apply_event_retention(&mut self, height: u32) -> Result<(), String> {
+        let EventRetention {
+            blocks,
+            max_entries,
+        } = self.event_retention;
+        if blocks.is_none() && max_entries.is_none() {
+            return Ok(());
+        }
+        let min_height = blocks.map_or(0, |blocks| height.saturating_sub(blocks));
+        let max_entries = max_entries.unwrap_or(usize::MAX);
+        self.header_events.prune(min_height, max_entries);
+
+        let mut rejected = self.store.rejected_blocks().map_err(|e| format!("{e:?}"))?;
+        let before = rejected.len();
+        rejected.retain(|_, entry| entry.tip_height >= min_height);
+        trim_rejections(&mut rejected, max_entries);
+        if rejected.len() < before {
+            self.store.set_rejected_blocks(&rejected);
+        }
+        Ok(())
+    }
+    /// Sets after how many blocks on top of our tip a rejected block may be tried again
+    /// without `retry`. The default is 6.
+    pub unsafe fn set_rejection_expiry(&mut self, blocks: u32) {
+        self.rejection_expiry = blocks;
+    }
+    /// Refuses blocks that would replace more than `max_reorg_depth` blocks of our best chain,
+    /// or that build on a block we refused, unless the user forced them
+    pub(crate) fn check_reorg_depth(&mut self, header: &BlockHeader) -> Result<(), String> {
+        let hash = header.block_hash();
+        if self.reorg_policy.forced.contains(&hash) {
+            return Ok(());
+        }
+        let policy = &mut self.reorg_policy;
+        if policy.invalid.contains(&header.prev_blockhash) {
+            policy.invalid.insert(hash);
+            return Err(format!("Block {hash} builds on a chain refused by policy"));
+        }
+        let depth = self.replaced_by(header).len() as u32;
+        if depth <= self.reorg_policy.max_depth {
+            // It may have been refused for building on a block that was forced since
+            self.reorg_policy.invalid.remove(&hash);
+            return Ok(());
+        }
+        self.reorg_policy.invalid.insert(hash);
+        let (_, tip) = self
+            .chain_state
+            .get_best_block()
+            .map_err(|e| format!("{e:?}"))?;
+        if let Some(callback) = &self.reorg_policy.callback {
+            let _ = callback.call3(
+                &wasm_bindgen::JsValue::NULL,
+                &tip.to_string().into(),
+                &hash.to_string().into(),
+                &depth.into(),
+            );
+        }
+        Err(format!(
+            "Block {hash} would reorg {depth} blocks, more than the limit of {}",
+            self.reorg_policy.max_depth
+        ))
+    }
+    /// Sets how many blocks of our best chain a reorg may replace, 100 by default. Deeper
+    /// reorgs are refused, see `set_deep_reorg_callback` and `force_reorg`.
+    pub unsafe fn set_max_reorg_depth(&mut self, depth: u32) {
+        self.reorg_policy.max_depth = depth;
+    }
+    /// Sets a function called as `callback(our_tip, their_block, depth)` when a block is
+    /// refused because it would reorg deeper than allowed, so the app can ask the user
+    /// whether to accept it with `force_reorg`
+    pub unsafe fn set_deep_reorg_callback(&mut self, callback: Option<js_sys::Function>) {
+        self.reorg_policy.callback = callback;
+    }
+    /// Allows a block refused for reorging too deep, given by its hash. The block must then
+    /// be given to `accept_block` again, blocks building on it are accepted normally.
+    pub unsafe fn force_reorg(&mut self, hash: String) -> Result<(), String> {
+        let hash = BlockHash::from_str(&hash).map_err(|_| "Invalid block hash")?;
+        if !self.reorg_policy.invalid.remove(&hash) {
+            return Err(format!("Block {hash} wasn't refused"));
+        }
+        self.reorg_policy.forced.insert(hash);
+        Ok(())
+    }
+    /// Returns the headers in our best chain at or above the height `header` would have,
+    /// which connecting it would replace
+    pub(crate) fn replaced_by(&self, header: &BlockHeader) -> Vec<(u32, BlockHash, BlockHeader)> {
+        let Some(parent_height) = self.best_chain_height(&header.prev_blockhash) else {
+            return Vec::new();
+        };
+        let Ok((tip_height, _)) = self.chain_state.get_best_block() else {
+            return Vec::new();
+        };
+        (parent_height + 1..=tip_height)
+            .filter_map(|height| {
+                let hash = self.chain_state.get_block_hash(height).ok()?;
+                let header = self.chain_state.get_block_header(&hash).ok()?;
+                Some((height, hash, header))
+            })
+            .collect()
+    }
+    /// Returns every header added to or removed from our best chain since the last call, as
+    /// JSON, oldest first. Each event has a `hash`, `height`, `time` and whether it was
+    /// `removed` by a reorg. Only the last 1024 events are kept; if older ones were dropped
+    /// `overflow` is set, and the caller should resync using the other getters.
+    pub unsafe fn drain_new_headers(&mut self) -> Result<String, String> {
+        let events = std::mem::take(&mut self.header_events);
+        serde_json::to_string(&events).map_err(|e| e.to_string())
+    }
+    /// Like `drain_new_headers`, but returns the headers added to our best chain as a single
+    /// buffer that can be transferred to another thread with `postMessage`. Each header takes
+    /// 84 bytes: its height as a little endian u32, followed by the 80 byte header. Removed
+    /// headers aren't included, a header at a height we've seen before replaces the old one.
+    /// Both functions drain the same events, so only one of them should be used.
+    ///
+    /// ```js
+    /// const buffer = chain.take_header_buffer();
+    /// const view = new DataView(buffer.buffer);
+    /// for (let i = 0; i < buffer.length; i += 84) {
+    ///     const height = view.getUint32(i, true);
+    ///     const header = buffer.subarray(i + 4, i + 84);
+    /// }
+    /// ```
+    pub unsafe fn take_header_buffer(&mut self) -> Vec<u8> {
+        let events = std::mem::take(&mut self.header_events);
+        let mut buffer = Vec::with_capacity(events.events.len() * 84);
+        for event in events.events.iter().filter(|event| !event.removed) {
+            buffer.extend_from_slice(&event.height.to_le_bytes());
+            buffer.extend_from_slice(&consensus::serialize(&event.header));
+        }
+        buffer
+    }
+    /// Starts or stops recording what each connected block adds to and removes from the
+    /// accumulator. Only the last 144 blocks are kept, stopping drops everything recorded.
+    pub unsafe fn record_accumulator_deltas(&mut self, enabled: bool) {
+        self.accumulator_deltas.enabled = enabled;
+        if !enabled {
+            self.accumulator_deltas.deltas.clear();
+        }
+    }
+    /// Returns what connecting the block with this hash did to the accumulator, as JSON with
+    /// the `added` and `removed` leaf hashes, and the roots before and after it. Only works for
+    /// blocks connected while `record_accumulator_deltas` was on.
+    pub unsafe fn get_accumulator_delta(&self, hash: String) -> Result<String, String> {
+        let hash = BlockHash::from_str(&hash).map_err(|_| "Invalid block hash")?;
+        let delta = self
+            .accumulator_deltas
+            .deltas
+            .iter()
+            .rev()
+            .find(|delta| delta.hash == hash)
+            .ok_or("No delta recorded for this block")?;
+        serde_json::to_string(delta).map_err(|e| e.to_string())
+    }
+    /// Starts or stops keeping the coinbase of each connected block, for `coinbase_info`. Only
+    /// the last 144 are kept, stopping drops everything recorded.
+    pub unsafe fn record_coinbases(&mut self, enabled: bool) {
+        self.coinbases = enabled.then(VecDeque::new);
+    }
+    /// Returns the coinbase of a block connected while `record_coinbases` was on, given its
+    /// hash or height, as JSON: the scriptSig, the BIP34 height it carries, its outputs and
+    /// witness commitment. If the BIP34 height doesn't match, `warning` says so.
+    pub unsafe fn coinbase_info(&self, hash_or_height: String) -> Result<String, String> {
+        let coinbases = self
+            .coinbases
+            .as_ref()
+            .ok_or("Coinbases aren't being recorded")?;
+        let info = match hash_or_height.parse::<u32>() {
+            Ok(height) => coinbases.iter().rev().find(|info| info.height == height),
+            Err(_) => {
+                let hash = BlockHash::from_str(&hash_or_height)
+                    .map_err(|_| "Expected a block hash or height")?;
+                coinbases.iter().rev().find(|info| info.hash == hash)
+            }
+        }
+        .ok_or("No coinbase recorded for this block")?;
+        serde_json::to_string(info).map_err(|e| e.to_string())
+    }
+    /// Returns how many milliseconds each stage of `accept_block` took for the last block, as
+    /// JSON. Moving averages are available in `get_metrics`.
+    pub unsafe fn last_block_timings(&self) -> Result<String, String> {
+        serde_json::to_string(&self.metrics.last_block_timings).map_err(|e| e.to_string())
+    }
+    /// First stage of accepting a block: accepts its header and reconstructs the coins it
+    /// spends from the leaf data. Nothing is verified against the accumulator yet.
+    pub(crate) fn prepare_block(
+        &mut self,
+        block: WasmBlock,
+        timer: &mut StageTimer,
+        timings: &mut BlockTimings,
+    ) -> Result<PreparedBlock, String> {
+        self.chain_state
+            .accept_header(block.block.header)
+            .map_err(ChainError::json("accept_header"))?;
+        timings.accept_header_ms = timer.lap();
+        let height = self
+            .chain_state
+            .get_block_height(&block.block.block_hash())
+            .ok()
+            .flatten()
+            .ok_or("Accepted header has no height")?;
+        let (mut del_hashes, inputs) = self
+            .process_proof(block.leaf_data, &block.block.txdata, height)
+            .map_err(|e| format!("Process Proof: {e:?}"))?;
+        let mut proof = block.proof;
+        proof
+            .normalize(self.acc_leaves, &mut del_hashes)
+            .map_err(|e| format!("Invalid proof: {e}"))?;
+        timings.leaf_reconstruction_ms = timer.lap();
+        Ok(PreparedBlock {
+            block: block.block,
+            proof: proof.into(),
+            inputs,
+            del_hashes,
+        })
+    }
+    /// Second stage of accepting a block: verifies the proof and connects the block to our
+    /// chain, then looks for our wallet transactions in it.
+    pub(crate) fn connect_prepared(
+        &mut self,
+        prepared: PreparedBlock,
+        timer: &mut StageTimer,
+        timings: &mut BlockTimings,
+    ) -> Result<(), String> {
+        let PreparedBlock {
+            block,
+            proof,
+            inputs,
+            del_hashes,
+        } = prepared;
+        // Only the coins spent by our transactions are needed after connecting, to compute fees
+        let spent_coins = self.wallet.spent_coins_for(&block, &inputs);
+        // Blocks this one replaces, their index entries get overwritten when connecting
+        let replaced = self.replaced_by(&block.header);
+        let delta_before = self.accumulator_deltas.enabled.then(|| {
+            let roots = self
+                .chain_state
+                .get_root_hashes()
+                .iter()
+                .map(|root| root.to_string())
+                .collect::<Vec<_>>();
+            (roots, del_hashes.clone())
+        });
+        self.chain_state
+            .connect_block(&block, proof, inputs, del_hashes)
+            .map_err(ChainError::json("connect_block"))?;
+        timings.connect_block_ms = timer.lap();
+
+        let height = self
+            .chain_state
+            .get_block_height(&block.block_hash())
+            .ok()
+            .flatten()
+            .ok_or("Connected block has no height")?;
+        // If we already have wallet data at this height, this block replaces a stale one
+        let reorged = self.wallet.disconnect_above(height.saturating_sub(1));
+        let stale_blocks = replaced
+            .iter()
+            .map(|(height, hash, _)| (*height, *hash))
+            .collect::<HashMap<_, _>>();
+        self.wallet.mark_reorged(&reorged, &stale_blocks);
+        // We don't have the bodies of replaced blocks to undo their leaves, so after a reorg
+        // we start over from the leaf count saved with the roots
+        if replaced.is_empty() {
+            self.acc_leaves += added_leaves(&block);
+        } else {
+            self.acc_leaves = self.store.stored_leaves();
+            self.sync_progress.reorgs += 1;
+        }
+        for (height, hash, header) in replaced.into_iter().rev() {
+            self.header_events.push(hash, &header, height, true);
+        }
+        self.header_events
+            .push(block.block_hash(), &block.header, height, false);
+        if let Some(coinbases) = &mut self.coinbases {
+            if let Some(info) = CoinbaseInfo::new(&block, height) {
+                if coinbases.len() == MAX_COINBASE_INFOS {
+                    coinbases.pop_front();
+                }
+                coinbases.push_back(info);
+            }
+        }
+        if let Some((roots_before, removed)) = delta_before {
+            self.accumulator_deltas.push(AccumulatorDelta {
+                hash: block.block_hash(),
+                height,
+                added: added_leaf_hashes(&block, height),
+                removed,
+                roots_before,
+                roots_after: self
+                    .chain_state
+                    .get_root_hashes()
+                    .iter()
+                    .map(|root| root.to_string())
+                    .collect(),
+            });
+        }
+        let cost = self
+            .wallet
+            .scan_block_for_wallet(&self.secp, &block, height, &spent_coins);
+        self.wallet.apply_tx_retention(height);
+        let matches = self.wallet.history.borrow().range((height, 0)..).count();
+        let requeued = self.wallet.requeue_reorged(reorged, &stale_blocks);
+        self.notify_wallet_reorgs(requeued);
+        self.notify_matured_coins(height);
+        timings.wallet_ms = timer.lap();
+        self.metrics.blocks_connected += 1;
+        let size = block.size() as f64;
+        self.metrics.average_block_size = Some(match self.metrics.average_block_size {
+            Some(average) => average + (size - average) * BlockTimings::SMOOTHING,
+            None => size,
+        });
+        if self.metrics.recent_connects.len() == MAX_RECENT_CONNECTS {
+            self.metrics.recent_connects.pop_front();
+        }
+        self.metrics.recent_connects.push_back(now_ms());
+        let state_size = self.state_size().total;
+        let progress = &mut self.sync_progress;
+        if progress.started_ms.is_none() {
+            progress.started_ms = Some(now_ms());
+            progress.start_height = height;
+        }
+        progress.blocks += 1;
+        progress.bytes += block.size() as u64;
+        progress.wallet_matches += matches as u64;
+        progress.peak_state_bytes = progress.peak_state_bytes.max(state_size);
+        self.metrics.last_block_filter_lookups = cost.lookups;
+        self.metrics.last_block_filter_bytes = cost.bytes;
+        // The block is connected already, failing to prune must not make it look rejected
+        if let Err(e) = self.apply_event_retention(height) {
+            log(format!("Could not apply the event retention: {e}"));
+        }
+        Ok(())
+    }
+    /// Combines whether one timelock is satisfied into the overall result. If any lock isn't
+    /// satisfied, the whole transaction isn't, otherwise an unknown makes everything unknown.
+    pub(crate) fn merge_satisfied(spendable_at: &mut SpendableAt, satisfied: Option<bool>) {
+        spendable_at.satisfied = match (spendable_at.satisfied, satisfied) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        };
+    }
+    /// The median of the timestamps for the 11 blocks ending at `height`, as defined by BIP113.
+    /// Returns None if we don't have all those headers, e.g. they are below our snapshot.
+    pub(crate) fn median_time_past(&self, height: u32) -> Option<u32> {
+        let mut times = Vec::new();
+        for height in height.saturating_sub(10)..=height {
+            let hash = self.chain_state.get_block_hash(height).ok()?;
+            let header = self.chain_state.get_block_header(&hash).ok()?;
+            times.push(header.time);
+        }
+        times.sort();
+        Some(times[times.len() / 2])
+    }
+    pub(crate) fn record_hash_discrepancy(
+        &mut self,
+        height: u32,
+        store: BlockHash,
+        table: BlockHash,
+    ) {
+        log(format!(
+            "Block hash mismatch at height {height}: store has {store}, hash table has {table}"
+        ));
+        self.metrics.hash_source_discrepancies += 1;
+        let recent = &mut self.metrics.recent_hash_discrepancies;
+        if recent.len() == MAX_HASH_DISCREPANCIES {
+            recent.pop_front();
+        }
+        recent.push_back(HashSourceDiscrepancy {
+            height,
+            store,
+            table,
+        });
+    }
+    /// Returns the last heights where our store and the block hash table disagreed, as a
+    /// JSON array with both hashes. The store's hash is the one used to rebuild leaves.
+    pub unsafe fn hash_source_discrepancies(&self) -> Result<String, String> {
+        serde_json::to_string(&self.metrics.recent_hash_discrepancies).map_err(|e| e.to_string())
+    }
+    pub(crate) fn get_block_hash(&self, height: u32) -> Option<BlockHash> {
+        let offset = height as usize * 32;
+        let hash = self.hashes.get(offset..(offset + 32))?;
+        BlockHash::from_slice(hash).ok()
+    }
+    /// Rebuilds the coins spent by `transactions`, a block at `block_height`, from its leaf
+    /// data. Returns the hashes of the spent leaves, and every coin the block spends.
+    pub(crate) fn process_proof(
+        &mut self,
+        leaves: Vec<CompLeafData>,
+        transactions: &[Transaction],
+        block_height: u32,
+    ) -> anyhow::Result<(Vec<sha256::Hash>, HashMap<OutPoint, TxOut>)> {
+        let mut inputs = HashMap::new();
+        let mut hashes = vec![];
+        // Skip the coinbase
+        for tx in transactions.iter().skip(1) {
+            let txid = tx.txid();
+            for (vout, out) in tx.output.iter().enumerate() {
+                inputs.insert(
+                    OutPoint {
+                        txid,
+                        vout: vout as u32,
+                    },
+                    out.clone(),
+                );
+            }
+        }
+        for (index, ((_, _, input), leaf)) in leaf_spending_inputs(transactions)
+            .into_iter()
+            .zip(leaves)
+            .enumerate()
+        {
+            let height = leaf.header_code >> 1;
+            // Genesis outputs can't be spent, and no coin is newer than the block spending
+            // it. Catch this before reading garbage from the hash table.
+            if height == 0 || height > block_height {
+                return Err(anyhow::anyhow!(
+                    "Leaf {index} claims to be created at height {height}, but the block is at height {block_height}"
+                ));
+            }
+            let hash = match self.chain_state.get_block_hash(height) {
+                Err(_) => self
+                    .get_block_hash(height)
+                    .ok_or_else(|| anyhow::anyhow!("Leaf created at unknown height {height}"))?,
+                Ok(hash) => {
+                    // The store wins, but a disagreement means one of them is
+                    // wrong, and the leaf we build may be too
+                    if let Some(table) = self.get_block_hash(height) {
+                        if table != hash {
+                            self.record_hash_discrepancy(height, hash, table);
+                        }
+                    }
+                    hash
+                }
+            };
+            let leaf = proof_util::reconstruct_leaf_data(&leaf.into(), input, hash)
+                .map_err(|e| anyhow::anyhow!("Invalid leaf data: {e:?}"))?;
+            // FIXME: Bring this back after finding wat the frick is going on with
+            // the bridge
+            // hashes.push(leaf._get_leaf_hashes());
+            inputs.insert(leaf.prevout, leaf.utxo);
+        }
+        Ok((hashes, inputs))
+    }
+    /// Checks the block hash table used to reconstruct leaves against the headers we have,
+    /// looking at every `sample_rate`th entry (1 checks all of them), and whether it is the
+    /// table embedded in this build. Returns a JSON report with the mismatched heights.
+    pub unsafe fn verify_hash_table(&self, sample_rate: u32) -> Result<String, String> {
+        if sample_rate == 0 {
+            return Err("The sample rate must be at least 1".into());
+        }
+        let entries = (self.hashes.len() / 32) as u32;
+        let mut report = HashTableReport {
+            entries,
+            matches_embedded: sha256::Hash::hash(&self.hashes).to_string() == HASH_TABLE_SHA256,
+            ..Default::default()
+        };
+        for height in (0..entries).step_by(sample_rate as usize) {
+            let Ok(hash) = self.chain_state.get_block_hash(height) else {
+                report.unavailable += 1;
+                continue;
+            };
+            report.checked += 1;
+            if self.get_block_hash(height) != Some(hash) {
+                report.mismatched.push(height);
+            }
+        }
+        serde_json::to_string(&report).map_err(|e| e.to_string())
+    }
+    /// Replaces the block hash table used to reconstruct leaves, `data` holds one 32 byte
+    /// hash per height starting at genesis. It's refused unless its sha256 matches
+    /// `expected_sha256`, so a truncated or wrong download can't be used by mistake.
+    pub unsafe fn set_block_hashes(
+        &mut self,
+        data: js_sys::Uint8Array,
+        expected_sha256: String,
+    ) -> Result<(), String> {
+        let data = data.to_vec();
+        if data.len() % 32 != 0 {
+            return Err("The table must have 32 bytes per block".into());
+        }
+        let hash = sha256::Hash::hash(&data);
+        if !hash
+            .to_string()
+            .eq_ignore_ascii_case(expected_sha256.trim())
+        {
+            return Err(format!(
+                "Hash table checksum is {hash}, expected {expected_sha256}"
+            ));
+        }
+        self.hashes = data;
+        Ok(())
+    }
+    /// Tells the chain whether we are in IBD, e.g. set it back to true before a resync.
+    /// Calls the function given to `set_ibd_callback` if this changes anything. Leaving IBD
+    /// saves a summary of the sync, see `sync_report`.
+    pub unsafe fn set_ibd(&mut self, value: bool) {
+        if self.chain_state.is_in_idb() == value {
+            return;
+        }
+        self.chain_state.toggle_ibd(value);
+        let progress = std::mem::take(&mut self.sync_progress);
+        if !value && !self.read_only {
+            let report = progress.report(self.best_header().0);
+            self.store.set_sync_report(&report);
+        }
+        if let Some(callback) = &self.ibd_callback {
+            let _ = callback.call1(&wasm_bindgen::JsValue::NULL, &value.into());
+        }
+    }
+    /// Deprecated: use `set_ibd(false)` instead
+    pub unsafe fn toggle_ibd(&mut self) {
+        self.set_ibd(false);
+    }
+    /// Sets a function called as `callback(txid, stale_block, reconfirmed)` when one of our
+    /// transactions was in a block that got reorganized out. `reconfirmed` tells if the block
+    /// replacing it has the transaction too, if not, it goes back to our pending transactions.
+    /// When it confirms again, its history entry has `reorged_from` set to the stale block.
+    pub unsafe fn set_wallet_reorg_callback(&mut self, callback: Option<js_sys::Function>) {
+        self.wallet_reorg_callback = callback;
+    }
+    /// Returns, as JSON, the summary saved the last time we left IBD: how long it took, how
+    /// many blocks and bytes we validated, per block timings, wallet matches, reorgs,
+    /// rejected and retried blocks and the peak memory estimate. Null if we never synced.
+    pub unsafe fn sync_report(&self) -> Result<String, String> {
+        let report = self.store.sync_report().map_err(|e| format!("{e:?}"))?;
+        serde_json::to_string(&report).map_err(|e| e.to_string())
+    }
+    /// Sets a function called as `callback(ibd)` every time we enter or leave IBD
+    pub unsafe fn set_ibd_callback(&mut self, callback: Option<js_sys::Function>) {
+        self.ibd_callback = callback;
+    }
+}
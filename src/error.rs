@@ -0,0 +1,187 @@
+// SPDX-License-Identifier: MIT
+
+//! Errors we return, and how panics poison every instance.
+
+use super::*;
+
+#[derive(Debug, Clone, Serialize)]
+/// What we know about a panic, see `last_panic`
+pub(crate) struct PanicReport {
+    pub(crate) message: String,
+    /// Where in our code it happened, as `file:line:column`
+    pub(crate) location: Option<String>,
+    /// Only has our function names if this was built with debug info
+    pub(crate) backtrace: String,
+}
+
+thread_local! {
+    /// Set when something panics. After that our state can't be trusted, so every instance
+    /// is poisoned and refuses to do anything else
+    pub(crate) static LAST_PANIC: RefCell<Option<PanicReport>> = RefCell::new(None);
+    /// Called with the panic report, see `on_fatal`
+    pub(crate) static FATAL_CALLBACK: RefCell<Option<js_sys::Function>> = RefCell::new(None);
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn backtrace() -> String {
+    StackCapture::new().stack()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn backtrace() -> String {
+    std::backtrace::Backtrace::force_capture().to_string()
+}
+
+#[wasm_bindgen(start)]
+/// Runs when the module is loaded, making panics show up as a report instead of just an
+/// "unreachable executed" error
+pub fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let payload = info.payload();
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Unknown panic".into());
+        let report = PanicReport {
+            message,
+            location: info.location().map(|location| location.to_string()),
+            backtrace: backtrace(),
+        };
+        let json = serde_json::to_string(&report).unwrap_or_default();
+        // Don't panic again if we panicked while holding one of those
+        LAST_PANIC.with(|last| {
+            if let Ok(mut last) = last.try_borrow_mut() {
+                *last = Some(report);
+            }
+        });
+        #[cfg(target_arch = "wasm32")]
+        {
+            log(format!("Panicked: {json}"));
+            FATAL_CALLBACK.with(|callback| {
+                if let Ok(Some(callback)) = callback.try_borrow().as_deref() {
+                    let _ = callback.call1(&wasm_bindgen::JsValue::NULL, &json.into());
+                }
+            });
+        }
+        #[cfg(not(target_arch = "wasm32"))]
+        eprintln!("Panicked: {json}");
+    }));
+}
+
+#[wasm_bindgen]
+/// Returns the last panic as JSON, with its `message`, `location` and `backtrace`, or null
+/// if nothing panicked. Instances can't be used after a panic, so this isn't a method.
+pub unsafe fn last_panic() -> String {
+    LAST_PANIC.with(|last| serde_json::to_string(&*last.borrow()).unwrap_or_default())
+}
+
+#[wasm_bindgen]
+/// Sets a function called as `callback(report)` when we panic, with the same JSON returned
+/// by `last_panic`. The application should reload the module after that.
+pub unsafe fn on_fatal(callback: Option<js_sys::Function>) {
+    FATAL_CALLBACK.with(|fatal| *fatal.borrow_mut() = callback);
+}
+
+/// Fails once something panicked, since our state may be half updated
+pub(crate) fn check_poisoned() -> Result<(), String> {
+    if LAST_PANIC.with(|last| last.borrow().is_some()) {
+        return Err("Chain instance poisoned, reload required".into());
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+/// The error returned by our database
+pub enum Error {
+    /// A value couldn't be decoded, the store is probably corrupted
+    Corrupted(String),
+    /// A value failed authentication, either the cipher key is wrong or it was tampered with
+    Tampered(String),
+}
+impl DatabaseError for Error {}
+
+#[derive(Debug, Serialize)]
+/// An error from floresta-chain, returned by `accept_block` as JSON, like `{"stage":
+/// "accept_header", "code": "block_validation", "reason": "block_extends_an_orphan_chain",
+/// "details": ...}`. Codes are ours, so they don't change when floresta-chain renames things.
+/// Variants we don't know about are `other`.
+pub(crate) struct ChainError {
+    /// `accept_header` or `connect_block`
+    pub(crate) stage: &'static str,
+    pub(crate) code: &'static str,
+    /// For validation errors, why the block is invalid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reason: Option<&'static str>,
+    /// The error as floresta-chain prints it, for debugging only
+    pub(crate) details: String,
+}
+
+impl ChainError {
+    pub(crate) fn new(stage: &'static str, error: &BlockchainError) -> Self {
+        let (code, reason) = match error {
+            BlockchainError::BlockNotPresent => ("block_not_present", None),
+            BlockchainError::OrphanBlock => ("orphan_block", None),
+            BlockchainError::BlockValidation(reason) => {
+                ("block_validation", Some(Self::validation_reason(reason)))
+            }
+            BlockchainError::InvalidProof => ("invalid_proof", None),
+            BlockchainError::UtreexoError(_) => ("utreexo_error", None),
+            BlockchainError::Database(_) => ("database", None),
+            BlockchainError::ConsensusDecode(_) => ("consensus_decode", None),
+            BlockchainError::ChainNotInitialized => ("chain_not_initialized", None),
+            _ => ("other", None),
+        };
+        ChainError {
+            stage,
+            code,
+            reason,
+            details: format!("{error:?}"),
+        }
+    }
+    pub(crate) fn validation_reason(reason: &BlockValidationErrors) -> &'static str {
+        match reason {
+            BlockValidationErrors::NotEnoughPow => "not_enough_pow",
+            BlockValidationErrors::BadMerkleRoot => "bad_merkle_root",
+            BlockValidationErrors::BadWitnessCommitment => "bad_witness_commitment",
+            BlockValidationErrors::NotEnoughMoney => "not_enough_money",
+            BlockValidationErrors::FirstTxIsnNotCoinbase => "first_tx_is_not_coinbase",
+            BlockValidationErrors::BadCoinbaseOutValue => "bad_coinbase_out_value",
+            BlockValidationErrors::EmptyBlock => "empty_block",
+            BlockValidationErrors::BlockExtendsAnOrphanChain => "block_extends_an_orphan_chain",
+            BlockValidationErrors::BadBip34 => "bad_bip34",
+            BlockValidationErrors::CoinbaseNotMatured => "coinbase_not_matured",
+            _ => "other",
+        }
+    }
+    /// Maps a floresta-chain error, for `map_err`
+    pub(crate) fn json(stage: &'static str) -> impl Fn(BlockchainError) -> String {
+        move |error| {
+            let error = Self::new(stage, &error);
+            serde_json::to_string(&error).unwrap_or(error.details)
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Why a block coming from the bridge was rejected before touching any state
+pub(crate) enum BlockValidationError {
+    /// The JSON payload is bigger than MAX_BLOCK_JSON_SIZE
+    PayloadTooBig(usize),
+    /// More transactions than could fit in a valid block
+    TooManyTransactions(usize),
+    /// More leaves than inputs spending them
+    TooManyLeaves { leaves: usize, inputs: usize },
+    /// More proof targets than leaves being deleted
+    TooManyTargets { targets: usize, leaves: usize },
+    /// A proof hash that isn't 32 bytes of hex
+    InvalidProofHash(String),
+    /// An output or leaf amount above MAX_MONEY
+    AmountTooBig(u64),
+    /// A leaf script bigger than MAX_SCRIPT_SIZE
+    ScriptTooBig(usize),
+    /// The transactions don't match the merkle root in the header
+    MerkleRootMismatch,
+    /// The witnesses don't match the commitment in the coinbase
+    WitnessCommitmentMismatch,
+}
@@ -0,0 +1,620 @@
+// SPDX-License-Identifier: MIT
+
+//! The key value store the chain state and our own data are kept in.
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize)]
+/// A rescan over a range of blocks, kept in the store so it can be resumed after a restart
+pub(crate) struct RescanSession {
+    /// First and last heights to scan, inclusive
+    pub(crate) start: u32,
+    pub(crate) end: u32,
+    /// The height `rescan_block` expects next
+    pub(crate) next_height: u32,
+    /// The last block scanned, if any
+    pub(crate) last_scanned: Option<BlockHash>,
+    /// Unix time at which this rescan was started
+    pub(crate) started_at: u64,
+}
+
+#[derive(Debug, Serialize)]
+/// What `rescan_status` returns
+pub(crate) struct RescanStatus<'a> {
+    #[serde(flatten)]
+    pub(crate) session: &'a RescanSession,
+    pub(crate) scanned: u32,
+    pub(crate) remaining: u32,
+    /// Whether the last scanned block is still in our chain, so the rescan can go on from it
+    pub(crate) resumable: bool,
+}
+
+#[wasm_bindgen]
+#[derive(Default, Debug, Clone)]
+/// A super simple key value ChainStore using a HashMap. This is just for testing
+/// purposes, the contents will be lost when the wasm instance is destroyed. You should
+/// use a proper Wasm database implementation for production. Clones share the same map.
+pub struct WasmStore {
+    pub(crate) store: Rc<RefCell<HashMap<String, String>>>,
+    /// If set, every value is encrypted before going into `store`
+    pub(crate) cipher: Rc<RefCell<Option<StoreCipher>>>,
+    /// Recently used headers, so we don't need to decode (and decrypt) them every time
+    pub(crate) header_cache: Rc<RefCell<HashMap<BlockHash, floresta_chain::DiskBlockHeader>>>,
+    pub(crate) stats: Rc<RefCell<StoreStats>>,
+    /// How many bytes of keys and values are in `store`, kept up to date on every write
+    pub(crate) bytes_stored: Rc<Cell<usize>>,
+    /// Whether some FlorestaChain is writing to this store, only one may do it at a time
+    pub(crate) has_writer: Rc<Cell<bool>>,
+    pub(crate) flush_policy: Rc<Cell<FlushPolicy>>,
+    /// What `store` looked like at the last flush, this is what read only views see
+    pub(crate) flushed: Rc<RefCell<HashMap<String, String>>>,
+    /// Keys written or removed since the last flush
+    pub(crate) dirty: Rc<RefCell<HashSet<String>>>,
+    /// Whether this handle belongs to a read only view, and should read from `flushed`
+    pub(crate) read_view: bool,
+    /// The lowest height in the index of `store` and of `flushed`, None until someone asks
+    pub(crate) lowest_height: Rc<Cell<Option<u32>>>,
+    pub(crate) flushed_lowest_height: Rc<Cell<Option<u32>>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// How often flushes requested by the chain actually reach the backend, see
+/// `set_flush_policy`
+pub(crate) struct FlushPolicy {
+    /// Flush once this many flushes were requested, 0 or 1 flushes every time
+    pub(crate) every_n_blocks: u32,
+    /// Flush if this many milliseconds passed since the last one, 0 to disable
+    pub(crate) max_ms: f64,
+    /// Flushes requested since the last one we did
+    pub(crate) pending: u32,
+    pub(crate) last_flush_ms: f64,
+}
+
+/// How many headers we keep in the header cache
+pub(crate) const HEADER_CACHE_SIZE: usize = 2_000;
+
+#[derive(Debug, Default, Clone, Serialize)]
+/// Counters for what the store has been doing. Those are just integers, so they are always on.
+pub(crate) struct StoreStats {
+    pub(crate) gets: u64,
+    pub(crate) puts: u64,
+    pub(crate) cache_hits: u64,
+    pub(crate) cache_misses: u64,
+    pub(crate) bytes_read: u64,
+    pub(crate) bytes_written: u64,
+    /// Flushes that actually reached the backend
+    pub(crate) flushes: u64,
+}
+
+/// Encrypts values in the store with ChaCha20-Poly1305. Each value is stored as a counter
+/// followed by the ciphertext, the nonce is derived from the store key and that counter, so
+/// overwriting a key never reuses a nonce. The key is also authenticated, so values can't be
+/// moved around between keys.
+pub(crate) struct StoreCipher {
+    pub(crate) aead: ChaCha20Poly1305,
+    /// Starts at a random value, so nonces don't repeat across sessions using the same key
+    pub(crate) counter: u64,
+}
+
+impl std::fmt::Debug for StoreCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StoreCipher")
+    }
+}
+
+impl StoreCipher {
+    pub(crate) fn nonce(key: &str, counter: u64) -> [u8; 12] {
+        let mut preimage = key.as_bytes().to_vec();
+        preimage.extend(counter.to_le_bytes());
+        let hash = sha256::Hash::hash(&preimage);
+        let mut nonce = [0; 12];
+        nonce.copy_from_slice(&hash[..12]);
+        nonce
+    }
+    pub(crate) fn encrypt(&mut self, key: &str, value: &[u8]) -> Vec<u8> {
+        self.counter = self.counter.wrapping_add(1);
+        let nonce = Self::nonce(key, self.counter);
+        let payload = Payload {
+            msg: value,
+            aad: key.as_bytes(),
+        };
+        let ciphertext = self
+            .aead
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .expect("encrypting into a Vec can't fail");
+        let mut out = self.counter.to_le_bytes().to_vec();
+        out.extend(ciphertext);
+        out
+    }
+    pub(crate) fn decrypt(&self, key: &str, value: &[u8]) -> Result<Vec<u8>, Error> {
+        if value.len() < 8 {
+            return Err(Error::Tampered(key.into()));
+        }
+        let (counter, ciphertext) = value.split_at(8);
+        let counter = u64::from_le_bytes(counter.try_into().expect("split at 8"));
+        let nonce = Self::nonce(key, counter);
+        let payload = Payload {
+            msg: ciphertext,
+            aad: key.as_bytes(),
+        };
+        self.aead
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::Tampered(key.into()))
+    }
+}
+
+/// How deep a reorg we protect against. Data for stale branches forking less than this many
+/// blocks below the tip is kept, since that branch may still become the best one.
+pub(crate) const REORG_PROTECTION_DEPTH: u32 = 100;
+
+#[derive(Debug, Default, Serialize)]
+/// The result of walking the best chain, returned by `verify_chainstate`
+pub(crate) struct ChainstateReport {
+    /// The lowest height we have in the index, 0 or the snapshot height
+    pub(crate) start_height: u32,
+    pub(crate) tip_height: u32,
+    /// The highest height up to which everything checks out, None if even the first entry
+    /// is broken
+    pub(crate) last_consistent_height: Option<u32>,
+    pub(crate) issues: Vec<ChainstateIssue>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ChainstateIssue {
+    pub(crate) height: u32,
+    pub(crate) problem: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+/// What `compact_store` removed
+pub(crate) struct CompactionReport {
+    pub(crate) removed_headers: usize,
+    pub(crate) removed_index_entries: usize,
+    pub(crate) bytes_freed: usize,
+}
+
+impl WasmStore {
+    /// The map we read from: the working one for writers, the flushed one for views
+    pub(crate) fn map(&self) -> &Rc<RefCell<HashMap<String, String>>> {
+        match self.read_view {
+            true => &self.flushed,
+            false => &self.store,
+        }
+    }
+    /// Returns a handle for a read only view. It shares our data, but only sees what was
+    /// flushed, and has its own header cache so it can't see unflushed headers through it.
+    pub(crate) fn read_view(&self) -> WasmStore {
+        WasmStore {
+            header_cache: Rc::default(),
+            read_view: true,
+            ..self.clone()
+        }
+    }
+    /// Reads a value, decrypting it if we have a cipher
+    pub(crate) fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut stats = self.stats.borrow_mut();
+        stats.gets += 1;
+        let Some(value) = self.map().borrow().get(key).cloned() else {
+            return Ok(None);
+        };
+        stats.bytes_read += value.len() as u64;
+        drop(stats);
+        let value = hex::decode(value).map_err(|_| Error::Corrupted(key.into()))?;
+        match self.cipher.borrow().as_ref() {
+            Some(cipher) => cipher.decrypt(key, &value).map(Some),
+            None => Ok(Some(value)),
+        }
+    }
+    /// Writes a value, encrypting it if we have a cipher
+    pub(crate) fn put(&self, key: String, value: &[u8]) {
+        let value = match self.cipher.borrow_mut().as_mut() {
+            Some(cipher) => cipher.encrypt(&key, value),
+            None => value.to_vec(),
+        };
+        let value = hex::encode(value);
+        let mut stats = self.stats.borrow_mut();
+        stats.puts += 1;
+        stats.bytes_written += value.len() as u64;
+        let added = key.len() + value.len();
+        if let Some(height) = Self::index_height(&key) {
+            let lowest = self.lowest_height.get();
+            self.lowest_height
+                .set(lowest.map(|lowest| lowest.min(height)));
+        }
+        // If we are overwriting, the key was already accounted for
+        let replaced = match self.store.borrow_mut().insert(key.clone(), value) {
+            Some(old) => key.len() + old.len(),
+            None => 0,
+        };
+        self.dirty.borrow_mut().insert(key.clone());
+        self.bytes_stored
+            .set(self.bytes_stored.get() + added - replaced);
+    }
+    /// Removes a value, returning it in its stored (hex) form
+    pub(crate) fn remove(&self, key: &str) -> Option<String> {
+        let value = self.store.borrow_mut().remove(key)?;
+        let height = Self::index_height(key);
+        if height.is_some() && height == self.lowest_height.get() {
+            self.lowest_height.set(None);
+        }
+        self.dirty.borrow_mut().insert(key.to_string());
+        self.bytes_stored
+            .set(self.bytes_stored.get() - key.len() - value.len());
+        Some(value)
+    }
+    /// Adds a header to the cache, making room for it if needed. We don't track which header
+    /// is the oldest, so we evict an arbitrary one. During sync most lookups are for recent
+    /// headers that were just saved, so this works well enough.
+    pub(crate) fn cache_header(&self, hash: BlockHash, header: &floresta_chain::DiskBlockHeader) {
+        let mut cache = self.header_cache.borrow_mut();
+        if cache.len() >= HEADER_CACHE_SIZE && !cache.contains_key(&hash) {
+            if let Some(evicted) = cache.keys().next().copied() {
+                cache.remove(&evicted);
+            }
+        }
+        cache.insert(hash, header.clone());
+    }
+    /// Reads the provenance of the snapshot this store was bootstrapped from, if any
+    pub(crate) fn snapshot_provenance(&self) -> Result<Option<SnapshotProvenance>, Error> {
+        self.get("snapshot_provenance")?
+            .map(|value| {
+                serde_json::from_slice(&value)
+                    .map_err(|_| Error::Corrupted("snapshot_provenance".into()))
+            })
+            .transpose()
+    }
+    /// Reads the log of blocks we rejected, see `rejection_log`
+    pub(crate) fn rejected_blocks(&self) -> Result<BTreeMap<BlockHash, RejectedBlock>, Error> {
+        let Some(value) = self.get("rejected_blocks")? else {
+            return Ok(BTreeMap::new());
+        };
+        serde_json::from_slice(&value).map_err(|_| Error::Corrupted("rejected_blocks".into()))
+    }
+    pub(crate) fn set_rejected_blocks(&self, rejected: &BTreeMap<BlockHash, RejectedBlock>) {
+        self.put(
+            "rejected_blocks".into(),
+            &serde_json::to_vec(rejected).expect("serializing can't fail"),
+        );
+    }
+    /// Reads the coins reserved with `mark_reserved`, and the height their reservation ends at
+    pub(crate) fn utxo_reservations(&self) -> Result<BTreeMap<OutPoint, u32>, Error> {
+        let Some(value) = self.get("utxo_reservations")? else {
+            return Ok(BTreeMap::new());
+        };
+        let reservations: Vec<(OutPoint, u32)> = serde_json::from_slice(&value)
+            .map_err(|_| Error::Corrupted("utxo_reservations".into()))?;
+        Ok(reservations.into_iter().collect())
+    }
+    pub(crate) fn set_utxo_reservations(&self, reservations: &BTreeMap<OutPoint, u32>) {
+        let reservations = reservations.iter().collect::<Vec<_>>();
+        self.put(
+            "utxo_reservations".into(),
+            &serde_json::to_vec(&reservations).expect("serializing can't fail"),
+        );
+    }
+    /// Reads the rescan in progress, if any
+    pub(crate) fn rescan_session(&self) -> Result<Option<RescanSession>, Error> {
+        self.get("rescan_session")?
+            .map(|value| {
+                serde_json::from_slice(&value)
+                    .map_err(|_| Error::Corrupted("rescan_session".into()))
+            })
+            .transpose()
+    }
+    /// Saves the rescan in progress, or removes it if it's done
+    pub(crate) fn set_rescan_session(&self, session: Option<&RescanSession>) {
+        match session {
+            Some(session) => self.put(
+                "rescan_session".into(),
+                &serde_json::to_vec(session).expect("serializing can't fail"),
+            ),
+            None => {
+                self.remove("rescan_session");
+            }
+        }
+    }
+    /// The report of the last initial sync, if we finished one
+    pub(crate) fn sync_report(&self) -> Result<Option<SyncReport>, Error> {
+        self.get("sync_report")?
+            .map(|value| {
+                serde_json::from_slice(&value).map_err(|_| Error::Corrupted("sync_report".into()))
+            })
+            .transpose()
+    }
+    pub(crate) fn set_sync_report(&self, report: &SyncReport) {
+        self.put(
+            "sync_report".into(),
+            &serde_json::to_vec(report).expect("serializing can't fail"),
+        );
+    }
+    /// Writes everything to the backend, regardless of the flush policy. Our map is the
+    /// backend for now, so we only publish the keys changed since the last flush to read
+    /// only views, but a persistent backend would write them here.
+    pub(crate) fn flush_now(&self) -> Result<(), Error> {
+        {
+            let store = self.store.borrow();
+            let mut flushed = self.flushed.borrow_mut();
+            for key in self.dirty.borrow_mut().drain() {
+                match store.get(&key) {
+                    Some(value) => flushed.insert(key, value.clone()),
+                    None => flushed.remove(&key),
+                };
+            }
+        }
+        self.flushed_lowest_height.set(self.lowest_height.get());
+        self.stats.borrow_mut().flushes += 1;
+        let mut policy = self.flush_policy.get();
+        policy.pending = 0;
+        policy.last_flush_ms = now_ms();
+        self.flush_policy.set(policy);
+        Ok(())
+    }
+    /// Reads and deserializes a value
+    pub(crate) fn get_decoded<T: consensus::Decodable>(
+        &self,
+        key: &str,
+    ) -> Result<Option<T>, Error> {
+        self.get(key)?
+            .map(|value| consensus::deserialize(&value).map_err(|_| Error::Corrupted(key.into())))
+            .transpose()
+    }
+    /// Starts encrypting everything in this store with `key`. Values already in the store
+    /// are encrypted in place, so this may be called on a store that already has data.
+    /// Nonces come from a counter starting at `counter`, which should be random so two stores
+    /// with the same key don't reuse nonces.
+    pub(crate) fn set_cipher(&self, key: &[u8], counter: u64) -> Result<(), String> {
+        if self.cipher.borrow().is_some() {
+            return Err("This store is already encrypted".into());
+        }
+        if key.len() != 32 {
+            return Err("The store key must be 32 bytes long".into());
+        }
+        let cipher = StoreCipher {
+            aead: ChaCha20Poly1305::new(Key::from_slice(key)),
+            counter,
+        };
+
+        // Take every plaintext value out, and write it back encrypted
+        let plaintext = self
+            .store
+            .borrow()
+            .keys()
+            .map(|key| Ok((key.clone(), self.get(key)?.unwrap_or_default())))
+            .collect::<Result<Vec<_>, Error>>()
+            .map_err(|e| format!("Can't migrate the store: {e:?}"))?;
+        *self.cipher.borrow_mut() = Some(cipher);
+        for (key, value) in plaintext {
+            self.put(key, &value);
+        }
+        Ok(())
+    }
+    /// The number of accumulator leaves saved with our roots, which come after it
+    pub(crate) fn stored_leaves(&self) -> u64 {
+        self.get("roots")
+            .ok()
+            .flatten()
+            .and_then(|roots| consensus::deserialize_partial::<u64>(&roots).ok())
+            .map_or(0, |(leaves, _)| leaves)
+    }
+    /// The lowest height in our index. This is 0 for chains synced from genesis, or the
+    /// snapshot height for chains built from a snapshot. This is cached, so we only scan the
+    /// store the first time or after the lowest entry got removed.
+    pub(crate) fn lowest_indexed_height(&self) -> u32 {
+        let cached = match self.read_view {
+            true => &self.flushed_lowest_height,
+            false => &self.lowest_height,
+        };
+        if let Some(height) = cached.get() {
+            return height;
+        }
+        let lowest = self
+            .map()
+            .borrow()
+            .keys()
+            .filter_map(|key| Self::index_height(key))
+            .min();
+        // An empty index isn't cached, or the first entry would be compared against 0
+        cached.set(lowest);
+        lowest.unwrap_or(0)
+    }
+    /// The height of an index entry's key, None for other keys
+    pub(crate) fn index_height(key: &str) -> Option<u32> {
+        key.strip_prefix("index")?.parse().ok()
+    }
+    /// Walks the index from its lowest height up to `tip_height`, checking that every indexed
+    /// header exists, hashes to the indexed value and links to the one before it.
+    pub(crate) fn verify_chain(&self, tip_height: u32) -> ChainstateReport {
+        let start_height = self.lowest_indexed_height();
+        let mut report = ChainstateReport {
+            start_height,
+            tip_height,
+            ..Default::default()
+        };
+
+        let mut previous: Option<BlockHash> = None;
+        for height in start_height..=tip_height {
+            let problem = match self.get_decoded::<BlockHash>(&format!("index{height}")) {
+                Err(e) => Some(format!("Index entry can't be read: {e:?}")),
+                Ok(None) => Some("Missing index entry".to_string()),
+                Ok(Some(hash)) => {
+                    match self.get_decoded::<floresta_chain::DiskBlockHeader>(&hash.to_string()) {
+                        Err(e) => Some(format!("Header {hash} can't be read: {e:?}")),
+                        Ok(None) => Some(format!("Missing header {hash}")),
+                        Ok(Some(header)) if header.block_hash() != hash => {
+                            Some(format!("Header indexed as {hash} hashes to something else"))
+                        }
+                        Ok(Some(header))
+                            if previous.map_or(false, |prev| header.prev_blockhash != prev) =>
+                        {
+                            Some(format!("Header {hash} doesn't link to the one below it"))
+                        }
+                        Ok(Some(_)) => {
+                            previous = Some(hash);
+                            None
+                        }
+                    }
+                }
+            };
+            match problem {
+                Some(problem) => {
+                    report.issues.push(ChainstateIssue { height, problem });
+                    // Everything above a broken link is meaningless
+                    break;
+                }
+                None => report.last_consistent_height = Some(height),
+            }
+        }
+        report
+    }
+    /// Drops headers that aren't in the best chain and aren't recent enough to matter for a
+    /// reorg, and index entries above the tip. Keys that aren't headers or index entries are
+    /// never touched, so roots, height and anything else we store stays around.
+    pub(crate) fn compact(&self, tip_height: u32) -> Result<CompactionReport, Error> {
+        let mut report = CompactionReport::default();
+        let keep_from = tip_height.saturating_sub(REORG_PROTECTION_DEPTH);
+        let keys = self.store.borrow().keys().cloned().collect::<Vec<_>>();
+
+        let mut best_chain = HashSet::new();
+        let mut stale_keys = Vec::new();
+        for key in keys.iter() {
+            let Some(height) = key.strip_prefix("index") else {
+                continue;
+            };
+            match height.parse::<u32>() {
+                Ok(height) if height > tip_height => stale_keys.push(key.clone()),
+                Ok(_) => {
+                    if let Some(hash) = self.get_decoded::<BlockHash>(key)? {
+                        best_chain.insert(hash.to_string());
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+        for key in keys.iter() {
+            if key.len() != 64 || BlockHash::from_str(key).is_err() || best_chain.contains(key) {
+                continue;
+            }
+            // Headers for a stale branch, keep them if they are recent
+            let header = self.get_decoded::<floresta_chain::DiskBlockHeader>(key)?;
+            if let Some(height) = header.and_then(|header| header.height()) {
+                if height < keep_from {
+                    stale_keys.push(key.clone());
+                }
+            }
+        }
+
+        for key in stale_keys {
+            let Some(value) = self.remove(&key) else {
+                continue;
+            };
+            if key.starts_with("index") {
+                report.removed_index_entries += 1;
+            } else {
+                if let Ok(hash) = BlockHash::from_str(&key) {
+                    self.header_cache.borrow_mut().remove(&hash);
+                }
+                report.removed_headers += 1;
+            }
+            report.bytes_freed += key.len() + value.len();
+        }
+        Ok(report)
+    }
+}
+
+impl ChainStore for WasmStore {
+    type Error = Error;
+    fn save_roots(&self, roots: Vec<u8>) -> Result<(), Error> {
+        self.put("roots".into(), &roots);
+        Ok(())
+    }
+
+    fn load_roots(&self) -> Result<Option<Vec<u8>>, Error> {
+        self.get("roots")
+    }
+
+    fn load_height(&self) -> Result<Option<floresta_chain::BestChain>, Error> {
+        self.get_decoded("height")
+    }
+
+    fn save_height(&self, height: &floresta_chain::BestChain) -> Result<(), Error> {
+        self.put("height".into(), &consensus::serialize(height));
+        Ok(())
+    }
+
+    fn get_header(
+        &self,
+        block_hash: &BlockHash,
+    ) -> Result<Option<floresta_chain::DiskBlockHeader>, Error> {
+        if let Some(header) = self.header_cache.borrow().get(block_hash) {
+            self.stats.borrow_mut().cache_hits += 1;
+            return Ok(Some(header.clone()));
+        }
+        self.stats.borrow_mut().cache_misses += 1;
+        let header = self.get_decoded(&block_hash.to_string())?;
+        if let Some(ref header) = header {
+            self.cache_header(*block_hash, header);
+        }
+        Ok(header)
+    }
+
+    fn save_header(&self, header: &floresta_chain::DiskBlockHeader) -> Result<(), Error> {
+        self.put(
+            header.block_hash().to_string(),
+            &consensus::serialize(header),
+        );
+        self.cache_header(header.block_hash(), header);
+        Ok(())
+    }
+
+    fn get_block_hash(&self, height: u32) -> Result<Option<BlockHash>, Error> {
+        self.get_decoded(&format!("index{height}"))
+    }
+
+    fn flush(&self) -> Result<(), Error> {
+        let mut policy = self.flush_policy.get();
+        policy.pending += 1;
+        let due = policy.pending >= policy.every_n_blocks
+            || (policy.max_ms > 0.0 && now_ms() - policy.last_flush_ms >= policy.max_ms);
+        if due {
+            return self.flush_now();
+        }
+        self.flush_policy.set(policy);
+        Ok(())
+    }
+
+    fn update_block_index(&self, height: u32, hash: BlockHash) -> Result<(), Error> {
+        self.put(format!("index{height}"), &consensus::serialize(&hash));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn values_round_trip_before_and_after_encrypting() {
+        let store = WasmStore::default();
+        store.put("plain".into(), &[1, 2, 3]);
+        store.set_cipher(&[7; 32], 0).unwrap();
+        assert_eq!(store.get("plain").unwrap(), Some(vec![1, 2, 3]));
+
+        store.put("secret".into(), &[4, 5]);
+        assert_eq!(store.get("secret").unwrap(), Some(vec![4, 5]));
+        // We keep the counter and the ciphertext, not the value
+        assert_ne!(store.map().borrow()["secret"], hex::encode([4, 5]));
+
+        let session = RescanSession {
+            start: 1,
+            end: 10,
+            next_height: 3,
+            last_scanned: None,
+            started_at: 0,
+        };
+        store.set_rescan_session(Some(&session));
+        let saved = store.rescan_session().unwrap().unwrap();
+        assert_eq!((saved.start, saved.end, saved.next_height), (1, 10, 3));
+        store.set_rescan_session(None);
+        assert!(store.rescan_session().unwrap().is_none());
+    }
+}
@@ -0,0 +1,1401 @@
+// SPDX-License-Identifier: MIT
+
+use super::*;
+
+#[test]
+fn read_views_only_see_flushed_data() {
+    let writer = WasmStore::default();
+    let view = writer.read_view();
+    writer.put("height".into(), &[1]);
+    assert_eq!(view.get("height").unwrap(), None);
+    assert_eq!(writer.get("height").unwrap(), Some(vec![1]));
+
+    writer.flush_now().unwrap();
+    assert_eq!(view.get("height").unwrap(), Some(vec![1]));
+
+    // Overwrites and removals are also held back until the next flush
+    writer.put("height".into(), &[2]);
+    writer.remove("height");
+    assert_eq!(view.get("height").unwrap(), Some(vec![1]));
+    writer.flush_now().unwrap();
+    assert_eq!(view.get("height").unwrap(), None);
+}
+
+#[test]
+fn lowest_indexed_height_follows_writes() {
+    let writer = WasmStore::default();
+    let view = writer.read_view();
+    assert_eq!(writer.lowest_indexed_height(), 0);
+    writer.put("index1000".into(), &[0]);
+    assert_eq!(writer.lowest_indexed_height(), 1000);
+    // Backfilling moves it down, and other keys don't count
+    writer.put("index900".into(), &[0]);
+    writer.put("index_version".into(), &[0]);
+    assert_eq!(writer.lowest_indexed_height(), 900);
+    assert_eq!(view.lowest_indexed_height(), 0);
+
+    writer.flush_now().unwrap();
+    assert_eq!(view.lowest_indexed_height(), 900);
+    writer.remove("index900");
+    assert_eq!(writer.lowest_indexed_height(), 1000);
+    assert_eq!(view.lowest_indexed_height(), 900);
+    writer.flush_now().unwrap();
+    assert_eq!(view.lowest_indexed_height(), 1000);
+}
+
+#[test]
+fn open_shared_borrows_the_handle() {
+    let chain = unsafe { FlorestaChain::new() };
+    let handle = unsafe { chain.store_handle() };
+    assert!(unsafe { FlorestaChain::open_shared(&handle, true) }.is_err());
+    // We still own the handle, and it still points to the same data
+    handle.put("key".into(), &[1]);
+    assert_eq!(chain.store.get("key").unwrap(), Some(vec![1]));
+}
+
+/// The x-only key of the secp256k1 generator, we never need to sign with it
+const TEST_KEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+fn test_tx(input: Vec<TxIn>, output: Vec<TxOut>) -> Transaction {
+    Transaction {
+        version: 2,
+        lock_time: PackedLockTime(0),
+        input,
+        output,
+    }
+}
+
+fn test_input(previous_output: OutPoint, witness: Vec<Vec<u8>>) -> TxIn {
+    TxIn {
+        previous_output,
+        script_sig: Script::new(),
+        sequence: Sequence::MAX,
+        witness: Witness::from_vec(witness),
+    }
+}
+
+#[test]
+fn taproot_key_and_script_path_spends() {
+    let chain = unsafe { FlorestaChain::new() };
+    let leaf = Script::from(hex::decode(format!("20{TEST_KEY}ac")).unwrap());
+    let other_leaf = Script::from(vec![0x51]);
+    let leaves = format!(
+        r#"[{{"depth": 1, "script": "{}"}}, {{"depth": 1, "script": "51"}}]"#,
+        hex::encode(leaf.as_bytes())
+    );
+    let address = unsafe { chain.add_taproot_output(TEST_KEY.into(), leaves) }.unwrap();
+    let script_pubkey = Address::from_str(&address).unwrap().script_pubkey();
+
+    let funding = test_tx(
+        vec![test_input(OutPoint::default(), vec![])],
+        vec![
+            TxOut {
+                value: 10_000,
+                script_pubkey: script_pubkey.clone(),
+            },
+            TxOut {
+                value: 20_000,
+                script_pubkey,
+            },
+        ],
+    );
+    let no_coins = HashMap::new();
+    let mut cost = FilterCost::default();
+    assert!(chain.wallet.scan_transaction(
+        &chain.secp,
+        &funding,
+        &ScriptKey::outputs(&funding),
+        1,
+        1,
+        &no_coins,
+        &mut cost
+    ));
+
+    // Same tree, so we get the control block the wallet expects
+    let spend_info = TaprootBuilder::new()
+        .add_leaf(1, leaf.clone())
+        .unwrap()
+        .add_leaf(1, other_leaf)
+        .unwrap()
+        .finalize(&chain.secp, XOnlyPublicKey::from_str(TEST_KEY).unwrap())
+        .unwrap();
+    let control_block = spend_info
+        .control_block(&(leaf.clone(), LeafVersion::TapScript))
+        .unwrap();
+    let spending = test_tx(
+        vec![
+            test_input(OutPoint::new(funding.txid(), 0), vec![vec![1; 64]]),
+            test_input(
+                OutPoint::new(funding.txid(), 1),
+                vec![vec![1; 64], leaf.to_bytes(), control_block.serialize()],
+            ),
+        ],
+        vec![],
+    );
+    assert!(chain.wallet.scan_transaction(
+        &chain.secp,
+        &spending,
+        &ScriptKey::outputs(&spending),
+        2,
+        1,
+        &no_coins,
+        &mut cost
+    ));
+
+    let history = chain.wallet.history.borrow();
+    let spends = &history.get(&(2, 1)).unwrap().spends;
+    assert!(matches!(spends[0].kind, SpendKind::TaprootKeyPath));
+    match &spends[1].kind {
+        SpendKind::TaprootScriptPath {
+            leaf_script,
+            leaf_version,
+        } => {
+            assert_eq!(*leaf_script, hex::encode(leaf.as_bytes()));
+            assert_eq!(*leaf_version, 0xc0);
+        }
+        kind => panic!("Expected a script path spend, got {kind:?}"),
+    }
+}
+
+#[test]
+fn estimated_state_size_tracks_actual_size() {
+    let chain = unsafe { FlorestaChain::new() };
+    let store = &chain.store;
+    store.put("a".into(), &[0; 100]);
+    store.put("b".into(), &[0; 10]);
+    // Overwriting and removing must give back what was accounted for the old value
+    store.put("a".into(), &[0; 20]);
+    store.remove("b");
+    let actual = store
+        .store
+        .borrow()
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum::<usize>();
+    assert_eq!(store.bytes_stored.get(), actual);
+
+    let script_pubkey = Script::from(vec![0x51; 34]);
+    let mut address_set = chain.wallet.address_set.borrow_mut();
+    chain.wallet.watch(&mut address_set, script_pubkey.clone());
+    drop(address_set);
+    let tx = test_tx(
+        vec![test_input(OutPoint::default(), vec![])],
+        vec![
+            TxOut {
+                value: 1_000,
+                script_pubkey: script_pubkey.clone(),
+            };
+            3
+        ],
+    );
+    let mut cost = FilterCost::default();
+    chain.wallet.scan_transaction(
+        &chain.secp,
+        &tx,
+        &ScriptKey::outputs(&tx),
+        1,
+        1,
+        &HashMap::new(),
+        &mut cost,
+    );
+
+    // Compare with what the serialized coins and transactions take, give or take a few
+    // bytes of length prefixes
+    let size = *chain.wallet.size.borrow();
+    let serialized_utxos = chain
+        .wallet
+        .utxos
+        .borrow()
+        .iter()
+        .map(|(outpoint, out)| {
+            consensus::serialize(outpoint).len() + consensus::serialize(out).len()
+        })
+        .sum::<usize>();
+    assert!(size.utxos.abs_diff(serialized_utxos) <= 3);
+    assert_eq!(size.transactions, consensus::serialize(&tx).len());
+    assert_eq!(size.scripts, script_pubkey.len());
+
+    chain.wallet.disconnect_above(0);
+    let size = *chain.wallet.size.borrow();
+    assert_eq!((size.utxos, size.transactions), (0, 0));
+}
+
+/// A coinbase paying `output`, with the height push BIP34 asks for
+fn test_coinbase(height: u32, output: Vec<TxOut>) -> Transaction {
+    let script_sig = Builder::new()
+        .push_int(height as i64)
+        .push_int(0)
+        .into_script();
+    test_tx(
+        vec![TxIn {
+            script_sig,
+            ..test_input(OutPoint::null(), vec![])
+        }],
+        output,
+    )
+}
+
+/// Builds a block on top of `prev`, with the same difficulty, and mines it. Blocks on
+/// top of signet's genesis need about 16k hashes.
+fn test_block(prev: &BlockHeader, txdata: Vec<Transaction>) -> Block {
+    let mut block = Block {
+        header: BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: prev.block_hash(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: prev.time + 600,
+            bits: prev.bits,
+            nonce: 0,
+        },
+        txdata,
+    };
+    block.header.merkle_root = block.compute_merkle_root().unwrap();
+    while block.header.validate_pow(&block.header.target()).is_err() {
+        block.header.nonce += 1;
+    }
+    block
+}
+
+/// The JSON `accept_block` takes, for a block that spends nothing
+fn test_block_json(block: &Block) -> String {
+    serde_json::to_string(&WasmBlock {
+        block: block.clone(),
+        proof: JsonProof {
+            targets: vec![],
+            hashes: vec![],
+        },
+        leaf_data: vec![],
+    })
+    .unwrap()
+}
+
+fn genesis_header() -> BlockHeader {
+    bitcoin::blockdata::constants::genesis_block(CHAIN_NETWORK).header
+}
+
+/// Counts the bytes allocated by each thread, so tests running in parallel don't see each
+/// other's allocations
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATED: Cell<isize> = Cell::new(0);
+    static PEAK: Cell<isize> = Cell::new(0);
+}
+
+fn track_allocation(delta: isize) {
+    let _ = ALLOCATED.try_with(|allocated| {
+        allocated.set(allocated.get() + delta);
+        let _ = PEAK.try_with(|peak| peak.set(peak.get().max(allocated.get())));
+    });
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let ptr = std::alloc::System.alloc(layout);
+        if !ptr.is_null() {
+            track_allocation(layout.size() as isize);
+        }
+        ptr
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        track_allocation(-(layout.size() as isize));
+        std::alloc::System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = std::alloc::System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            track_allocation(new_size as isize - layout.size() as isize);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn accept_block_peak_memory() {
+    let mut chain = unsafe { FlorestaChain::new_tracking_only() };
+    // About 1MB, mostly in big scripts, so the size of the structs around them is noise
+    let mut txdata = (0..100)
+        .map(|i| {
+            test_tx(
+                vec![test_input(OutPoint::new(Txid::all_zeros(), i), vec![])],
+                vec![TxOut {
+                    value: 1_000,
+                    script_pubkey: Script::from(vec![0x6a; 9_000]),
+                }],
+            )
+        })
+        .collect::<Vec<_>>();
+    txdata.insert(0, test_coinbase(1, vec![]));
+    let block = test_block(&genesis_header(), txdata);
+    let block_size = consensus::serialize(&block).len() as isize;
+    let json = test_block_json(&block);
+    drop(block);
+
+    // The JSON is already allocated by the caller, we only count what we add on top
+    let baseline = ALLOCATED.with(Cell::get);
+    PEAK.with(|peak| peak.set(baseline));
+    unsafe { chain.accept_block(json, None) }.unwrap();
+    let peak = PEAK.with(Cell::get) - baseline;
+    assert!(
+        peak < 3 * block_size,
+        "Peak of {peak} bytes for a {block_size} byte block"
+    );
+}
+
+#[test]
+fn pathological_inputs_return_errors() {
+    let mut chain = unsafe { FlorestaChain::new() };
+    let depth = 100_000;
+    let nested = format!("{}{}", "[".repeat(depth), "]".repeat(depth));
+    assert!(unsafe { chain.accept_block(nested.clone(), None) }.is_err());
+    let nested = format!(r#"{{"block": {nested}, "proof": {nested}, "leaf_data": []}}"#);
+    assert!(unsafe { chain.accept_block(nested, None) }.is_err());
+    let too_big = " ".repeat(MAX_BLOCK_JSON_SIZE + 1);
+    assert!(unsafe { chain.accept_block(too_big, None) }.is_err());
+
+    // Version, then an input count of 2^64 - 1, and of 2^32 - 1 as a 5 byte varint
+    for tx in ["02000000ffffffffffffffffff", "02000000feffffffff00"] {
+        assert!(unsafe { FlorestaChain::tx_size_info(tx.into()) }.is_err());
+    }
+    // Thousands of nested OP_IFs are just a long list of opcodes to us
+    let script = hex::encode(vec![0x63; 100_000]);
+    assert!(unsafe { FlorestaChain::script_asm(script) }.is_ok());
+}
+
+#[test]
+fn validate_rejects_fuzzed_blocks() {
+    let genesis = genesis_header();
+    let spend = test_tx(
+        vec![test_input(OutPoint::new(Txid::all_zeros(), 0), vec![])],
+        vec![],
+    );
+    let block = WasmBlock {
+        block: test_block(&genesis, vec![test_coinbase(1, vec![]), spend]),
+        proof: JsonProof {
+            targets: vec![],
+            hashes: vec![],
+        },
+        leaf_data: vec![],
+    };
+    assert!(block.validate().is_ok());
+    let leaf = |amount, spk_ty| CompLeafData {
+        header_code: 0,
+        amount,
+        spk_ty,
+    };
+
+    let mut bad = serde_json::from_str::<WasmBlock>(&test_block_json(&block.block)).unwrap();
+    bad.proof.hashes = vec!["00".repeat(31)];
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::InvalidProofHash(_))
+    ));
+    bad.proof.hashes = vec!["zz".repeat(32)];
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::InvalidProofHash(_))
+    ));
+
+    let mut bad = serde_json::from_str::<WasmBlock>(&test_block_json(&block.block)).unwrap();
+    bad.leaf_data = vec![leaf(u64::MAX, ScriptPubkeyType::PubKeyHash)];
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::AmountTooBig(u64::MAX))
+    ));
+    let script = vec![0; MAX_SCRIPT_SIZE + 1].into_boxed_slice();
+    bad.leaf_data = vec![leaf(1, ScriptPubkeyType::Other(script))];
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::ScriptTooBig(_))
+    ));
+    bad.leaf_data = vec![leaf(1, ScriptPubkeyType::PubKeyHash); 2];
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::TooManyLeaves { .. })
+    ));
+    bad.leaf_data = vec![leaf(1, ScriptPubkeyType::PubKeyHash)];
+    bad.proof.targets = vec![0, 1];
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::TooManyTargets { .. })
+    ));
+
+    // Amounts that look negative when read as signed
+    let coinbase = test_coinbase(
+        1,
+        vec![TxOut {
+            value: -1i64 as u64,
+            script_pubkey: Script::new(),
+        }],
+    );
+    let bad = WasmBlock {
+        block: test_block(&genesis, vec![coinbase]),
+        proof: block.proof.clone(),
+        leaf_data: vec![],
+    };
+    assert!(matches!(
+        bad.validate(),
+        Err(BlockValidationError::AmountTooBig(_))
+    ));
+
+    // Header codes with heights we can't have a hash for
+    let mut chain = unsafe { FlorestaChain::new() };
+    for header_code in [u32::MAX, 0, 1] {
+        let leaves = vec![CompLeafData {
+            header_code,
+            ..leaf(1, ScriptPubkeyType::PubKeyHash)
+        }];
+        assert!(chain.process_proof(leaves, &block.block.txdata, 1).is_err());
+    }
+}
+
+#[test]
+fn flipped_byte_fails_the_merkle_check() {
+    let mut chain = unsafe { FlorestaChain::new() };
+    let spend = test_tx(
+        vec![test_input(OutPoint::new(Txid::all_zeros(), 0), vec![])],
+        vec![],
+    );
+    let mut block = test_block(&genesis_header(), vec![test_coinbase(1, vec![]), spend]);
+    let mut raw = consensus::serialize(&block.txdata[1]);
+    let last = raw.len() - 1;
+    raw[last] ^= 1;
+    block.txdata[1] = deserialize(&raw).unwrap();
+
+    let error = unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap_err();
+    assert!(error.contains("MerkleRootMismatch"), "{error}");
+    // Rejected before the header got anywhere
+    let hash = block.block_hash();
+    assert!(!matches!(
+        chain.chain_state.get_block_height(&hash),
+        Ok(Some(_))
+    ));
+}
+
+#[test]
+fn stripped_witnesses_fail_the_commitment_check() {
+    let (mut chain, tip) = snapshot_chain("");
+    let spend = test_tx(
+        vec![test_input(
+            OutPoint::new(Txid::all_zeros(), 0),
+            vec![vec![1; 72], vec![2; 33]],
+        )],
+        vec![],
+    );
+    let mut coinbase = test_coinbase(1001, vec![]);
+    coinbase.input[0].witness = Witness::from_vec(vec![vec![0; 32]]);
+    let witness_root = Block {
+        header: tip,
+        txdata: vec![coinbase.clone(), spend.clone()],
+    }
+    .witness_root()
+    .unwrap();
+    let commitment = Block::compute_witness_commitment(&witness_root, &[0; 32]);
+    let mut script = vec![0xaa, 0x21, 0xa9, 0xed];
+    script.extend_from_slice(&commitment[..]);
+    coinbase.output.push(TxOut {
+        value: 0,
+        script_pubkey: Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_slice(&script)
+            .into_script(),
+    });
+    let block = test_block(&tip, vec![coinbase, spend]);
+    let parsed = serde_json::from_str::<WasmBlock>(&test_block_json(&block)).unwrap();
+    assert!(parsed.validate().is_ok());
+
+    // Only the spend's witness, or every witness including the coinbase's reserved value
+    let mut stripped = block.clone();
+    stripped.txdata[1].input[0].witness = Witness::new();
+    let mut all_stripped = stripped.clone();
+    all_stripped.txdata[0].input[0].witness = Witness::new();
+    let mut altered = block.clone();
+    altered.txdata[1].input[0].witness = Witness::from_vec(vec![vec![3; 72], vec![2; 33]]);
+    for block in [stripped, all_stripped, altered] {
+        let error = unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap_err();
+        assert!(error.contains("WitnessCommitmentMismatch"), "{error}");
+        assert!(!matches!(
+            chain.chain_state.get_block_height(&block.block_hash()),
+            Ok(Some(_))
+        ));
+    }
+}
+
+/// A proof for a transaction in a block on top of genesis, with one more header on top.
+/// Returns the proof and that last header, which proofs can be anchored to.
+fn spv_fixture() -> (SpvProof, BlockHeader) {
+    let spend = test_tx(
+        vec![test_input(OutPoint::new(Txid::all_zeros(), 0), vec![])],
+        vec![],
+    );
+    let block = test_block(
+        &genesis_header(),
+        vec![test_coinbase(1, vec![]), spend.clone()],
+    );
+    let next = test_block(&block.header, vec![test_coinbase(2, vec![])]);
+    let leaves = block
+        .txdata
+        .iter()
+        .map(|tx| TxMerkleNode::from_inner(tx.txid().into_inner()))
+        .collect::<Vec<_>>();
+    let proof = SpvProof {
+        version: SPV_PROOF_VERSION,
+        network: CHAIN_NETWORK.to_string(),
+        tx: hex::encode(consensus::serialize(&spend)),
+        height: 1,
+        position: 1,
+        branch: merkle_branch(&leaves, 1),
+        header: hex::encode(consensus::serialize(&block.header)),
+        headers: vec![hex::encode(consensus::serialize(&next.header))],
+    };
+    (proof, next.header)
+}
+
+#[test]
+fn tampered_spv_proofs_are_refused() {
+    let (proof, anchor) = spv_fixture();
+    let anchor = format!(r#"{{"hash": "{}", "height": 2}}"#, anchor.block_hash());
+    let verify = |proof: &SpvProof| unsafe {
+        FlorestaChain::verify_spv_proof(
+            serde_json::to_string(proof).unwrap(),
+            CHAIN_NETWORK.to_string(),
+            Some(anchor.clone()),
+            None,
+        )
+    };
+    let result = verify(&proof).unwrap();
+    assert!(result.contains(r#""confirmations":2"#), "{result}");
+    assert!(result.contains(r#""height":1"#), "{result}");
+
+    let tampered = |tamper: fn(&mut SpvProof)| {
+        let mut proof = serde_json::from_str(&serde_json::to_string(&proof).unwrap()).unwrap();
+        tamper(&mut proof);
+        verify(&proof)
+    };
+    // A different transaction, or the right one at the wrong position
+    assert!(tampered(|proof| {
+        let mut raw = hex::decode(&proof.tx).unwrap();
+        let last = raw.len() - 1;
+        raw[last] ^= 1;
+        proof.tx = hex::encode(raw);
+    })
+    .is_err());
+    assert!(tampered(|proof| proof.position = 0).is_err());
+    assert!(tampered(|proof| proof.branch.clear()).is_err());
+    // Headers that don't connect, or without proof of work
+    assert!(tampered(|proof| proof.headers = vec![proof.header.clone()]).is_err());
+    assert!(tampered(|proof| {
+        let mut header: BlockHeader = deserialize(&hex::decode(&proof.header).unwrap()).unwrap();
+        while header.validate_pow(&header.target()).is_ok() {
+            header.nonce += 1;
+        }
+        proof.header = hex::encode(consensus::serialize(&header));
+    })
+    .is_err());
+    assert!(tampered(|proof| proof.version += 1).is_err());
+    assert!(tampered(|proof| proof.network = "bitcoin".into()).is_err());
+    assert!(tampered(|proof| proof.tx = "zz".into()).is_err());
+    // Lying about the height doesn't match the anchor anymore
+    assert!(tampered(|proof| proof.height = 5).is_err());
+}
+
+#[test]
+fn self_mined_spv_proofs_are_refused() {
+    // Anyone can mine headers at the pow limit, like the ones in the fixture
+    let (proof, tip) = spv_fixture();
+    let proof = serde_json::to_string(&proof).unwrap();
+    let verify = |anchor: Option<String>, min_work: Option<String>| unsafe {
+        FlorestaChain::verify_spv_proof(proof.clone(), CHAIN_NETWORK.to_string(), anchor, min_work)
+    };
+
+    let error = verify(None, None).unwrap_err();
+    assert!(error.contains("anchor or a minimum work"), "{error}");
+    // A block we trust that the forged headers don't lead to
+    let trusted = format!(
+        r#"{{"hash": "{}", "height": 2}}"#,
+        BlockHash::hash(b"trusted")
+    );
+    let error = verify(Some(trusted), None).unwrap_err();
+    assert_eq!(error, "The headers don't reach the anchor");
+    // Two headers at the pow limit are far from a real chain's work
+    let real_work = format!("{:064x}", 1u128 << 80);
+    let error = verify(None, Some(real_work)).unwrap_err();
+    assert!(error.contains("of work"), "{error}");
+
+    // With little enough work required it passes, but the height is only a claim
+    let work = hex::encode(tip.work().mul_u32(2).to_be_bytes());
+    let result = verify(None, Some(work)).unwrap();
+    assert!(result.contains(r#""height":null"#), "{result}");
+}
+
+#[test]
+fn deep_reorgs_need_to_be_forced() {
+    let mut chain = unsafe { FlorestaChain::new_tracking_only() };
+    unsafe { chain.set_max_reorg_depth(1) };
+    let genesis = genesis_header();
+    let a1 = test_block(&genesis, vec![test_coinbase(1, vec![])]);
+    let a2 = test_block(&a1.header, vec![test_coinbase(2, vec![])]);
+    for block in [&a1, &a2] {
+        unsafe { chain.accept_block(test_block_json(block), None) }.unwrap();
+    }
+
+    // A fork from genesis replaces both blocks
+    let paying = |height| {
+        test_coinbase(
+            height,
+            vec![TxOut {
+                value: 1,
+                script_pubkey: Script::new(),
+            }],
+        )
+    };
+    let b1 = test_block(&genesis, vec![paying(1)]);
+    let b2 = test_block(&b1.header, vec![paying(2)]);
+    let b3 = test_block(&b2.header, vec![paying(3)]);
+    let error = unsafe { chain.accept_block(test_block_json(&b1), None) }.unwrap_err();
+    assert!(error.contains("would reorg 2 blocks"), "{error}");
+    let error = unsafe { chain.accept_block(test_block_json(&b2), None) }.unwrap_err();
+    assert!(error.contains("refused by policy"), "{error}");
+    assert!(unsafe { chain.force_reorg(a1.block_hash().to_string()) }.is_err());
+
+    unsafe { chain.force_reorg(b1.block_hash().to_string()) }.unwrap();
+    unsafe { chain.accept_block(test_block_json(&b1), None) }.unwrap();
+    // b2 was refused on its own, so it needs a retry
+    unsafe { chain.accept_block(test_block_json(&b2), Some(true)) }.unwrap();
+    unsafe { chain.accept_block(test_block_json(&b3), None) }.unwrap();
+    let (height, tip) = chain.chain_state.get_best_block().unwrap();
+    assert_eq!((height, tip), (3, b3.block_hash()));
+}
+
+#[test]
+fn rescanning_a_block_adds_no_duplicates() {
+    let chain = unsafe { FlorestaChain::new() };
+    let script_pubkey = Script::from(vec![0x51; 34]);
+    let mut address_set = chain.wallet.address_set.borrow_mut();
+    chain.wallet.watch(&mut address_set, script_pubkey.clone());
+    drop(address_set);
+    let pay = |height| {
+        test_coinbase(
+            height,
+            vec![TxOut {
+                value: 1_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        )
+    };
+    let genesis = genesis_header();
+    let first = test_block(&genesis, vec![pay(1)]);
+    let second = test_block(&first.header, vec![pay(2)]);
+
+    // Out of order, and the second one twice, like a rescan after connecting it
+    let no_coins = HashMap::new();
+    for (block, height) in [(&second, 2), (&first, 1), (&second, 2)] {
+        chain
+            .wallet
+            .scan_block_for_wallet(&chain.secp, block, height, &no_coins);
+    }
+    let txids = chain
+        .wallet
+        .history
+        .borrow()
+        .values()
+        .map(|entry| entry.txid)
+        .collect::<Vec<_>>();
+    assert_eq!(txids, vec![first.txdata[0].txid(), second.txdata[0].txid()]);
+    assert_eq!(chain.wallet.utxos.borrow().len(), 2);
+    assert_eq!(chain.wallet.stats.borrow().total_received, 2_000);
+}
+
+#[test]
+fn verify_proofs_batch_entries() {
+    // With a single leaf, the root is the leaf itself and proofs have no hashes
+    let leaf = sha256::Hash::hash(b"leaf");
+    let stump = rustreexo::accumulator::stump::Stump {
+        leaves: 1,
+        roots: vec![leaf],
+    };
+    let entry = |del_hashes: Vec<String>, targets: Vec<u64>, hashes: Vec<String>| ProofBatchEntry {
+        del_hashes,
+        proof: JsonProof { targets, hashes },
+    };
+    let valid = entry(vec![leaf.to_string()], vec![0], vec![]);
+    assert_eq!(valid.verify(&stump), Ok(true));
+
+    let other = sha256::Hash::hash(b"other").to_string();
+    let invalid = entry(vec![other], vec![0], vec![]);
+    assert!(!invalid.verify(&stump).unwrap_or(false));
+
+    let malformed = [
+        entry(vec!["not a hash".into()], vec![0], vec![]),
+        entry(vec![leaf.to_string()], vec![0], vec!["zz".into()]),
+        entry(vec![leaf.to_string()], vec![1], vec![]),
+        entry(vec![leaf.to_string(); 2], vec![0, 0], vec![]),
+    ];
+    for entry in malformed {
+        assert!(entry.verify(&stump).is_err());
+    }
+}
+
+/// The easiest target there is, so test headers are mined on the first few tries
+const EASY_POW_LIMIT: &str = "7fffff0000000000000000000000000000000000000000000000000000000000";
+
+/// A header with the easiest target, mined
+fn easy_header(prev_blockhash: BlockHash, time: u32) -> BlockHeader {
+    let mut header = BlockHeader {
+        version: 0x2000_0000,
+        prev_blockhash,
+        merkle_root: TxMerkleNode::all_zeros(),
+        time,
+        bits: 0x207f_ffff,
+        nonce: 0,
+    };
+    while header.validate_pow(&header.target()).is_err() {
+        header.nonce += 1;
+    }
+    header
+}
+
+/// A chain built from a snapshot at height 1000, with the easiest target so tests can
+/// mine blocks on top of it. `overrides` are added to the parameter overrides.
+fn snapshot_chain(overrides: &str) -> (FlorestaChain, BlockHeader) {
+    let header = easy_header(BlockHash::all_zeros(), 1_700_000_000);
+    let overrides = format!(r#"{{"pow_limit": "{EASY_POW_LIMIT}"{overrides}}}"#);
+    let chain = unsafe {
+        FlorestaChain::build_chain_from(
+            header.block_hash().to_string(),
+            1000,
+            hex::encode(consensus::serialize(&header)),
+            None,
+            Some(overrides),
+            None,
+        )
+    }
+    .unwrap();
+    (chain, header)
+}
+
+#[test]
+fn getters_work_right_after_construction() {
+    let (snapshot, tip) = snapshot_chain("");
+    for (chain, height, hash) in [
+        (
+            unsafe { FlorestaChain::new() },
+            0,
+            genesis_header().block_hash(),
+        ),
+        (snapshot, 1000, tip.block_hash()),
+    ] {
+        unsafe {
+            assert_eq!(chain.show_height(), height);
+            assert_eq!(chain.return_tip(), hash.to_string());
+            assert_eq!(chain.show_difficulty(), 1);
+            assert!(!chain.show_target().is_empty());
+            assert_eq!(chain.show_network(), "Signet");
+            assert!(chain.get_our_transactions().is_empty());
+            assert!(!chain.is_read_only());
+            assert!(!chain.is_tracking_only());
+            chain.show_ibd();
+            chain.verified_from_height();
+            chain.assumed_valid_below();
+            chain.assumed_base().unwrap();
+            chain.snapshot_provenance().unwrap();
+            chain.estimated_state_size().unwrap();
+            chain.get_metrics().unwrap();
+
+            let view = chain.snapshot_view().unwrap();
+            assert_eq!(view.show_height(), height);
+            assert_eq!(view.return_tip(), hash.to_string());
+            assert_eq!(view.show_balance(), 0);
+            assert_eq!(view.show_immature_balance(), 0);
+            assert_eq!(view.show_utxo_count(), 0);
+            assert_eq!(view.show_tx_count(), 0);
+            view.show_ibd();
+            view.show_roots();
+        }
+    }
+}
+
+#[test]
+fn reorged_transactions_reconfirmed_or_not() {
+    for reincluded in [true, false] {
+        let chain = unsafe { FlorestaChain::new() };
+        let wallet = &chain.wallet;
+        let script_pubkey = Script::from(vec![0x51; 34]);
+        wallet.watch(&mut wallet.address_set.borrow_mut(), script_pubkey.clone());
+        let payment = test_tx(
+            vec![test_input(OutPoint::new(Txid::all_zeros(), 0), vec![])],
+            vec![TxOut {
+                value: 1_000,
+                script_pubkey,
+            }],
+        );
+        let genesis = genesis_header();
+        let stale = test_block(&genesis, vec![test_coinbase(1, vec![]), payment.clone()]);
+        // A different coinbase, so the replacement has another hash
+        let coinbase = test_coinbase(
+            1,
+            vec![TxOut {
+                value: 1,
+                script_pubkey: Script::new(),
+            }],
+        );
+        let mut txdata = vec![coinbase];
+        if reincluded {
+            txdata.push(payment.clone());
+        }
+        let replacement = test_block(&genesis, txdata);
+
+        let no_coins = HashMap::new();
+        wallet.scan_block_for_wallet(&chain.secp, &stale, 1, &no_coins);
+        let reorged = wallet.disconnect_above(0);
+        let stale_blocks = HashMap::from([(1, stale.block_hash())]);
+        wallet.mark_reorged(&reorged, &stale_blocks);
+        wallet.scan_block_for_wallet(&chain.secp, &replacement, 1, &no_coins);
+        let requeued = wallet.requeue_reorged(reorged, &stale_blocks);
+
+        let txid = payment.txid();
+        assert_eq!(requeued, vec![(txid, stale.block_hash(), reincluded)]);
+        assert_eq!(wallet.pending.borrow().contains_key(&txid), !reincluded);
+        let reorged_from = wallet
+            .history
+            .borrow()
+            .values()
+            .find(|entry| entry.txid == txid)
+            .map(|entry| entry.reorged_from);
+        match reincluded {
+            true => assert_eq!(reorged_from, Some(Some(stale.block_hash()))),
+            false => assert_eq!(reorged_from, None),
+        }
+    }
+}
+
+#[test]
+fn addresses_for_other_networks_are_refused() {
+    let secp = Secp256k1::new();
+    let key = PrivateKey::from_slice(&[1; 32], bitcoin::Network::Bitcoin).unwrap();
+    let public_key = key.public_key(&secp);
+    let addresses = |network| {
+        [
+            Address::p2wpkh(&public_key, network).unwrap().to_string(),
+            Address::p2pkh(&public_key, network).to_string(),
+        ]
+    };
+    let chain = unsafe { FlorestaChain::new() };
+    // Signet and testnet share their prefixes
+    for address in addresses(bitcoin::Network::Testnet) {
+        assert!(unsafe { chain.add_address(address, None, None) }.is_ok());
+    }
+    for address in addresses(bitcoin::Network::Bitcoin) {
+        let error = unsafe { chain.add_address(address.clone(), None, None) }.unwrap_err();
+        assert!(error.contains("network bitcoin"), "{error}");
+        assert!(unsafe { chain.add_address(address, None, Some(true)) }.is_ok());
+    }
+    // Regtest has its own bech32 prefix, its base58 addresses look like testnet ones
+    let [bech32, _] = addresses(bitcoin::Network::Regtest);
+    let error = unsafe { chain.add_address(bech32.clone(), None, None) }.unwrap_err();
+    assert!(error.contains("network regtest"), "{error}");
+    assert!(unsafe { chain.add_address(bech32, None, Some(true)) }.is_ok());
+}
+
+#[test]
+fn reconstruction_per_script_type() {
+    let kind = |spk_ty: &str, script_sig: Script, witness: Vec<Vec<u8>>| {
+        let input = TxIn {
+            script_sig,
+            ..test_input(OutPoint::default(), witness)
+        };
+        let input = hex::encode(consensus::serialize(&input));
+        let result = unsafe { FlorestaChain::is_reconstructible(spk_ty.into(), input) };
+        let result: serde_json::Value = serde_json::from_str(&result.unwrap()).unwrap();
+        result["kind"].as_str().unwrap().to_string()
+    };
+    let pushes = |pushes: &[&[u8]]| {
+        pushes
+            .iter()
+            .fold(Builder::new(), |builder, push| builder.push_slice(push))
+            .into_script()
+    };
+    let sig = [1; 71];
+    let key = [2; 33];
+    let none = Script::new;
+    let cases = [
+        (r#""PubKeyHash""#, pushes(&[&sig, &key]), vec![], "derived"),
+        (r#""PubKeyHash""#, pushes(&[&sig]), vec![], "impossible"),
+        (r#""ScriptHash""#, pushes(&[&[0x51]]), vec![], "derived"),
+        (r#""ScriptHash""#, none(), vec![], "impossible"),
+        (
+            r#""WitnessV0PubKeyHash""#,
+            none(),
+            vec![sig.to_vec(), key.to_vec()],
+            "derived",
+        ),
+        (
+            r#""WitnessV0PubKeyHash""#,
+            none(),
+            vec![sig.to_vec()],
+            "impossible",
+        ),
+        (
+            r#""WitnessV0ScriptHash""#,
+            none(),
+            vec![vec![0x51]],
+            "derived",
+        ),
+        (r#""WitnessV0ScriptHash""#, none(), vec![], "impossible"),
+        (r#"{"Other": [81]}"#, none(), vec![], "copied"),
+    ];
+    for (spk_ty, script_sig, witness, expected) in cases {
+        assert_eq!(kind(spk_ty, script_sig, witness), expected, "{spk_ty}");
+    }
+}
+
+/// Everything the wallet learned from blocks, in a form that can be compared
+fn wallet_state(
+    chain: &FlorestaChain,
+) -> (
+    String,
+    HashMap<OutPoint, TxOut>,
+    String,
+    HashMap<Script, u32>,
+) {
+    let wallet = &chain.wallet;
+    let history = wallet
+        .history
+        .borrow()
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let stats = wallet.stats.borrow();
+    (
+        serde_json::to_string(&history).unwrap(),
+        wallet.utxos.borrow().clone(),
+        format!(
+            "{} {} {} {} {:?} {:?}",
+            stats.total_received,
+            stats.total_sent,
+            stats.total_fees,
+            stats.tx_count,
+            stats.first_activity,
+            stats.last_activity
+        ),
+        stats.receive_counts.clone(),
+    )
+}
+
+#[test]
+fn every_path_scans_blocks_the_same_way() {
+    let secp = Secp256k1::new();
+    let key = PrivateKey::from_slice(&[1; 32], CHAIN_NETWORK).unwrap();
+    let address = Address::p2wpkh(&key.public_key(&secp), CHAIN_NETWORK).unwrap();
+    let coinbase = test_coinbase(
+        1001,
+        vec![
+            TxOut {
+                value: 25 * 100_000_000,
+                script_pubkey: address.script_pubkey(),
+            },
+            TxOut {
+                value: 0,
+                script_pubkey: Builder::new()
+                    .push_opcode(opcodes::all::OP_RETURN)
+                    .push_slice(b"floresta")
+                    .into_script(),
+            },
+        ],
+    );
+    let (_, tip) = snapshot_chain("");
+    let block = test_block(&tip, vec![coinbase]);
+    let json = test_block_json(&block);
+
+    let mut states = Vec::new();
+    for path in ["connect", "track", "rescan"] {
+        let (mut chain, _) = snapshot_chain("");
+        unsafe { chain.add_address(address.to_string(), None, None) }.unwrap();
+        match path {
+            "connect" => unsafe { chain.accept_block(json.clone(), None) }.unwrap(),
+            "track" => {
+                chain.tracking_only = true;
+                unsafe { chain.accept_block(json.clone(), None) }.unwrap();
+            }
+            _ => {
+                chain.chain_state.accept_header(block.header).unwrap();
+                unsafe { chain.rescan_block(json.clone()) }.unwrap();
+            }
+        }
+        states.push((path, wallet_state(&chain)));
+    }
+    let (_, connected) = &states[0];
+    assert_eq!(connected.1.len(), 1);
+    for (path, state) in states.iter() {
+        assert_eq!(state, connected, "{path}");
+    }
+}
+
+#[test]
+fn custom_coinbase_maturity_survives_reloads() {
+    let secp = Secp256k1::new();
+    let key = PrivateKey::from_slice(&[1; 32], CHAIN_NETWORK).unwrap();
+    let address = Address::p2wpkh(&key.public_key(&secp), CHAIN_NETWORK).unwrap();
+    let (mut chain, mut tip) = snapshot_chain(r#", "coinbase_maturity": 10"#);
+    unsafe { chain.add_address(address.to_string(), None, None) }.unwrap();
+    assert_eq!(chain.coinbase_maturity(), 10);
+
+    let mut coinbase = None;
+    for height in 1001..=1010 {
+        let script_pubkey = match height {
+            1001 => address.script_pubkey(),
+            _ => Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .into_script(),
+        };
+        let tx = test_coinbase(
+            height,
+            vec![TxOut {
+                value: 0,
+                script_pubkey,
+            }],
+        );
+        coinbase.get_or_insert(OutPoint::new(tx.txid(), 0));
+        let block = test_block(&tip, vec![tx]);
+        unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap();
+        tip = block.header;
+        // A reload must keep the easy pow limit, or the next block would be refused
+        unsafe { chain.flush_store() }.unwrap();
+        unsafe { chain.refresh() }.unwrap();
+
+        let immature = chain.immature_coins();
+        match height {
+            1010 => assert!(immature.is_empty()),
+            _ => assert_eq!(immature.get(&coinbase.unwrap()), Some(&1011)),
+        }
+    }
+}
+
+#[test]
+fn proof_targets_are_normalized() {
+    let hashes = (0..3u8)
+        .map(|i| sha256::Hash::hash(&[i]))
+        .collect::<Vec<_>>();
+    let proof = |targets: Vec<u64>| JsonProof {
+        targets,
+        hashes: vec![],
+    };
+
+    let mut unsorted = proof(vec![5, 1, 3]);
+    let mut del_hashes = hashes.clone();
+    unsorted.normalize(8, &mut del_hashes).unwrap();
+    assert_eq!(unsorted.targets, vec![1, 3, 5]);
+    assert_eq!(del_hashes, vec![hashes[1], hashes[2], hashes[0]]);
+
+    let mut duplicate = proof(vec![3, 1, 3]);
+    assert_eq!(
+        duplicate.normalize(8, &mut hashes.clone()),
+        Err("Proof target 3 appears more than once".into())
+    );
+
+    let mut out_of_range = proof(vec![1, 8, 3]);
+    assert_eq!(
+        out_of_range.normalize(8, &mut hashes.clone()),
+        Err("Proof target 8 is past the last leaf, we have 8".into())
+    );
+    // With no leaves at all, every target is out of range
+    assert!(proof(vec![0]).normalize(0, &mut vec![hashes[0]]).is_err());
+}
+
+#[test]
+fn sync_report_is_persisted() {
+    let (mut chain, tip) = snapshot_chain("");
+    assert_eq!(unsafe { chain.sync_report() }.unwrap(), "null");
+    unsafe { chain.set_ibd(true) };
+
+    let block = test_block(&tip, vec![test_coinbase(1001, vec![])]);
+    unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap();
+    unsafe { chain.set_ibd(false) };
+    let report = unsafe { chain.sync_report() }.unwrap();
+    let parsed: serde_json::Value = serde_json::from_str(&report).unwrap();
+    assert_eq!(parsed["start_height"], 1001);
+    assert_eq!(parsed["end_height"], 1001);
+    assert_eq!(parsed["blocks"], 1);
+    assert_eq!(parsed["bytes"], block.size() as u64);
+
+    // Another instance over the same store reads the same report once it's flushed
+    unsafe { chain.flush_store() }.unwrap();
+    let store = unsafe { chain.store_handle() };
+    let view = unsafe { FlorestaChain::open_shared(&store, false) }.unwrap();
+    assert_eq!(unsafe { view.sync_report() }.unwrap(), report);
+}
+
+#[test]
+fn sweeping_skips_reserved_coins_and_watches_nothing() {
+    let secp = Secp256k1::new();
+    let key = PrivateKey::from_slice(&[1; 32], CHAIN_NETWORK).unwrap();
+    let address = Address::p2wpkh(&key.public_key(&secp), CHAIN_NETWORK).unwrap();
+    let destination = Address::p2wpkh(
+        &PrivateKey::from_slice(&[2; 32], CHAIN_NETWORK)
+            .unwrap()
+            .public_key(&secp),
+        CHAIN_NETWORK,
+    )
+    .unwrap();
+    let (mut chain, tip) = snapshot_chain(r#", "coinbase_maturity": 1"#);
+    let watch_types = r#"["p2wpkh"]"#.to_string();
+    unsafe { chain.import_privkey(key.to_wif(), watch_types) }.unwrap();
+    let output = TxOut {
+        value: 50_000,
+        script_pubkey: address.script_pubkey(),
+    };
+    let coinbase = test_coinbase(1001, vec![output.clone(), output]);
+    let block = test_block(&tip, vec![coinbase.clone()]);
+    unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap();
+    let watched = chain.wallet.address_set.borrow().len();
+
+    // A key we never imported has no coins we know of, and sweeping doesn't import it
+    let other = PrivateKey::from_slice(&[3; 32], CHAIN_NETWORK).unwrap();
+    let sweep = |wif: String| unsafe { chain.sweep_key(wif, destination.to_string(), 1) };
+    assert!(sweep(other.to_wif()).is_err());
+    assert_eq!(chain.wallet.address_set.borrow().len(), watched);
+
+    let reserved = OutPoint::new(coinbase.txid(), 0);
+    let outpoints = format!(r#"["{reserved}"]"#);
+    unsafe { chain.mark_reserved(outpoints, 10) }.unwrap();
+    let result: serde_json::Value = serde_json::from_str(&sweep(key.to_wif()).unwrap()).unwrap();
+    let tx: Transaction =
+        deserialize(&hex::decode(result["tx"].as_str().unwrap()).unwrap()).unwrap();
+    let spent = tx
+        .input
+        .iter()
+        .map(|input| input.previous_output)
+        .collect::<Vec<_>>();
+    assert_eq!(spent, vec![OutPoint::new(coinbase.txid(), 1)]);
+    assert_eq!(chain.wallet.address_set.borrow().len(), watched);
+}
+
+#[test]
+fn build_chain_from_refuses_bad_snapshots() {
+    let header = easy_header(BlockHash::all_zeros(), 1_700_000_000);
+    let header_hex = hex::encode(consensus::serialize(&header));
+    let overrides = Some(format!(r#"{{"pow_limit": "{EASY_POW_LIMIT}"}}"#));
+    let build = |tip: String, height: u32, header: String, overrides: Option<String>| match unsafe {
+        FlorestaChain::build_chain_from(tip, height, header, None, overrides, None)
+    } {
+        Ok(_) => panic!("the snapshot was accepted"),
+        Err(e) => e,
+    };
+    let tip = header.block_hash().to_string();
+
+    let other_tip = genesis_header().block_hash().to_string();
+    let mismatch = build(other_tip, 1000, header_hex.clone(), overrides.clone());
+    assert!(mismatch.starts_with("The header hashes to"), "{mismatch}");
+
+    let malformed = build(tip.clone(), 1000, "zz".into(), overrides.clone());
+    assert_eq!(malformed, "Invalid header hex");
+    let truncated = build(
+        tip.clone(),
+        1000,
+        header_hex[..100].into(),
+        overrides.clone(),
+    );
+    assert!(truncated.starts_with("Invalid header:"), "{truncated}");
+    assert_eq!(
+        build(
+            "not a hash".into(),
+            1000,
+            header_hex.clone(),
+            overrides.clone()
+        ),
+        "Invalid tip hash"
+    );
+
+    // Without the easy pow limit, nBits are above what signet allows
+    let easy_bits = build(tip.clone(), 1000, header_hex.clone(), None);
+    assert!(
+        easy_bits.ends_with("has invalid proof of work"),
+        "{easy_bits}"
+    );
+    // And a header that doesn't meet its own target is refused too
+    let mut unmined = header;
+    unmined.bits = 0x1d00_ffff;
+    let unmined_hex = hex::encode(consensus::serialize(&unmined));
+    let unmined = build(
+        unmined.block_hash().to_string(),
+        1000,
+        unmined_hex,
+        overrides.clone(),
+    );
+    assert!(unmined.ends_with("has invalid proof of work"), "{unmined}");
+
+    assert_eq!(
+        build(tip, 0, header_hex, overrides),
+        "The snapshot can't be at genesis"
+    );
+}
+
+#[test]
+fn chain_errors_have_stable_codes() {
+    let json = |stage, error| {
+        let error = ChainError::json(stage)(error);
+        serde_json::from_str::<serde_json::Value>(&error).unwrap()
+    };
+
+    // A header whose parent we don't know
+    let (chain, _) = snapshot_chain("");
+    let orphan = easy_header(BlockHash::hash(b"unknown"), 1_700_000_000);
+    let error = chain.chain_state.accept_header(orphan).unwrap_err();
+    let orphan = json("accept_header", error);
+    assert_eq!(orphan["stage"], "accept_header");
+    assert_eq!(orphan["code"], "block_not_present");
+    assert!(orphan.get("reason").is_none());
+
+    let bad_pow = json(
+        "accept_header",
+        BlockchainError::BlockValidation(BlockValidationErrors::NotEnoughPow),
+    );
+    assert_eq!(bad_pow["code"], "block_validation");
+    assert_eq!(bad_pow["reason"], "not_enough_pow");
+    assert_eq!(bad_pow["details"], "BlockValidation(NotEnoughPow)");
+
+    let bad_proof = json("connect_block", BlockchainError::InvalidProof);
+    assert_eq!(bad_proof["stage"], "connect_block");
+    assert_eq!(bad_proof["code"], "invalid_proof");
+    assert!(bad_proof.get("reason").is_none());
+}
+
+#[test]
+fn tracking_ignores_blocks_off_the_best_chain() {
+    let secp = Secp256k1::new();
+    let key = PrivateKey::from_slice(&[1; 32], CHAIN_NETWORK).unwrap();
+    let address = Address::p2wpkh(&key.public_key(&secp), CHAIN_NETWORK).unwrap();
+    let (mut chain, tip) = snapshot_chain("");
+    chain.tracking_only = true;
+    unsafe { chain.add_address(address.to_string(), None, None) }.unwrap();
+    let pay = |value| {
+        test_coinbase(
+            1001,
+            vec![TxOut {
+                value,
+                script_pubkey: address.script_pubkey(),
+            }],
+        )
+    };
+
+    let block = test_block(&tip, vec![pay(1)]);
+    unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap();
+    let state = wallet_state(&chain);
+    assert_eq!(state.1.len(), 1);
+
+    // A competing block with the same work doesn't replace ours
+    let fork = test_block(&tip, vec![pay(2)]);
+    unsafe { chain.accept_block(test_block_json(&fork), None) }.unwrap();
+    assert_eq!(wallet_state(&chain), state);
+    assert_eq!(
+        unsafe { chain.return_tip() },
+        block.block_hash().to_string()
+    );
+
+    assert!(unsafe { chain.snapshot_view() }.unwrap().is_tracking_only());
+    let params: serde_json::Value =
+        serde_json::from_str(&unsafe { chain.chain_params() }.unwrap()).unwrap();
+    assert_eq!(params["tracking_only"], true);
+}
+
+#[test]
+fn address_lookups_parse_like_add_address() {
+    let secp = Secp256k1::new();
+    let key = PrivateKey::from_slice(&[1; 32], CHAIN_NETWORK).unwrap();
+    let address = Address::p2wpkh(&key.public_key(&secp), CHAIN_NETWORK)
+        .unwrap()
+        .to_string();
+    let chain = unsafe { FlorestaChain::new() };
+    assert!(unsafe { chain.add_address(format!(" {address}\n"), None, None) }.unwrap());
+
+    for form in [format!("  {address} "), address.to_uppercase()] {
+        unsafe { chain.address_info(form.clone()) }.unwrap();
+        assert_eq!(unsafe { chain.address_first_seen(form.clone()) }, Ok(None));
+        let filter = serde_json::json!({ "address": form }).to_string();
+        unsafe { chain.get_wallet_history(Some(filter)) }.unwrap();
+    }
+
+    let mut mixed = address.clone();
+    mixed.replace_range(..2, "TB");
+    let error = unsafe { chain.address_info(mixed) }.unwrap_err();
+    assert!(error.contains("Mixed-case"), "{error}");
+    let mainnet = Address::p2wpkh(&key.public_key(&secp), bitcoin::Network::Bitcoin)
+        .unwrap()
+        .to_string();
+    let error = unsafe { chain.address_first_seen(mainnet) }.unwrap_err();
+    assert!(error.contains("network bitcoin"), "{error}");
+}
+
+#[test]
+fn uncompressed_keys_can_be_swept() {
+    let secp = Secp256k1::new();
+    let mut key = PrivateKey::from_slice(&[4; 32], CHAIN_NETWORK).unwrap();
+    key.compressed = false;
+    let address = Address::p2pkh(&key.public_key(&secp), CHAIN_NETWORK);
+    let destination = Address::p2pkh(
+        &PrivateKey::from_slice(&[5; 32], CHAIN_NETWORK)
+            .unwrap()
+            .public_key(&secp),
+        CHAIN_NETWORK,
+    );
+    let (mut chain, tip) = snapshot_chain(r#", "coinbase_maturity": 1"#);
+    let watch_types = r#"["p2pkh"]"#.to_string();
+    unsafe { chain.import_privkey(key.to_wif(), watch_types) }.unwrap();
+    let coinbase = test_coinbase(
+        1001,
+        vec![TxOut {
+            value: 100_000,
+            script_pubkey: address.script_pubkey(),
+        }],
+    );
+    let block = test_block(&tip, vec![coinbase.clone()]);
+    unsafe { chain.accept_block(test_block_json(&block), None) }.unwrap();
+
+    let sweep = unsafe { chain.sweep_key(key.to_wif(), destination.to_string(), 1) }.unwrap();
+    let sweep: serde_json::Value = serde_json::from_str(&sweep).unwrap();
+    let tx: Transaction =
+        deserialize(&hex::decode(sweep["tx"].as_str().unwrap()).unwrap()).unwrap();
+    assert_eq!(tx.input.len(), 1);
+    assert_eq!(
+        tx.input[0].previous_output,
+        OutPoint::new(coinbase.txid(), 0)
+    );
+    assert!(!tx.input[0].script_sig.is_empty());
+    assert!(tx.input[0].witness.is_empty());
+}
+
+#[test]
+fn watch_set_matches_by_key() {
+    let mut watched = WatchSet::default();
+    let ours = Script::from(vec![0x00, 0x14, 1, 2, 3]);
+    let theirs = Script::from(vec![0x00, 0x14, 1, 2, 4]);
+    assert!(watched.insert(ours.clone()));
+    assert!(!watched.insert(ours.clone()));
+    assert_eq!(watched.len(), 1);
+
+    let tx = test_tx(
+        vec![],
+        vec![
+            TxOut {
+                value: 1,
+                script_pubkey: theirs.clone(),
+            },
+            TxOut {
+                value: 2,
+                script_pubkey: ours.clone(),
+            },
+        ],
+    );
+    let keys = ScriptKey::outputs(&tx);
+    assert!(!watched.contains_key(&keys[0]));
+    assert!(watched.contains_key(&keys[1]));
+    assert_eq!(watched.iter().collect::<Vec<_>>(), vec![&ours]);
+
+    assert!(!watched.remove(&theirs));
+    assert!(watched.remove(&ours));
+    assert!(!watched.contains(&ours));
+    assert_eq!(watched.len(), 0);
+}
@@ -0,0 +1,444 @@
+// SPDX-License-Identifier: MIT
+
+//! The blocks, proofs and leaf data we take from JS, and their conversions.
+
+use super::*;
+
+#[wasm_bindgen]
+#[derive(Deserialize, Serialize)]
+/// A block and a set of proof. Using this here because we still don't have serde for
+/// UtreexoBlock in my rust-bitcoin fork. We pass this as a stringified json object
+pub struct WasmBlock {
+    pub(crate) block: Block,
+    pub(crate) proof: JsonProof,
+    pub(crate) leaf_data: Vec<CompLeafData>,
+}
+
+/// The biggest block JSON we are willing to parse. A full 4MB block with its proof and leaf
+/// data fits comfortably in this
+pub(crate) const MAX_BLOCK_JSON_SIZE: usize = 32 * 1024 * 1024;
+/// Consensus limit for block weight
+pub(crate) const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+/// The smallest possible transaction is 60 bytes without witness, weighting 240
+pub(crate) const MIN_TRANSACTION_WEIGHT: usize = 60 * 4;
+
+/// No output may hold more than 21 million bitcoins
+pub(crate) const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+/// Consensus limit for the size of a script
+pub(crate) const MAX_SCRIPT_SIZE: usize = 10_000;
+
+impl WasmBlock {
+    /// Whether the coinbase has a BIP141 witness commitment output
+    pub(crate) fn has_witness_commitment(&self) -> bool {
+        const MAGIC: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        self.block.txdata.first().map_or(false, |coinbase| {
+            coinbase.output.iter().any(|output| {
+                let script = output.script_pubkey.as_bytes();
+                script.len() >= 38 && script[..6] == MAGIC
+            })
+        })
+    }
+    /// Sanity checks on the decoded block, so malformed or absurd inputs are rejected before
+    /// we start walking through them
+    pub(crate) fn validate(&self) -> Result<(), BlockValidationError> {
+        // A corrupted body would otherwise only be noticed deep inside connect_block
+        if !self.block.check_merkle_root() {
+            return Err(BlockValidationError::MerkleRootMismatch);
+        }
+        // rust-bitcoin skips the check if no input has a witness, but a block committing to
+        // witnesses must have them, or stripping every witness would go unnoticed
+        let witnessless = self
+            .block
+            .txdata
+            .iter()
+            .all(|tx| tx.input.iter().all(|input| input.witness.is_empty()));
+        if !self.block.check_witness_commitment() || (witnessless && self.has_witness_commitment())
+        {
+            return Err(BlockValidationError::WitnessCommitmentMismatch);
+        }
+        let transactions = self.block.txdata.len();
+        if transactions > MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT {
+            return Err(BlockValidationError::TooManyTransactions(transactions));
+        }
+        let inputs = self
+            .block
+            .txdata
+            .iter()
+            .map(|tx| tx.input.len())
+            .sum::<usize>();
+        let leaves = self.leaf_data.len();
+        if leaves > inputs {
+            return Err(BlockValidationError::TooManyLeaves { leaves, inputs });
+        }
+        let targets = self.proof.targets.len();
+        if targets > leaves {
+            return Err(BlockValidationError::TooManyTargets { targets, leaves });
+        }
+        for hash in self.proof.hashes.iter() {
+            if hash.len() != 64 || sha256::Hash::from_str(hash).is_err() {
+                return Err(BlockValidationError::InvalidProofHash(hash.clone()));
+            }
+        }
+        for output in self.block.txdata.iter().flat_map(|tx| tx.output.iter()) {
+            if output.value > MAX_MONEY {
+                return Err(BlockValidationError::AmountTooBig(output.value));
+            }
+        }
+        for leaf in self.leaf_data.iter() {
+            if leaf.amount > MAX_MONEY {
+                return Err(BlockValidationError::AmountTooBig(leaf.amount));
+            }
+            if let ScriptPubkeyType::Other(script) = &leaf.spk_ty {
+                if script.len() > MAX_SCRIPT_SIZE {
+                    return Err(BlockValidationError::ScriptTooBig(script.len()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct CompLeafData {
+    /// Header code tells the height of creating for this UTXO and whether it's a coinbase
+    pub header_code: u32,
+    /// The amount locked in this UTXO
+    pub amount: u64,
+    /// The type of the locking script for this UTXO
+    pub spk_ty: ScriptPubkeyType,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub enum ScriptPubkeyType {
+    /// An non-specified type, in this case the script is just copied over
+    Other(Box<[u8]>),
+    /// p2pkh
+    PubKeyHash,
+    /// p2wsh
+    WitnessV0PubKeyHash,
+    /// p2sh
+    ScriptHash,
+    /// p2wsh
+    WitnessV0ScriptHash,
+}
+
+#[derive(Debug, Serialize)]
+/// What `utxo_leaf_data` returns
+pub(crate) struct UtxoLeafData {
+    #[serde(flatten)]
+    pub(crate) leaf: CompLeafData,
+    /// The block that created this coin, part of the leaf preimage
+    pub(crate) block_hash: BlockHash,
+    /// Where `block_hash` came from: `store`, or `hash_table` below our snapshot
+    pub(crate) hash_source: &'static str,
+    pub(crate) script_pubkey: String,
+    pub(crate) leaf_hash: sha256::Hash,
+}
+
+/// Bumped whenever the format returned by `export_utxos_psbt_ready` changes
+pub(crate) const UTXO_EXPORT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize)]
+/// What `export_utxos_psbt_ready` returns
+pub(crate) struct UtxoExport {
+    pub(crate) version: u32,
+    pub(crate) height: u32,
+    pub(crate) utxos: Vec<ExportedUtxo>,
+}
+
+#[derive(Debug, Serialize)]
+/// One of the coins in a `UtxoExport`
+pub(crate) struct ExportedUtxo {
+    pub(crate) outpoint: OutPoint,
+    pub(crate) amount: u64,
+    pub(crate) script_pubkey: String,
+    pub(crate) confirmations: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) derivation: Option<UtxoDerivation>,
+    pub(crate) leaf: UtxoLeafData,
+}
+
+#[derive(Debug, Serialize)]
+/// Where the keys of a coin paying to one of our multisig descriptors come from
+pub(crate) struct UtxoDerivation {
+    pub(crate) descriptor: u32,
+    pub(crate) index: u32,
+    pub(crate) witness_script: String,
+    pub(crate) keys: Vec<KeyDerivation>,
+}
+
+#[derive(Debug, Serialize)]
+/// A key in a multisig script, derived from a cosigner's xpub following `path`
+pub(crate) struct KeyDerivation {
+    pub(crate) pubkey: PublicKey,
+    pub(crate) xpub: ExtendedPubKey,
+    pub(crate) path: String,
+}
+
+/// A block whose header was accepted and whose spent coins were reconstructed, waiting to
+/// have its proof verified and be connected
+pub(crate) struct PreparedBlock {
+    pub(crate) block: Block,
+    pub(crate) proof: Proof,
+    pub(crate) inputs: HashMap<OutPoint, TxOut>,
+    pub(crate) del_hashes: Vec<sha256::Hash>,
+}
+
+#[derive(Deserialize)]
+/// An entry given to `verify_proofs_batch`
+pub(crate) struct ProofBatchEntry {
+    pub(crate) del_hashes: Vec<String>,
+    pub(crate) proof: JsonProof,
+}
+
+#[derive(Debug, Default, Serialize)]
+/// The result of verifying one entry in `verify_proofs_batch`
+pub(crate) struct ProofBatchResult {
+    pub(crate) valid: bool,
+    pub(crate) error: Option<String>,
+}
+
+impl ProofBatchEntry {
+    /// Checks this proof against `stump`, errors are for malformed entries
+    pub(crate) fn verify(
+        mut self,
+        stump: &rustreexo::accumulator::stump::Stump,
+    ) -> Result<bool, String> {
+        let mut del_hashes = self
+            .del_hashes
+            .iter()
+            .map(|hash| sha256::Hash::from_str(hash).map_err(|_| format!("Invalid hash {hash}")))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(hash) = self
+            .proof
+            .hashes
+            .iter()
+            .find(|hash| sha256::Hash::from_str(hash).is_err())
+        {
+            return Err(format!("Invalid hash {hash}"));
+        }
+        self.proof.normalize(stump.leaves, &mut del_hashes)?;
+        Proof::from(self.proof).verify(&del_hashes, stump)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub(crate) struct JsonProof {
+    pub(crate) targets: Vec<u64>,
+    pub(crate) hashes: Vec<String>,
+}
+
+impl JsonProof {
+    /// Sorts the targets, keeping each deleted hash with its target, and makes sure none is
+    /// repeated or past the last of our `leaves`. `del_hashes` must be in the same order as
+    /// the targets. Every proof we get goes through this before being verified, so bad
+    /// proofs get a clear error instead of whatever the accumulator makes of them.
+    pub(crate) fn normalize(
+        &mut self,
+        leaves: u64,
+        del_hashes: &mut Vec<sha256::Hash>,
+    ) -> Result<(), String> {
+        if let Some(target) = self.targets.iter().find(|target| **target >= leaves) {
+            return Err(format!(
+                "Proof target {target} is past the last leaf, we have {leaves}"
+            ));
+        }
+        if del_hashes.len() == self.targets.len() {
+            let mut pairs = self
+                .targets
+                .iter()
+                .copied()
+                .zip(del_hashes.drain(..))
+                .collect::<Vec<_>>();
+            pairs.sort_by_key(|(target, _)| *target);
+            (self.targets, *del_hashes) = pairs.into_iter().unzip();
+        } else {
+            self.targets.sort_unstable();
+        }
+        if let Some(pair) = self.targets.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(format!("Proof target {} appears more than once", pair[0]));
+        }
+        Ok(())
+    }
+}
+
+impl From<JsonProof> for Proof {
+    fn from(json_proof: JsonProof) -> Self {
+        let mut targets = Vec::new();
+        let mut hashes = Vec::new();
+        for target in json_proof.targets {
+            targets.push(target);
+        }
+        for hash in json_proof.hashes {
+            hashes.push(hash.parse().unwrap());
+        }
+        Proof { targets, hashes }
+    }
+}
+
+/// The inputs of a block that spend a coin from a previous block, and so consume one leaf of
+/// the block's leaf data each, in order. Returns the txid and index of each one.
+pub(crate) fn leaf_spending_inputs(transactions: &[Transaction]) -> Vec<(Txid, usize, &TxIn)> {
+    let mut known = HashSet::new();
+    let mut spending = Vec::new();
+    // Skip the coinbase
+    for tx in transactions.iter().skip(1) {
+        let txid = tx.txid();
+        for vout in 0..tx.output.len() {
+            known.insert(OutPoint {
+                txid,
+                vout: vout as u32,
+            });
+        }
+        for (vin, input) in tx.input.iter().enumerate() {
+            if known.insert(input.previous_output) {
+                spending.push((txid, vin, input));
+            }
+        }
+    }
+    spending
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+/// How the spent script of a leaf is recovered from the input spending it
+pub(crate) enum LeafReconstruction {
+    /// The script is rebuilt from the public key or script the input reveals
+    Derived,
+    /// The leaf carries the whole script, we copy it without checking it against the input
+    Copied,
+    /// The input doesn't have what we need to rebuild the script
+    Impossible { reason: String },
+}
+
+impl ScriptPubkeyType {
+    /// The type a bridge would give a leaf locked by `script`
+    pub(crate) fn from_script(script: &Script) -> Self {
+        if script.is_p2pkh() {
+            ScriptPubkeyType::PubKeyHash
+        } else if script.is_v0_p2wpkh() {
+            ScriptPubkeyType::WitnessV0PubKeyHash
+        } else if script.is_p2sh() {
+            ScriptPubkeyType::ScriptHash
+        } else if script.is_v0_p2wsh() {
+            ScriptPubkeyType::WitnessV0ScriptHash
+        } else {
+            ScriptPubkeyType::Other(script.as_bytes().into())
+        }
+    }
+    /// Tells whether the script spent by `input` can be rebuilt for this leaf type
+    pub(crate) fn reconstruction(&self, input: &TxIn) -> LeafReconstruction {
+        let last_push = input
+            .script_sig
+            .instructions()
+            .filter_map(|instruction| match instruction {
+                Ok(Instruction::PushBytes(bytes)) => Some(bytes.len()),
+                _ => None,
+            })
+            .last();
+        let impossible = |reason: &str| LeafReconstruction::Impossible {
+            reason: reason.to_string(),
+        };
+        match self {
+            ScriptPubkeyType::Other(_) => LeafReconstruction::Copied,
+            ScriptPubkeyType::PubKeyHash => match last_push {
+                Some(33) | Some(65) => LeafReconstruction::Derived,
+                _ => impossible("scriptSig doesn't end with a public key"),
+            },
+            ScriptPubkeyType::ScriptHash => match last_push {
+                Some(len) if len > 0 => LeafReconstruction::Derived,
+                _ => impossible("scriptSig doesn't end with a redeem script"),
+            },
+            ScriptPubkeyType::WitnessV0PubKeyHash => match input.witness.last() {
+                Some(key) if input.witness.len() == 2 && key.len() == 33 => {
+                    LeafReconstruction::Derived
+                }
+                _ => impossible("witness doesn't have a signature and a public key"),
+            },
+            ScriptPubkeyType::WitnessV0ScriptHash => match input.witness.last() {
+                Some(script) if !script.is_empty() => LeafReconstruction::Derived,
+                _ => impossible("witness doesn't end with a witness script"),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// An input whose spent script can't be derived, returned by `check_leaf_reconstruction`
+pub(crate) struct UnprovableInput {
+    pub(crate) txid: Txid,
+    pub(crate) vin: usize,
+    #[serde(flatten)]
+    pub(crate) reconstruction: LeafReconstruction,
+}
+
+impl From<CompLeafData> for CompactLeafData {
+    fn from(leaf: CompLeafData) -> Self {
+        let spk_ty: bitcoin::network::utreexo::ScriptPubkeyType = match leaf.spk_ty {
+            ScriptPubkeyType::Other(script) => {
+                bitcoin::network::utreexo::ScriptPubkeyType::Other(script)
+            }
+            ScriptPubkeyType::PubKeyHash => bitcoin::network::utreexo::ScriptPubkeyType::PubKeyHash,
+            ScriptPubkeyType::WitnessV0PubKeyHash => {
+                bitcoin::network::utreexo::ScriptPubkeyType::WitnessV0PubKeyHash
+            }
+            ScriptPubkeyType::ScriptHash => bitcoin::network::utreexo::ScriptPubkeyType::ScriptHash,
+            ScriptPubkeyType::WitnessV0ScriptHash => {
+                bitcoin::network::utreexo::ScriptPubkeyType::WitnessV0ScriptHash
+            }
+        };
+
+        Self {
+            header_code: leaf.header_code,
+            amount: leaf.amount,
+            spk_ty,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::network::utreexo::ScriptPubkeyType as BridgeType;
+
+    fn leaf(script: &Script) -> CompactLeafData {
+        CompLeafData {
+            header_code: 5,
+            amount: 10,
+            spk_ty: ScriptPubkeyType::from_script(script),
+        }
+        .into()
+    }
+
+    #[test]
+    fn leaf_data_converts_to_the_bridge_types() {
+        let p2wpkh = Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::hash(&[1]));
+        let converted = leaf(&p2wpkh);
+        assert_eq!((converted.header_code, converted.amount), (5, 10));
+        assert!(matches!(converted.spk_ty, BridgeType::WitnessV0PubKeyHash));
+
+        // Anything without a type of its own keeps the whole script
+        let op_return = Script::new_op_return(&[1, 2]);
+        assert!(matches!(
+            leaf(&op_return).spk_ty,
+            BridgeType::Other(script) if script[..] == op_return.as_bytes()[..]
+        ));
+    }
+
+    #[test]
+    fn proofs_parse_from_json() {
+        let hash = sha256::Hash::hash(&[1]);
+        let json = format!(r#"{{"targets": [3, 1], "hashes": ["{hash}"]}}"#);
+        let proof = Proof::from(serde_json::from_str::<JsonProof>(&json).unwrap());
+        assert_eq!(proof.targets, vec![3, 1]);
+        assert_eq!(
+            proof
+                .hashes
+                .iter()
+                .map(|hash| hash.to_string())
+                .collect::<Vec<_>>(),
+            vec![hash.to_string()]
+        );
+        assert!(serde_json::from_str::<JsonProof>(r#"{"targets": [-1], "hashes": []}"#).is_err());
+    }
+}
@@ -0,0 +1,2356 @@
+// SPDX-License-Identifier: MIT
+
+//! The wallet: what we watch, and what we found in blocks for it.
+
+use super::*;
+
+/// Identifies a watched script by the sha256 of its bytes, so matching a block hashes each
+/// output script once, and lookups only compare 32 bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ScriptKey(pub(crate) sha256::Hash);
+
+impl ScriptKey {
+    pub(crate) fn new(script: &Script) -> Self {
+        ScriptKey(sha256::Hash::hash(script.as_bytes()))
+    }
+    /// The keys for every output of `tx`, in order
+    pub(crate) fn outputs(tx: &Transaction) -> Vec<Self> {
+        tx.output
+            .iter()
+            .map(|output| Self::new(&output.script_pubkey))
+            .collect()
+    }
+}
+
+impl std::hash::Hash for ScriptKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let mut prefix = [0; 8];
+        prefix.copy_from_slice(&self.0[..8]);
+        state.write_u64(u64::from_le_bytes(prefix));
+    }
+}
+
+/// The keys are already uniformly distributed, so they are used as they are instead of
+/// being hashed again
+#[derive(Default)]
+pub(crate) struct ScriptKeyHasher(pub(crate) u64);
+
+impl Hasher for ScriptKeyHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+    fn write_u64(&mut self, value: u64) {
+        self.0 = value;
+    }
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = self.0.rotate_left(8) ^ *byte as u64;
+        }
+    }
+}
+
+/// The scripts we watch, keyed by `ScriptKey`. The map goes back from each key to its
+/// script, for anything that needs to list or report them.
+#[derive(Default, Debug, Clone)]
+pub(crate) struct WatchSet {
+    pub(crate) scripts: HashMap<ScriptKey, Script, BuildHasherDefault<ScriptKeyHasher>>,
+}
+
+impl WatchSet {
+    pub(crate) fn contains(&self, script: &Script) -> bool {
+        self.contains_key(&ScriptKey::new(script))
+    }
+    pub(crate) fn contains_key(&self, key: &ScriptKey) -> bool {
+        self.scripts.contains_key(key)
+    }
+    /// Returns false if we were already watching it
+    pub(crate) fn insert(&mut self, script: Script) -> bool {
+        match self.scripts.entry(ScriptKey::new(&script)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(script);
+                true
+            }
+        }
+    }
+    pub(crate) fn remove(&mut self, script: &Script) -> bool {
+        self.scripts.remove(&ScriptKey::new(script)).is_some()
+    }
+    pub(crate) fn len(&self) -> usize {
+        self.scripts.len()
+    }
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Script> {
+        self.scripts.values()
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Default, Debug, Clone)]
+pub struct Wallet {
+    pub(crate) address_set: RefCell<WatchSet>,
+    /// Outputs paying to one of our scripts that weren't spent yet
+    pub(crate) utxos: RefCell<HashMap<OutPoint, TxOut>>,
+    /// Taproot outputs we know the script tree for, keyed by their script_pubkey
+    pub(crate) taproot_outputs: RefCell<HashMap<Script, TaprootWatch>>,
+    /// The multisig descriptors we derive scripts from, the position is the descriptor id
+    pub(crate) descriptors: RefCell<Vec<MultisigDescriptor>>,
+    /// Every p2wsh script derived from one of our descriptors
+    pub(crate) multisig_scripts: RefCell<HashMap<Script, DerivedMultisig>>,
+    /// Our confirmed transactions in chain order, keyed by height and position in the block
+    pub(crate) history: RefCell<BTreeMap<(u32, u32), HistoryEntry>>,
+    /// Where each transaction is in `history`
+    pub(crate) tx_index: RefCell<HashMap<Txid, (u32, u32)>>,
+    /// Unconfirmed transactions handed to us by the application
+    pub(crate) pending: RefCell<HashMap<Txid, PendingTx>>,
+    pub(crate) stats: RefCell<WalletStats>,
+    /// The first height each of our scripts received something at
+    pub(crate) first_seen: RefCell<HashMap<Script, u32>>,
+    /// The height each script was created at, if the application told us
+    pub(crate) created_at: RefCell<HashMap<Script, u32>>,
+    /// Where rescans start for scripts without a creation height
+    pub(crate) birthday: RefCell<u32>,
+    /// How many scripts we may watch, None means unlimited
+    pub(crate) max_watched_scripts: RefCell<Option<usize>>,
+    /// Which confirmed transaction spent each of our coins
+    pub(crate) spent_by: RefCell<HashMap<OutPoint, Txid>>,
+    /// Scripts restored from a snapshot, and the height it was taken at. Blocks up to that
+    /// height never need to be rescanned for them
+    pub(crate) synced_to: RefCell<HashMap<Script, u32>>,
+    /// Transactions with OP_RETURN outputs starting with any of those are recorded, even if
+    /// they don't touch our addresses
+    pub(crate) op_return_prefixes: RefCell<Vec<Vec<u8>>>,
+    /// OP_RETURN payloads of our transactions, and of the ones matching a watched prefix
+    pub(crate) op_returns: RefCell<HashMap<Txid, OpReturnRecord>>,
+    /// Private keys imported with `import_privkey`, keyed by the scripts they can spend.
+    /// Those are only kept in memory, and must never be serialized in plaintext
+    pub(crate) keys: RefCell<HashMap<Script, PrivateKey>>,
+    /// Our transactions that were in a block that got reorganized out, and that block's hash
+    pub(crate) reorged: RefCell<HashMap<Txid, BlockHash>>,
+    /// Which raw transactions we keep in `history`
+    pub(crate) tx_retention: RefCell<TxRetention>,
+    /// Labels given by the user or imported from other wallets, see `set_label`
+    pub(crate) labels: RefCell<BTreeMap<(LabelKind, String), Bip329Label>>,
+    /// A JS function deciding whether outputs not paying to our scripts are ours
+    pub(crate) script_classifier: RefCell<Option<ScriptClassifier>>,
+    /// Roughly how much memory the wallet is using, kept up to date as things are added
+    /// and removed
+    pub(crate) size: RefCell<WalletSize>,
+}
+
+/// After failing this many times in a row, the script classifier is dropped
+pub(crate) const MAX_CLASSIFIER_FAILURES: u32 = 10;
+
+#[derive(Debug, Clone)]
+/// See `set_script_classifier`
+pub(crate) struct ScriptClassifier {
+    pub(crate) callback: js_sys::Function,
+    /// How many calls in a row threw
+    pub(crate) failures: u32,
+}
+
+impl ScriptClassifier {
+    /// Asks the callback whether `output` is ours. If it throws, we take it as a no.
+    pub(crate) fn is_ours(&mut self, txid: Txid, vout: u32, output: &TxOut) -> bool {
+        let args = js_sys::Array::of4(
+            &txid.to_string().into(),
+            &vout.into(),
+            &hex::encode(output.script_pubkey.as_bytes()).into(),
+            &(output.value as f64).into(),
+        );
+        match self.callback.apply(&wasm_bindgen::JsValue::NULL, &args) {
+            Ok(result) => {
+                self.failures = 0;
+                result.is_truthy()
+            }
+            Err(e) => {
+                self.failures += 1;
+                log(format!("Script classifier failed: {e:?}"));
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+/// Estimated size, in bytes, of the biggest parts of the wallet
+pub(crate) struct WalletSize {
+    pub(crate) scripts: usize,
+    pub(crate) transactions: usize,
+    pub(crate) utxos: usize,
+}
+
+/// Serialized size of an outpoint, the key of our utxo map
+pub(crate) const OUTPOINT_SIZE: usize = 36;
+/// Rough size of a cached header entry: the hash, the header and its height and status
+pub(crate) const CACHED_HEADER_SIZE: usize = 32 + 80 + 8;
+
+pub(crate) fn utxo_size(out: &TxOut) -> usize {
+    OUTPOINT_SIZE + 8 + out.script_pubkey.len()
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+/// Estimated size, in bytes, of everything we keep around
+pub(crate) struct StateSize {
+    pub(crate) store: usize,
+    pub(crate) wallet: WalletSize,
+    pub(crate) header_cache: usize,
+    pub(crate) total: usize,
+    /// See `set_memory_budget`
+    pub(crate) budget: usize,
+    /// How many entries the event buffers hold, see `set_event_retention`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) events: Option<EventOccupancy>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+/// How much work it took to filter a block for our wallet
+pub(crate) struct FilterCost {
+    pub(crate) lookups: u64,
+    pub(crate) bytes: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+/// The outcome for each entry given to `add_addresses`
+pub(crate) enum AddressImportResult {
+    Ok,
+    Duplicate,
+    Error { error: String },
+}
+
+#[derive(Debug, Serialize)]
+/// The outcome for each descriptor given to `import_core_descriptors`, or each address found
+/// by `import_core_dump`
+pub(crate) struct CoreImportResult {
+    /// The descriptor or address, as given
+    pub(crate) entry: String,
+    #[serde(flatten)]
+    pub(crate) status: AddressImportResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) descriptor_id: Option<u32>,
+    /// The height rescans for this entry start at, from its timestamp
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) created_at: Option<u32>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) key_imported: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+/// Bitcoin Core's `listdescriptors` output, or just its `descriptors` array
+pub(crate) enum CoreDescriptors {
+    Wallet { descriptors: Vec<CoreDescriptor> },
+    List(Vec<CoreDescriptor>),
+}
+
+#[derive(Deserialize)]
+/// A descriptor as listed by Bitcoin Core. `active` and `internal` are accepted but not used,
+/// we watch every descriptor the same way
+pub(crate) struct CoreDescriptor {
+    pub(crate) desc: String,
+    /// Unix time, or "now"
+    pub(crate) timestamp: Option<serde_json::Value>,
+    /// Either the end of the range, or `[start, end]`
+    pub(crate) range: Option<serde_json::Value>,
+}
+
+/// Bitcoin Core rescans from this many seconds before a key's timestamp, since block times
+/// may be off by that much
+pub(crate) const TIMESTAMP_WINDOW: u64 = 2 * 60 * 60;
+
+/// Parses the `YYYY-MM-DDTHH:MM:SSZ` times used by `dumpwallet` into unix time
+pub(crate) fn parse_iso8601(time: &str) -> Option<u64> {
+    let (date, time) = time.strip_suffix('Z')?.split_once('T')?;
+    let mut date = date.split('-').map(|part| part.parse::<i64>().ok());
+    let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+    let mut time = time.split(':').map(|part| part.parse::<i64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    // Days since the epoch, from Howard Hinnant's days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+/// Decodes the %XX escapes Bitcoin Core uses for labels in `dumpwallet`
+pub(crate) fn percent_decode(text: &str) -> String {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (byte, escaped) {
+            (b'%', Some(escaped)) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Hashes two merkle tree nodes into their parent
+pub(crate) fn merkle_parent(left: &TxMerkleNode, right: &TxMerkleNode) -> TxMerkleNode {
+    let mut engine = TxMerkleNode::engine();
+    engine.input(&left[..]);
+    engine.input(&right[..]);
+    TxMerkleNode::from_engine(engine)
+}
+
+/// The siblings needed to go from the leaf at `position` up to the merkle root
+pub(crate) fn merkle_branch(leaves: &[TxMerkleNode], mut position: usize) -> Vec<TxMerkleNode> {
+    let mut branch = Vec::new();
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        // If a level has an odd number of nodes, the last one is paired with itself
+        branch.push(*level.get(position ^ 1).unwrap_or(&level[position]));
+        level = level
+            .chunks(2)
+            .map(|pair| merkle_parent(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        position /= 2;
+    }
+    branch
+}
+
+/// Computes the merkle root from a leaf at `position` and its branch
+pub(crate) fn merkle_root_from_branch(
+    leaf: TxMerkleNode,
+    branch: &[TxMerkleNode],
+    mut position: u32,
+) -> TxMerkleNode {
+    let mut node = leaf;
+    for sibling in branch {
+        node = if position & 1 == 0 {
+            merkle_parent(&node, sibling)
+        } else {
+            merkle_parent(sibling, &node)
+        };
+        position >>= 1;
+    }
+    node
+}
+
+/// The current version of the SPV proof bundle format
+pub(crate) const SPV_PROOF_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+/// A self contained proof that a transaction was confirmed, see `export_spv_proof`
+pub(crate) struct SpvProof {
+    pub(crate) version: u32,
+    pub(crate) network: String,
+    /// The transaction, in hex
+    pub(crate) tx: String,
+    pub(crate) height: u32,
+    /// Position of the transaction in its block
+    pub(crate) position: u32,
+    pub(crate) branch: Vec<TxMerkleNode>,
+    /// The 80 byte header of the block confirming the transaction, in hex
+    pub(crate) header: String,
+    /// Headers built on top of that block, in hex and in chain order
+    pub(crate) headers: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+/// What `verify_spv_proof` returns for a valid proof
+pub(crate) struct SpvProofResult {
+    pub(crate) txid: Txid,
+    pub(crate) block_hash: BlockHash,
+    /// Only set when the proof reaches the trusted anchor, otherwise the height in the
+    /// proof is just a claim
+    pub(crate) height: Option<u32>,
+    /// How many valid headers, starting at the transaction's block, the proof has
+    pub(crate) confirmations: u32,
+    /// The work done on those headers, in hex
+    pub(crate) work: String,
+}
+
+#[derive(Debug, Deserialize)]
+/// A block the verifier of an SPV proof already trusts, see `verify_spv_proof`
+pub(crate) struct SpvAnchor {
+    pub(crate) hash: BlockHash,
+    pub(crate) height: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What a label is attached to, as defined by BIP329
+pub(crate) enum LabelKind {
+    Tx,
+    Addr,
+    Pubkey,
+    Input,
+    Output,
+    Xpub,
+}
+
+impl LabelKind {
+    /// Puts a reference in the form we index it by, so the same thing written in different
+    /// ways gets the same label
+    pub(crate) fn normalize(self, reference: &str) -> Result<String, String> {
+        let reference = reference.trim();
+        match self {
+            LabelKind::Tx => Txid::from_str(reference)
+                .map(|txid| txid.to_string())
+                .map_err(|_| format!("Invalid txid {reference}")),
+            LabelKind::Input | LabelKind::Output => OutPoint::from_str(reference)
+                .map(|outpoint| outpoint.to_string())
+                .map_err(|_| format!("Invalid outpoint {reference}")),
+            LabelKind::Addr | LabelKind::Pubkey | LabelKind::Xpub => Ok(reference.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A line of a BIP329 label export
+pub(crate) struct Bip329Label {
+    #[serde(rename = "type")]
+    pub(crate) kind: LabelKind,
+    #[serde(rename = "ref")]
+    pub(crate) reference: String,
+    pub(crate) label: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) origin: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) spendable: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+/// The OP_RETURN payloads of a confirmed transaction
+pub(crate) struct OpReturnRecord {
+    pub(crate) height: u32,
+    pub(crate) payloads: Vec<Vec<u8>>,
+}
+
+/// Returns the data pushed by every OP_RETURN output of `tx`, in output order. Outputs
+/// bigger than the maximum script size are ignored.
+pub(crate) fn op_return_payloads(tx: &Transaction) -> Vec<Vec<u8>> {
+    tx.output
+        .iter()
+        .filter(|out| out.script_pubkey.is_op_return())
+        .filter(|out| out.script_pubkey.len() <= MAX_SCRIPT_SIZE)
+        .map(|out| {
+            out.script_pubkey
+                .instructions()
+                .skip(1)
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(bytes)) => Some(bytes.to_vec()),
+                    _ => None,
+                })
+                .flatten()
+                .collect()
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+/// A coin we hold the private key for, returned by `list_spendable`
+pub(crate) struct SpendableUtxo {
+    pub(crate) outpoint: OutPoint,
+    pub(crate) value: u64,
+    pub(crate) address: String,
+    pub(crate) asm: String,
+    /// The label of this output, or of its address if the output has none
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+/// What we know about one of our addresses
+pub(crate) struct AddressInfo {
+    pub(crate) address: String,
+    pub(crate) script_pubkey: String,
+    /// The first height this address received something at, if ever
+    pub(crate) first_seen: Option<u32>,
+    /// The creation height hint given when this address was added
+    pub(crate) created_at: Option<u32>,
+    /// How many times this address received something
+    pub(crate) receive_count: u32,
+}
+
+#[derive(Debug, Default, Clone)]
+/// Aggregated numbers about our wallet. Those are updated as blocks get connected or
+/// disconnected, so we never need to walk the whole history to get them.
+pub(crate) struct WalletStats {
+    pub(crate) total_received: u64,
+    pub(crate) total_sent: u64,
+    /// Fees paid by transactions spending our coins
+    pub(crate) total_fees: u64,
+    pub(crate) tx_count: usize,
+    /// How many times each of our scripts received something
+    pub(crate) receive_counts: HashMap<Script, u32>,
+    pub(crate) first_activity: Option<u32>,
+    pub(crate) last_activity: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+/// What `wallet_stats` returns
+pub(crate) struct WalletStatsJson {
+    pub(crate) total_received: u64,
+    pub(crate) total_sent: u64,
+    /// What we can spend now, without coinbase outputs that didn't mature yet
+    pub(crate) balance: u64,
+    pub(crate) immature_balance: u64,
+    pub(crate) total_fees: u64,
+    pub(crate) tx_count: usize,
+    pub(crate) utxo_count: usize,
+    pub(crate) used_addresses: usize,
+    pub(crate) unused_addresses: usize,
+    pub(crate) first_activity: Option<u32>,
+    pub(crate) last_activity: Option<u32>,
+}
+
+impl WalletStats {
+    pub(crate) fn add(&mut self, entry: &HistoryEntry, received_scripts: &[Script]) {
+        self.total_received += entry.received;
+        self.total_sent += entry.sent;
+        if entry.sent > 0 {
+            self.total_fees += entry.fee.unwrap_or(0);
+        }
+        self.tx_count += 1;
+        for script in received_scripts {
+            *self.receive_counts.entry(script.clone()).or_default() += 1;
+        }
+        // Rescans may add entries below the ones we already have
+        self.first_activity = self
+            .first_activity
+            .min(Some(entry.height))
+            .or(Some(entry.height));
+        self.last_activity = self.last_activity.max(Some(entry.height));
+    }
+    /// Reverts `add` for an entry that got reorged out. `first` and `last` are the remaining
+    /// history entries after removing this one.
+    pub(crate) fn remove(
+        &mut self,
+        entry: &HistoryEntry,
+        received_scripts: &[Script],
+        first: Option<&HistoryEntry>,
+        last: Option<&HistoryEntry>,
+    ) {
+        self.total_received -= entry.received;
+        self.total_sent -= entry.sent;
+        if entry.sent > 0 {
+            self.total_fees -= entry.fee.unwrap_or(0);
+        }
+        self.tx_count -= 1;
+        for script in received_scripts {
+            if let Some(count) = self.receive_counts.get_mut(script) {
+                *count -= 1;
+                if *count == 0 {
+                    self.receive_counts.remove(script);
+                }
+            }
+        }
+        self.first_activity = first.map(|entry| entry.height);
+        self.last_activity = last.map(|entry| entry.height);
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A transaction that touches our wallet but isn't confirmed yet
+pub(crate) struct PendingTx {
+    pub(crate) tx: Transaction,
+    /// The fee paid by this transaction, if we know the value of all its inputs
+    pub(crate) fee: Option<u64>,
+}
+
+/// How many txids `tx_statuses` takes at once
+pub(crate) const MAX_TX_STATUSES: usize = 500;
+
+#[derive(Debug, Serialize)]
+/// The state of a transaction, see `tx_statuses`
+pub(crate) struct TxStatusEntry {
+    /// The txid as given to us
+    pub(crate) txid: String,
+    #[serde(flatten)]
+    pub(crate) status: TxStatus,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub(crate) enum TxStatus {
+    /// Not a txid at all
+    Invalid,
+    Unknown,
+    Pending,
+    /// Pending, but spends a coin some confirmed transaction already spent
+    Conflicted,
+    Confirmed {
+        height: u32,
+        confirmations: u32,
+    },
+}
+
+#[derive(Debug, Serialize)]
+/// What `sweep_key` returns
+pub(crate) struct SweepResult {
+    /// The signed transaction, in hex
+    pub(crate) tx: String,
+    pub(crate) amount: u64,
+    pub(crate) fee: u64,
+    pub(crate) vsize: u64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+/// The sizes of a transaction, as returned by `tx_size_info`. Everything that shows a size
+/// or fee rate uses this, so numbers always agree.
+pub(crate) struct TxSizeInfo {
+    pub(crate) size: u64,
+    /// The size without witness data
+    pub(crate) stripped_size: u64,
+    pub(crate) weight: u64,
+    /// Weight divided by 4, rounded up
+    pub(crate) vsize: u64,
+}
+
+impl TxSizeInfo {
+    pub(crate) fn new(tx: &Transaction) -> Self {
+        let weight = tx.weight() as u64;
+        TxSizeInfo {
+            size: tx.size() as u64,
+            stripped_size: tx.strippedsize() as u64,
+            weight,
+            vsize: (weight + 3) / 4,
+        }
+    }
+    /// The fee rate in sat/vB for a transaction paying `fee`
+    pub(crate) fn fee_rate(&self, fee: u64) -> f64 {
+        fee as f64 / self.vsize as f64
+    }
+}
+
+/// Estimates the virtual size `tx` will have after signing, given the outputs it spends.
+/// We assume 72 byte signatures and compressed keys, so this may be a few bytes above the
+/// real size, never below.
+pub(crate) fn estimate_vsize<'a>(
+    tx: &Transaction,
+    prevouts: impl Iterator<Item = &'a TxOut>,
+) -> u64 {
+    let outputs = tx
+        .output
+        .iter()
+        .map(|out| 8 + 1 + out.script_pubkey.len() as u64)
+        .sum::<u64>();
+    let mut segwit = false;
+    let inputs = prevouts
+        .map(|prevout| {
+            segwit |= !prevout.script_pubkey.is_p2pkh();
+            input_vsize(&prevout.script_pubkey)
+        })
+        .sum::<u64>();
+    // Version, locktime and input/output counts, plus the segwit marker and flag
+    let overhead = if segwit { 11 } else { 10 };
+    overhead + inputs + outputs
+}
+
+/// Highest transaction version relayed by default
+pub(crate) const MAX_STANDARD_VERSION: i32 = 2;
+/// Transactions heavier than this aren't relayed
+pub(crate) const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+/// Biggest scriptSig relayed, enough for a 15-of-15 p2sh multisig
+pub(crate) const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1_650;
+pub(crate) const MAX_STANDARD_TX_SIGOPS_COST: u64 = 16_000;
+/// The biggest OP_RETURN output script relayed, including the OP_RETURN itself
+pub(crate) const MAX_OP_RETURN_RELAY: usize = 83;
+/// In sat/vB
+pub(crate) const MIN_RELAY_FEE_RATE: f64 = 1.0;
+
+#[derive(Debug, Serialize)]
+/// A relay policy rule broken by a transaction, see `check_standardness`
+pub(crate) struct PolicyViolation {
+    pub(crate) rule: &'static str,
+    pub(crate) detail: String,
+}
+
+/// Disassembles a script the way Bitcoin Core's `asm` fields do: opcode names, pushes of up
+/// to 4 bytes as numbers and longer ones in hex. A truncated script, or a push that isn't
+/// minimal, ends with `[error]` where it stops making sense.
+pub(crate) fn script_to_asm(script: &Script) -> String {
+    let mut asm = Vec::new();
+    for instruction in script.instructions_minimal() {
+        match instruction {
+            Ok(Instruction::PushBytes(bytes)) if bytes.len() <= 4 => {
+                asm.push(script_num(bytes).to_string())
+            }
+            Ok(Instruction::PushBytes(bytes)) => asm.push(hex::encode(bytes)),
+            Ok(Instruction::Op(op)) => asm.push(opcode_name(op)),
+            Err(_) => {
+                asm.push("[error]".into());
+                break;
+            }
+        }
+    }
+    asm.join(" ")
+}
+
+/// Decodes a script number: little endian, with the sign in the top bit of the last byte
+pub(crate) fn script_num(bytes: &[u8]) -> i64 {
+    let Some(last) = bytes.last() else {
+        return 0;
+    };
+    let mut value = bytes
+        .iter()
+        .enumerate()
+        .fold(0i64, |value, (i, byte)| value | (*byte as i64) << (8 * i));
+    if last & 0x80 != 0 {
+        value &= !(0x80 << (8 * (bytes.len() - 1)));
+        value = -value;
+    }
+    value
+}
+
+/// The name Bitcoin Core gives an opcode, where it differs from ours
+pub(crate) fn opcode_name(op: opcodes::All) -> String {
+    match op.to_u8() {
+        0x4f => "-1".into(),
+        n @ 0x51..=0x60 => (n - 0x50).to_string(),
+        0xb1 => "OP_CHECKLOCKTIMEVERIFY".into(),
+        0xb2 => "OP_CHECKSEQUENCEVERIFY".into(),
+        0xbb..=0xff => "OP_UNKNOWN".into(),
+        _ => format!("{op:?}"),
+    }
+}
+
+/// Counts signature operations in a script. If `accurate`, multisigs count as many keys as
+/// they have, otherwise as 20, like the legacy sigop count does
+pub(crate) fn count_sigops(script: &Script, accurate: bool) -> u64 {
+    let mut count = 0;
+    let mut last_op = None;
+    for instruction in script.instructions() {
+        let Ok(instruction) = instruction else {
+            break;
+        };
+        let Instruction::Op(op) = instruction else {
+            last_op = None;
+            continue;
+        };
+        if op == opcodes::all::OP_CHECKSIG || op == opcodes::all::OP_CHECKSIGVERIFY {
+            count += 1;
+        } else if op == opcodes::all::OP_CHECKMULTISIG || op == opcodes::all::OP_CHECKMULTISIGVERIFY
+        {
+            count += match last_op.map(|op: opcodes::All| op.to_u8()) {
+                Some(n @ 0x51..=0x60) if accurate => (n - 0x50) as u64,
+                _ => 20,
+            };
+        }
+        last_op = Some(op);
+    }
+    count
+}
+
+/// Sigops for spending a witness program, zero if `script` isn't one we know
+pub(crate) fn witness_sigops(script: &Script, witness: &Witness) -> u64 {
+    if script.is_v0_p2wpkh() {
+        1
+    } else if script.is_v0_p2wsh() {
+        witness.last().map_or(0, |script| {
+            count_sigops(&Script::from(script.to_vec()), true)
+        })
+    } else {
+        0
+    }
+}
+
+/// Checks the main relay policy rules nodes apply by default. `prevouts` are the outputs
+/// spent by each input. Returns every rule broken, an empty list means the transaction
+/// should relay.
+pub(crate) fn check_standardness(tx: &Transaction, prevouts: &[TxOut]) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+    let mut violation = |rule, detail: String| violations.push(PolicyViolation { rule, detail });
+    if !(1..=MAX_STANDARD_VERSION).contains(&tx.version) {
+        violation("version", format!("Version {} isn't standard", tx.version));
+    }
+    let size = TxSizeInfo::new(tx);
+    if size.weight > MAX_STANDARD_TX_WEIGHT {
+        violation("tx-size", format!("Weight {} is too big", size.weight));
+    }
+
+    let mut sigops = 0;
+    for (vin, input) in tx.input.iter().enumerate() {
+        if input.script_sig.len() > MAX_STANDARD_SCRIPTSIG_SIZE {
+            violation(
+                "scriptsig-size",
+                format!("Input {vin} has a scriptSig too big"),
+            );
+        }
+        if !input.script_sig.is_push_only() {
+            violation(
+                "scriptsig-not-pushonly",
+                format!("Input {vin} scriptSig isn't push only"),
+            );
+        }
+        sigops += count_sigops(&input.script_sig, false) * 4;
+        let Some(prevout) = prevouts.get(vin) else {
+            continue;
+        };
+        let mut spent_script = prevout.script_pubkey.clone();
+        if spent_script.is_p2sh() {
+            // The redeem script is the last push in the scriptSig
+            let redeem_script = input
+                .script_sig
+                .instructions()
+                .filter_map(|instruction| match instruction {
+                    Ok(Instruction::PushBytes(bytes)) => Some(Script::from(bytes.to_vec())),
+                    _ => None,
+                })
+                .last()
+                .unwrap_or_default();
+            sigops += count_sigops(&redeem_script, true) * 4;
+            spent_script = redeem_script;
+        }
+        sigops += witness_sigops(&spent_script, &input.witness);
+    }
+
+    let mut op_returns = 0;
+    for (vout, output) in tx.output.iter().enumerate() {
+        let script = &output.script_pubkey;
+        sigops += count_sigops(script, false) * 4;
+        if script.is_op_return() {
+            op_returns += 1;
+            if script.len() > MAX_OP_RETURN_RELAY {
+                violation(
+                    "scriptpubkey",
+                    format!("Output {vout} OP_RETURN is too big"),
+                );
+            }
+            continue;
+        }
+        let is_standard = script.is_p2pkh()
+            || script.is_p2sh()
+            || script.is_p2pk()
+            || script.is_witness_program();
+        if !is_standard {
+            violation(
+                "scriptpubkey",
+                format!("Output {vout} has a non standard script"),
+            );
+        }
+        if output.value < script.dust_value().to_sat() {
+            violation("dust", format!("Output {vout} is dust"));
+        }
+    }
+    if op_returns > 1 {
+        violation("multi-op-return", format!("{op_returns} OP_RETURN outputs"));
+    }
+    if sigops > MAX_STANDARD_TX_SIGOPS_COST {
+        violation(
+            "bad-txns-too-many-sigops",
+            format!("Sigop cost {sigops} is too high"),
+        );
+    }
+
+    if prevouts.len() == tx.input.len() {
+        let input_value = prevouts.iter().map(|out| out.value).sum::<u64>();
+        let output_value = tx.output.iter().map(|out| out.value).sum::<u64>();
+        match input_value.checked_sub(output_value) {
+            None => violation(
+                "bad-txns-in-belowout",
+                "Outputs are worth more than inputs".into(),
+            ),
+            Some(fee) if size.fee_rate(fee) < MIN_RELAY_FEE_RATE => violation(
+                "min-relay-fee",
+                format!("Fee rate {:.2} sat/vB is too low", size.fee_rate(fee)),
+            ),
+            _ => {}
+        }
+    } else {
+        violation(
+            "missing-inputs",
+            format!(
+                "Expected {} prevouts, got {}",
+                tx.input.len(),
+                prevouts.len()
+            ),
+        );
+    }
+    violations
+}
+
+/// The virtual size of an input spending `script`, which must be p2pkh, p2wpkh or
+/// p2sh-p2wpkh, see `estimate_vsize`
+pub(crate) fn input_vsize(script: &Script) -> u64 {
+    if script.is_p2pkh() {
+        148
+    } else if script.is_p2sh() {
+        91
+    } else {
+        68
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// What `build_consolidation` returns
+pub(crate) struct Consolidation {
+    /// The unsigned transaction, in hex
+    pub(crate) tx: String,
+    pub(crate) fee: u64,
+    pub(crate) amount: u64,
+    /// How many coins we'll have less after this confirms
+    pub(crate) utxo_reduction: u32,
+    /// Coins worth less than what it costs to spend them at this fee rate
+    pub(crate) uneconomical: Vec<OutPoint>,
+}
+
+/// The size we assume for a CPFP child, roughly a one-input, one-output segwit transaction
+pub(crate) const CPFP_CHILD_VSIZE: u64 = 110;
+
+#[derive(Debug, Serialize)]
+/// The fee information for a package of unconfirmed transactions
+pub(crate) struct PackageFeeInfo {
+    pub(crate) txid: Txid,
+    pub(crate) package_txids: Vec<Txid>,
+    pub(crate) package_fee: u64,
+    pub(crate) package_vsize: u64,
+    pub(crate) package_fee_rate: f64,
+    pub(crate) target_fee_rate: f64,
+    /// How much a child spending this package would need to pay, so the whole package
+    /// reaches the target fee rate
+    pub(crate) cpfp_fee_needed: u64,
+    /// Whether there are ancestors or fees we don't know about, if so the numbers above are
+    /// a lower bound on the actual package
+    pub(crate) incomplete: bool,
+}
+
+#[derive(Debug, Clone)]
+/// A `wsh(sortedmulti(k, xpub/.../*, ...))` descriptor
+pub(crate) struct MultisigDescriptor {
+    pub(crate) threshold: usize,
+    pub(crate) cosigners: Vec<Cosigner>,
+    /// How many indexes we derived and watch, starting at 0
+    pub(crate) derived: u32,
+}
+
+/// How many unused addresses we keep derived after the last used one
+pub(crate) const GAP_LIMIT: u32 = 20;
+
+#[derive(Debug, Serialize)]
+/// How far we derived a descriptor, returned by `derivation_status`
+pub(crate) struct DerivationStatus {
+    pub(crate) id: u32,
+    pub(crate) highest_derived: Option<u32>,
+    pub(crate) highest_used: Option<u32>,
+    /// How many derived addresses come after the last used one
+    pub(crate) gap: u32,
+}
+
+#[derive(Debug, Default, Serialize)]
+/// Inconsistencies found by `audit_wallet`
+pub(crate) struct WalletAudit {
+    /// Watched multisig scripts that their descriptor doesn't derive anymore
+    pub(crate) stale_scripts: Vec<String>,
+    /// History entries that don't touch any script we still watch
+    pub(crate) orphaned: Vec<Txid>,
+    /// Coins paying to a script we don't watch
+    pub(crate) unwatched_utxos: Vec<OutPoint>,
+    /// Whether the problems above were fixed
+    pub(crate) pruned: bool,
+}
+
+impl WalletAudit {
+    pub(crate) fn is_clean(&self) -> bool {
+        self.stale_scripts.is_empty() && self.orphaned.is_empty() && self.unwatched_utxos.is_empty()
+    }
+}
+
+#[derive(Debug, Clone)]
+/// One of the keys in a multisig descriptor
+pub(crate) struct Cosigner {
+    pub(crate) label: String,
+    pub(crate) xpub: ExtendedPubKey,
+    /// The unhardened steps between the xpub and the wildcard
+    pub(crate) path: Vec<ChildNumber>,
+}
+
+#[derive(Debug, Clone)]
+/// A witness script derived from a multisig descriptor at some index
+pub(crate) struct DerivedMultisig {
+    pub(crate) descriptor: u32,
+    pub(crate) index: u32,
+    pub(crate) witness_script: Script,
+    /// The keys in this script and the cosigner each one belongs to
+    pub(crate) keys: Vec<(PublicKey, usize)>,
+}
+
+#[derive(Debug, Clone)]
+/// A taproot output we know the internal key and leaves for. Used to tell which leaf was used
+/// when one of our coins is spent through the script path.
+pub(crate) struct TaprootWatch {
+    pub(crate) output_key: XOnlyPublicKey,
+    pub(crate) leaves: Vec<(Script, LeafVersion)>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// How one of our coins got spent
+pub(crate) enum SpendKind {
+    /// We don't know anything special about this script
+    Other,
+    TaprootKeyPath,
+    TaprootScriptPath {
+        leaf_script: String,
+        leaf_version: u8,
+    },
+    /// A spend from a multisig descriptor, with the label of each cosigner that signed it
+    Multisig {
+        descriptor: u32,
+        index: u32,
+        signers: Vec<String>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// An input in a history entry that spends one of our coins
+pub(crate) struct InputSpend {
+    pub(crate) vin: u32,
+    pub(crate) prevout: OutPoint,
+    #[serde(flatten)]
+    pub(crate) kind: SpendKind,
+    /// The coin being spent, so we can put it back if this transaction gets reorged out
+    #[serde(skip)]
+    pub(crate) coin: TxOut,
+}
+
+#[derive(Debug, Clone, Serialize)]
+/// A transaction that either pays to us or spends one of our coins
+pub(crate) struct HistoryEntry {
+    pub(crate) txid: Txid,
+    pub(crate) height: u32,
+    pub(crate) received: u64,
+    pub(crate) sent: u64,
+    /// The fee paid by this transaction, if we know the value of all its inputs
+    pub(crate) fee: Option<u64>,
+    pub(crate) vsize: u64,
+    /// In sat/vB, if we know the fee
+    pub(crate) fee_rate: Option<f64>,
+    pub(crate) spends: Vec<InputSpend>,
+    /// Our scripts touched by this transaction, either as an output or as a spent coin
+    #[serde(skip)]
+    pub(crate) scripts: Vec<Script>,
+    /// If this transaction was first confirmed in a block that got reorganized out, that
+    /// block's hash
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reorged_from: Option<BlockHash>,
+    /// The raw transaction, None if it was pruned, see `set_tx_retention`
+    #[serde(skip)]
+    pub(crate) tx: Option<Transaction>,
+    /// The outputs paying to us and their index, kept even if `tx` is pruned so we can
+    /// undo this transaction
+    #[serde(skip)]
+    pub(crate) received_outputs: Vec<(u32, TxOut)>,
+    /// Position in the block and merkle branch, to prove this transaction was confirmed.
+    /// Only known if we saw the whole block
+    #[serde(skip)]
+    pub(crate) merkle_proof: Option<(u32, Vec<TxMerkleNode>)>,
+    /// Whether this is a coinbase, so our outputs in it are leaves with the coinbase flag
+    #[serde(skip)]
+    pub(crate) coinbase: bool,
+}
+
+#[derive(Debug, Default, Deserialize)]
+/// Which history entries to return from `get_wallet_history`. Every field is optional.
+pub(crate) struct HistoryFilter {
+    pub(crate) address: Option<String>,
+    pub(crate) min_height: Option<u32>,
+    pub(crate) max_height: Option<u32>,
+    pub(crate) direction: Option<Direction>,
+    /// Minimum net amount moved by this transaction, in satoshis
+    pub(crate) min_amount: Option<u64>,
+    #[serde(default)]
+    pub(crate) offset: usize,
+    pub(crate) limit: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Serialize)]
+/// A history entry as returned by `get_wallet_history`
+pub(crate) struct HistoryEntryJson<'a> {
+    #[serde(flatten)]
+    pub(crate) entry: &'a HistoryEntry,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) label: Option<String>,
+    /// Only set if privacy analysis is on, see `set_privacy_analysis`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) privacy_notes: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+/// One of our addresses that received more than once
+pub(crate) struct ReusedAddress {
+    pub(crate) address: String,
+    pub(crate) receive_count: u32,
+    /// Everything this address received, all of it linked together by the reuse
+    pub(crate) total_received: u64,
+}
+
+#[derive(Debug, Serialize)]
+/// What `privacy_report` returns
+pub(crate) struct PrivacyReport {
+    pub(crate) reused_addresses: Vec<ReusedAddress>,
+    /// The sum of `total_received` for every reused address
+    pub(crate) total_exposure: u64,
+}
+
+/// Payments that are a multiple of this look like they were typed by a person, so the other
+/// output is probably change
+pub(crate) const ROUND_AMOUNT: u64 = 100_000;
+
+impl HistoryEntry {
+    /// How many bytes the raw transaction takes, zero if it was pruned
+    pub(crate) fn raw_size(&self) -> usize {
+        self.tx.as_ref().map_or(0, |tx| tx.size())
+    }
+    pub(crate) fn direction(&self) -> Direction {
+        if self.received >= self.sent {
+            Direction::Incoming
+        } else {
+            Direction::Outgoing
+        }
+    }
+    pub(crate) fn net_amount(&self) -> u64 {
+        self.received.abs_diff(self.sent)
+    }
+}
+
+/// Values of nLockTime below this are block heights, and timestamps otherwise
+pub(crate) const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+/// If this bit is set in nSequence, the relative locktime is disabled (BIP68)
+pub(crate) const SEQUENCE_DISABLE_FLAG: u32 = 1 << 31;
+/// If this bit is set in nSequence, the relative locktime is in units of 512 seconds (BIP68)
+pub(crate) const SEQUENCE_TYPE_FLAG: u32 = 1 << 22;
+pub(crate) const SEQUENCE_MASK: u32 = 0x0000ffff;
+
+#[derive(Debug, Serialize)]
+/// What `get_transaction` returns for a transaction whose raw data we pruned
+pub(crate) struct PrunedTransaction {
+    pub(crate) txid: Txid,
+    pub(crate) height: Option<u32>,
+    pub(crate) pruned: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// Which raw wallet transactions we keep, see `set_tx_retention`
+pub(crate) enum TxRetention {
+    #[default]
+    Full,
+    /// Drop the raw transaction once it has this many confirmations
+    MetadataAfter(u32),
+    MetadataOnly,
+}
+
+impl FromStr for TxRetention {
+    type Err = String;
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "full" => Ok(TxRetention::Full),
+            "metadata-only" => Ok(TxRetention::MetadataOnly),
+            _ => mode
+                .strip_prefix("metadata-after-")
+                .and_then(|rest| rest.strip_suffix("-confs"))
+                .and_then(|confs| confs.parse().ok())
+                .map(TxRetention::MetadataAfter)
+                .ok_or(format!("Unknown retention mode {mode}")),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+/// A wallet transaction, with its timelocks decoded
+pub(crate) struct TransactionDetails {
+    pub(crate) txid: Txid,
+    /// The height this transaction was confirmed at, None if it's still pending
+    pub(crate) height: Option<u32>,
+    pub(crate) version: i32,
+    pub(crate) locktime: LockTimeInfo,
+    pub(crate) inputs: Vec<InputDetails>,
+    pub(crate) outputs: Vec<OutputDetails>,
+    pub(crate) spendable_at: SpendableAt,
+    /// Data in each OP_RETURN output, in hex
+    pub(crate) op_returns: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// What nLockTime means for a transaction, and whether it's satisfied by the current tip
+pub(crate) enum LockTimeInfo {
+    /// nLockTime is zero, or every input has a final sequence
+    Disabled,
+    Height {
+        value: u32,
+        satisfied: bool,
+    },
+    /// `satisfied` is None if we don't have enough headers to compute the median time past
+    Time {
+        value: u32,
+        satisfied: Option<bool>,
+    },
+}
+
+impl LockTimeInfo {
+    pub(crate) fn satisfied(&self) -> Option<bool> {
+        match self {
+            LockTimeInfo::Disabled => Some(true),
+            LockTimeInfo::Height { satisfied, .. } => Some(*satisfied),
+            LockTimeInfo::Time { satisfied, .. } => *satisfied,
+        }
+    }
+}
+
+impl RelativeLockTimeInfo {
+    pub(crate) fn satisfied(&self) -> Option<bool> {
+        match self {
+            RelativeLockTimeInfo::Disabled => Some(true),
+            RelativeLockTimeInfo::Blocks { satisfied, .. }
+            | RelativeLockTimeInfo::Time { satisfied, .. } => *satisfied,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct InputDetails {
+    pub(crate) prevout: OutPoint,
+    pub(crate) script_sig_asm: String,
+    pub(crate) sequence: u32,
+    pub(crate) relative_locktime: RelativeLockTimeInfo,
+    pub(crate) witness: Vec<WitnessItem>,
+}
+
+#[derive(Debug, Serialize)]
+/// An output of a transaction returned by `get_transaction`
+pub(crate) struct OutputDetails {
+    pub(crate) value: u64,
+    pub(crate) script_pubkey: String,
+    pub(crate) asm: String,
+}
+
+#[derive(Debug, Serialize)]
+/// A witness stack item, with our best guess at what it is
+pub(crate) struct WitnessItem {
+    pub(crate) hex: String,
+    #[serde(flatten)]
+    pub(crate) kind: WitnessItemKind,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum WitnessItemKind {
+    /// We couldn't tell what this is
+    Unknown,
+    Empty,
+    EcdsaSignature {
+        sighash: String,
+    },
+    SchnorrSignature {
+        sighash: String,
+    },
+    PublicKey,
+    /// A segwit v0 witness script, i.e. the last item ending in a signature check
+    WitnessScript {
+        asm: String,
+    },
+    Tapscript {
+        asm: String,
+    },
+    ControlBlock {
+        output_key_parity: u8,
+        leaf_version: u8,
+        /// How deep in the script tree the leaf being spent is
+        depth: usize,
+    },
+    Annex,
+}
+
+/// Tells, as far as we can, what each item of `witness` is. Taproot spends are recognized by
+/// their control block, or a lone signature for key path spends. This never fails, items we
+/// can't make sense of are just `Unknown`.
+pub(crate) fn annotate_witness(witness: &Witness) -> Vec<WitnessItem> {
+    let stack = witness.iter().collect::<Vec<_>>();
+    let mut kinds = stack
+        .iter()
+        .map(|_| WitnessItemKind::Unknown)
+        .collect::<Vec<_>>();
+    // Same as in `classify_taproot_spend`, a last item starting with 0x50 is an annex
+    let mut len = stack.len();
+    if len >= 2 && stack[len - 1].first() == Some(&0x50) {
+        kinds[len - 1] = WitnessItemKind::Annex;
+        len -= 1;
+    }
+    let control_block = match len {
+        0 | 1 => None,
+        _ => ControlBlock::from_slice(stack[len - 1]).ok(),
+    };
+    let is_taproot = match &control_block {
+        Some(control_block) => {
+            kinds[len - 1] = WitnessItemKind::ControlBlock {
+                output_key_parity: control_block.output_key_parity.to_u8(),
+                leaf_version: control_block.leaf_version.to_consensus(),
+                depth: control_block.merkle_branch.as_inner().len(),
+            };
+            kinds[len - 2] = WitnessItemKind::Tapscript {
+                asm: Script::from(stack[len - 2].to_vec()).asm(),
+            };
+            len -= 2;
+            true
+        }
+        // A key path spend only has the signature
+        None => len == 1 && matches!(stack[0].len(), 64 | 65),
+    };
+    if !is_taproot && len >= 2 {
+        let script = Script::from(stack[len - 1].to_vec());
+        let last_op = script.instructions().last().and_then(|op| match op {
+            Ok(Instruction::Op(op)) => Some(op),
+            _ => None,
+        });
+        let checks_signature = [
+            opcodes::all::OP_CHECKSIG,
+            opcodes::all::OP_CHECKSIGVERIFY,
+            opcodes::all::OP_CHECKMULTISIG,
+            opcodes::all::OP_CHECKMULTISIGVERIFY,
+        ];
+        if last_op.map_or(false, |op| checks_signature.contains(&op)) {
+            kinds[len - 1] = WitnessItemKind::WitnessScript { asm: script.asm() };
+            len -= 1;
+        }
+    }
+    for (item, kind) in stack.iter().zip(kinds.iter_mut()).take(len) {
+        *kind = if item.is_empty() {
+            WitnessItemKind::Empty
+        } else if is_taproot {
+            match bitcoin::SchnorrSig::from_slice(item) {
+                Ok(sig) => WitnessItemKind::SchnorrSignature {
+                    sighash: sig.hash_ty.to_string(),
+                },
+                Err(_) => WitnessItemKind::Unknown,
+            }
+        } else if let Ok(sig) = EcdsaSig::from_slice(item) {
+            WitnessItemKind::EcdsaSignature {
+                sighash: sig.hash_ty.to_string(),
+            }
+        } else if PublicKey::from_slice(item).is_ok() {
+            WitnessItemKind::PublicKey
+        } else {
+            WitnessItemKind::Unknown
+        };
+    }
+    stack
+        .into_iter()
+        .zip(kinds)
+        .map(|(item, kind)| WitnessItem {
+            hex: hex::encode(item),
+            kind,
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+/// What nSequence means for an input, as defined by BIP68. `satisfied` is None if we don't
+/// know when the coin being spent was confirmed.
+pub(crate) enum RelativeLockTimeInfo {
+    Disabled,
+    Blocks {
+        value: u16,
+        satisfied: Option<bool>,
+    },
+    Time {
+        seconds: u32,
+        satisfied: Option<bool>,
+    },
+}
+
+#[derive(Debug, Default, Serialize)]
+/// When a transaction may be included in a block, considering both nLockTime and nSequence.
+/// The block must be at `height` or above, and the median time past of its parent must be
+/// at or above `time`.
+pub(crate) struct SpendableAt {
+    pub(crate) height: Option<u32>,
+    pub(crate) time: Option<u32>,
+    /// Whether the next block may include this transaction, None if we can't tell
+    pub(crate) satisfied: Option<bool>,
+}
+
+#[derive(Deserialize)]
+/// A wallet state taken at some height by another instance, see `import_wallet_at_height`
+pub(crate) struct WalletSnapshot {
+    pub(crate) addresses: Vec<String>,
+    /// Every transaction touching those addresses up to the snapshot height
+    pub(crate) transactions: Vec<SnapshotTx>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SnapshotTx {
+    /// The serialized transaction, in hex
+    pub(crate) tx: String,
+    pub(crate) height: u32,
+}
+
+#[derive(Deserialize)]
+/// A leaf in a taproot script tree, as passed in by JS
+pub(crate) struct TapLeafJson {
+    pub(crate) depth: u8,
+    pub(crate) script: String,
+    pub(crate) leaf_version: Option<u8>,
+}
+
+impl Wallet {
+    /// Looks for transactions in this block that pay to us or spend our coins, updating the
+    /// utxo set, history and stats accordingly. Every path that feeds blocks into the wallet,
+    /// connecting and rescanning alike, goes through here, so they always agree on what's ours:
+    ///  - a transaction is ours if any output pays to a watched script, or any input spends
+    ///    one of our coins, including coins created earlier in the same block;
+    ///  - transactions with an OP_RETURN output matching a watched prefix are recorded too;
+    ///  - transactions are matched in block order, and matching one twice is a no-op.
+    ///
+    /// `inputs` are coins spent by this block; only the ones spent by our transactions are
+    /// kept, and used to figure out the fees we paid.
+    pub(crate) fn scan_block_for_wallet<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        block: &Block,
+        height: u32,
+        inputs: &HashMap<OutPoint, TxOut>,
+    ) -> FilterCost {
+        // Every output script is hashed once, matching is only lookups from here on
+        let keys = block
+            .txdata
+            .iter()
+            .map(ScriptKey::outputs)
+            .collect::<Vec<_>>();
+        let spent_coins = &self.spent_coins_for(block, &keys, inputs);
+        let mut cost = FilterCost::default();
+        let ours = block
+            .txdata
+            .iter()
+            .zip(keys.iter())
+            .enumerate()
+            .filter(|(position, (tx, keys))| {
+                let position = *position as u32;
+                self.scan_transaction(secp, tx, keys, height, position, spent_coins, &mut cost)
+            })
+            .map(|(position, (tx, _))| (position, tx.txid()))
+            .collect::<Vec<_>>();
+        if ours.is_empty() {
+            return cost;
+        }
+        // Keep merkle branches for our transactions, so we can prove them later
+        let leaves = block
+            .txdata
+            .iter()
+            .map(|tx| TxMerkleNode::from_inner(tx.txid().into_inner()))
+            .collect::<Vec<_>>();
+        let mut history = self.history.borrow_mut();
+        let tx_index = self.tx_index.borrow();
+        for (position, txid) in ours {
+            let Some(key) = tx_index.get(&txid) else {
+                continue;
+            };
+            if let Some(entry) = history.get_mut(key) {
+                entry.merkle_proof = Some((position as u32, merkle_branch(&leaves, position)));
+            }
+        }
+        cost
+    }
+    /// Checks whether a confirmed transaction pays to us or spends our coins, see `scan_block_for_wallet`.
+    /// `keys` are the `ScriptKey`s of its outputs, and `position` is its index in the block.
+    /// Returns whether it is in our history, either added now or already there, e.g. when
+    /// rescanning a block we already processed.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn scan_transaction<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        tx: &Transaction,
+        keys: &[ScriptKey],
+        height: u32,
+        position: u32,
+        spent_coins: &HashMap<OutPoint, TxOut>,
+        cost: &mut FilterCost,
+    ) -> bool {
+        let txid = tx.txid();
+        if self.tx_index.borrow().contains_key(&txid) {
+            return true;
+        }
+        let address_set = self.address_set.borrow();
+        let mut utxos = self.utxos.borrow_mut();
+        self.pending.borrow_mut().remove(&txid);
+        let mut spends = Vec::new();
+        let mut scripts = Vec::new();
+        let mut sent = 0;
+        cost.lookups += (tx.input.len() + tx.output.len()) as u64;
+        cost.bytes += tx
+            .output
+            .iter()
+            .map(|out| out.script_pubkey.len() as u64)
+            .sum::<u64>();
+        for (vin, input) in tx.input.iter().enumerate() {
+            let Some(coin) = utxos.remove(&input.previous_output) else {
+                continue;
+            };
+            self.size.borrow_mut().utxos -= utxo_size(&coin);
+            sent += coin.value;
+            scripts.push(coin.script_pubkey.clone());
+            let kind = self.classify_spend(secp, tx, vin, &coin);
+            self.spent_by
+                .borrow_mut()
+                .insert(input.previous_output, txid);
+            spends.push(InputSpend {
+                vin: vin as u32,
+                prevout: input.previous_output,
+                kind,
+                coin,
+            });
+        }
+
+        let mut matched = keys
+            .iter()
+            .map(|key| address_set.contains_key(key))
+            .collect::<Vec<_>>();
+        drop(utxos);
+        drop(address_set);
+        // The classifier is JS, which may call back into the wallet, so we ask it with nothing
+        // borrowed, and without it in its slot
+        let classifier = self.script_classifier.borrow_mut().take();
+        if let Some(mut classifier) = classifier {
+            for (vout, output) in tx.output.iter().enumerate() {
+                if !matched[vout] {
+                    matched[vout] = classifier.is_ours(txid, vout as u32, output);
+                }
+            }
+            let mut slot = self.script_classifier.borrow_mut();
+            if classifier.failures >= MAX_CLASSIFIER_FAILURES {
+                log("Script classifier keeps failing, dropping it".into());
+            } else if slot.is_none() {
+                // Unless a new one was set while we were calling it
+                *slot = Some(classifier);
+            }
+        }
+
+        let mut received = 0;
+        let mut received_scripts = Vec::new();
+        let mut received_outputs = Vec::new();
+        let mut utxos = self.utxos.borrow_mut();
+        for (vout, output) in tx.output.iter().enumerate() {
+            if !matched[vout] {
+                continue;
+            }
+            received += output.value;
+            received_scripts.push(output.script_pubkey.clone());
+            received_outputs.push((vout as u32, output.clone()));
+            self.size.borrow_mut().utxos += utxo_size(output);
+            utxos.insert(
+                OutPoint {
+                    txid,
+                    vout: vout as u32,
+                },
+                output.clone(),
+            );
+        }
+        drop(utxos);
+
+        let is_ours = received > 0 || !spends.is_empty();
+        let payloads = op_return_payloads(tx);
+        let prefix_match = payloads.iter().any(|payload| {
+            self.op_return_prefixes
+                .borrow()
+                .iter()
+                .any(|prefix| payload.starts_with(prefix))
+        });
+        if !payloads.is_empty() && (is_ours || prefix_match) {
+            self.op_returns
+                .borrow_mut()
+                .insert(txid, OpReturnRecord { height, payloads });
+        }
+        if !is_ours {
+            return false;
+        }
+        let mut first_seen = self.first_seen.borrow_mut();
+        for script in received_scripts.iter() {
+            let seen = first_seen.entry(script.clone()).or_insert(height);
+            *seen = (*seen).min(height);
+        }
+        drop(first_seen);
+        // We only know the fee if we can find the value of every input
+        let fee = if tx.is_coin_base() {
+            None
+        } else {
+            tx.input
+                .iter()
+                .map(|input| spent_coins.get(&input.previous_output).map(|out| out.value))
+                .sum::<Option<u64>>()
+                .and_then(|input_value| {
+                    input_value.checked_sub(tx.output.iter().map(|out| out.value).sum())
+                })
+        };
+        scripts.extend(received_scripts.iter().cloned());
+        let size = TxSizeInfo::new(tx);
+        let entry = HistoryEntry {
+            txid,
+            height,
+            received,
+            sent,
+            fee,
+            vsize: size.vsize,
+            fee_rate: fee.map(|fee| size.fee_rate(fee)),
+            spends,
+            scripts,
+            reorged_from: self.reorged.borrow().get(&txid).copied(),
+            tx: Some(tx.clone()),
+            received_outputs,
+            merkle_proof: None,
+            coinbase: tx.is_coin_base(),
+        };
+        self.stats.borrow_mut().add(&entry, &received_scripts);
+        self.size.borrow_mut().transactions += tx.size();
+        self.tx_index.borrow_mut().insert(txid, (height, position));
+        self.history.borrow_mut().insert((height, position), entry);
+        true
+    }
+    /// Remembers the block each of the `reorged` entries was in, if it's one of the
+    /// `stale_blocks` (by height), so their history entries tell when they confirm again
+    pub(crate) fn mark_reorged(
+        &self,
+        reorged: &[HistoryEntry],
+        stale_blocks: &HashMap<u32, BlockHash>,
+    ) {
+        let mut marked = self.reorged.borrow_mut();
+        for entry in reorged.iter() {
+            if let Some(stale_block) = stale_blocks.get(&entry.height) {
+                marked.insert(entry.txid, *stale_block);
+            }
+        }
+    }
+    /// Once the blocks replacing `stale_blocks` are scanned, puts the `reorged` transactions
+    /// that didn't confirm again back in our pending ones. Returns the txid, stale block and
+    /// whether it confirmed again for each transaction that was in a stale block.
+    pub(crate) fn requeue_reorged(
+        &self,
+        reorged: Vec<HistoryEntry>,
+        stale_blocks: &HashMap<u32, BlockHash>,
+    ) -> Vec<(Txid, BlockHash, bool)> {
+        let mut requeued = Vec::new();
+        for entry in reorged {
+            let Some(stale_block) = stale_blocks.get(&entry.height) else {
+                continue;
+            };
+            let reconfirmed = self.confirmation_height(&entry.txid).is_some();
+            // Until it confirms again, it's one of our unconfirmed transactions
+            if let (false, Some(tx)) = (reconfirmed, entry.tx) {
+                self.pending
+                    .borrow_mut()
+                    .insert(entry.txid, PendingTx { tx, fee: entry.fee });
+            }
+            requeued.push((entry.txid, *stale_block, reconfirmed));
+        }
+        requeued
+    }
+    /// Derives and watches the scripts of a multisig descriptor for every index below `up_to`
+    /// we didn't derive yet
+    pub(crate) fn derive_multisig<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        id: u32,
+        up_to: u32,
+    ) -> Result<(), String> {
+        let mut descriptors = self.descriptors.borrow_mut();
+        let descriptor = descriptors
+            .get_mut(id as usize)
+            .ok_or("Unknown descriptor")?;
+        let mut derived = Vec::new();
+        for index in descriptor.derived..up_to {
+            let (witness_script, keys) = descriptor.derive(secp, index)?;
+            derived.push(DerivedMultisig {
+                descriptor: id,
+                index,
+                witness_script,
+                keys,
+            });
+        }
+
+        let mut address_set = self.address_set.borrow_mut();
+        self.check_watch_capacity(address_set.len(), derived.len())?;
+        let mut multisig_scripts = self.multisig_scripts.borrow_mut();
+        for derived in derived {
+            let script_pubkey = Script::new_v0_p2wsh(&derived.witness_script.wscript_hash());
+            self.watch(&mut address_set, script_pubkey.clone());
+            multisig_scripts.insert(script_pubkey, derived);
+        }
+        descriptor.derived = descriptor.derived.max(up_to);
+        Ok(())
+    }
+    /// Checks that every history entry and coin belongs to a script we watch. If `rederive`
+    /// is set, multisig scripts are derived again from their descriptors first, and the ones
+    /// that don't match are no longer considered ours. With `prune`, stale scripts, orphaned
+    /// entries and unwatched coins are removed, and stats are recomputed from what's left.
+    pub(crate) fn audit<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        rederive: bool,
+        prune: bool,
+    ) -> WalletAudit {
+        let mut audit = WalletAudit::default();
+        let mut stale = HashSet::new();
+        if rederive {
+            let descriptors = self.descriptors.borrow();
+            for (script, derived) in self.multisig_scripts.borrow().iter() {
+                let matches = descriptors
+                    .get(derived.descriptor as usize)
+                    .filter(|descriptor| derived.index < descriptor.derived)
+                    .and_then(|descriptor| descriptor.derive(secp, derived.index).ok())
+                    .map_or(false, |(witness_script, _)| {
+                        Script::new_v0_p2wsh(&witness_script.wscript_hash()) == *script
+                    });
+                if !matches {
+                    stale.insert(script.clone());
+                }
+            }
+        }
+        let address_set = self.address_set.borrow();
+        let is_ours = |script: &Script| address_set.contains(script) && !stale.contains(script);
+
+        audit.stale_scripts = stale
+            .iter()
+            .map(|script| hex::encode(script.as_bytes()))
+            .collect();
+        audit.orphaned = self
+            .history
+            .borrow()
+            .values()
+            .filter(|entry| !entry.scripts.iter().any(is_ours))
+            .map(|entry| entry.txid)
+            .collect();
+        audit.unwatched_utxos = self
+            .utxos
+            .borrow()
+            .iter()
+            .filter(|(_, out)| !is_ours(&out.script_pubkey))
+            .map(|(outpoint, _)| *outpoint)
+            .collect();
+        drop(address_set);
+        if !prune || audit.is_clean() {
+            return audit;
+        }
+
+        let mut address_set = self.address_set.borrow_mut();
+        let mut size = self.size.borrow_mut();
+        for script in stale.iter() {
+            if address_set.remove(script) {
+                size.scripts -= script.len();
+            }
+            self.multisig_scripts.borrow_mut().remove(script);
+        }
+        drop(address_set);
+        let mut history = self.history.borrow_mut();
+        let mut tx_index = self.tx_index.borrow_mut();
+        for txid in audit.orphaned.iter() {
+            if let Some(entry) = tx_index.remove(txid).and_then(|key| history.remove(&key)) {
+                size.transactions -= entry.raw_size();
+                for spend in entry.spends.iter() {
+                    self.spent_by.borrow_mut().remove(&spend.prevout);
+                }
+            }
+        }
+        let mut utxos = self.utxos.borrow_mut();
+        for outpoint in audit.unwatched_utxos.iter() {
+            if let Some(out) = utxos.remove(outpoint) {
+                size.utxos -= utxo_size(&out);
+            }
+        }
+
+        let address_set = self.address_set.borrow();
+        let mut stats = WalletStats::default();
+        for entry in history.values() {
+            let received_scripts = entry
+                .received_outputs
+                .iter()
+                .map(|(_, out)| out.script_pubkey.clone())
+                .filter(|script| address_set.contains(script))
+                .collect::<Vec<_>>();
+            stats.add(entry, &received_scripts);
+        }
+        *self.stats.borrow_mut() = stats;
+        audit.pruned = true;
+        audit
+    }
+    /// The label for something, `reference` must already be normalized
+    pub(crate) fn label(&self, kind: LabelKind, reference: &str) -> Option<String> {
+        self.labels
+            .borrow()
+            .get(&(kind, reference.to_string()))
+            .map(|label| label.label.clone())
+    }
+    /// Things an observer could learn about us from this transaction: reused addresses, and
+    /// outputs that are easy to tell apart as our change
+    pub(crate) fn privacy_notes(&self, entry: &HistoryEntry) -> Vec<String> {
+        let address_set = self.address_set.borrow();
+        let stats = self.stats.borrow();
+        let mut notes = Vec::new();
+        for (_, out) in entry.received_outputs.iter() {
+            let count = stats
+                .receive_counts
+                .get(&out.script_pubkey)
+                .copied()
+                .unwrap_or(0);
+            if count > 1 {
+                let address = Address::from_script(&out.script_pubkey, CHAIN_NETWORK).map_or_else(
+                    |_| hex::encode(out.script_pubkey.as_bytes()),
+                    |a| a.to_string(),
+                );
+                notes.push(format!("address {address} reused {count} times"));
+            }
+        }
+        // Without the raw transaction we can't see the outputs paying someone else
+        let Some(tx) = &entry.tx else {
+            return notes;
+        };
+        let (ours, theirs): (Vec<_>, Vec<_>) = tx
+            .output
+            .iter()
+            .partition(|out| address_set.contains(&out.script_pubkey));
+        let round_payment = theirs.len() == 1 && theirs[0].value % ROUND_AMOUNT == 0;
+        if entry.sent > 0 && !ours.is_empty() && round_payment {
+            notes.push("round-amount payment suggests the other output is change".to_string());
+        }
+        notes
+    }
+    /// The highest index of a multisig descriptor whose script received something
+    pub(crate) fn highest_used_index(&self, id: u32) -> Result<Option<u32>, String> {
+        if id as usize >= self.descriptors.borrow().len() {
+            return Err("Unknown descriptor".into());
+        }
+        let stats = self.stats.borrow();
+        Ok(self
+            .multisig_scripts
+            .borrow()
+            .iter()
+            .filter(|(script, derived)| {
+                derived.descriptor == id && stats.receive_counts.contains_key(*script)
+            })
+            .map(|(_, derived)| derived.index)
+            .max())
+    }
+    /// The key that can spend coins locked to `script`, if we imported one
+    pub(crate) fn signing_key(&self, script: &Script) -> Option<PrivateKey> {
+        self.keys.borrow().get(script).copied()
+    }
+    /// Starts watching a script, returns false if we were already watching it
+    pub(crate) fn watch(&self, address_set: &mut WatchSet, script: Script) -> bool {
+        let len = script.len();
+        let inserted = address_set.insert(script);
+        if inserted {
+            self.size.borrow_mut().scripts += len;
+        }
+        inserted
+    }
+    /// Makes sure we can watch `additional` more scripts without going over the limit
+    pub(crate) fn check_watch_capacity(
+        &self,
+        watched: usize,
+        additional: usize,
+    ) -> Result<(), String> {
+        match *self.max_watched_scripts.borrow() {
+            Some(max) if watched + additional > max => Err(format!(
+                "Watch list full: watching {watched} scripts, the limit is {max}"
+            )),
+            _ => Ok(()),
+        }
+    }
+    /// Undoes everything we learned from blocks above `height`, used when those blocks are
+    /// reorganized out of the chain. History is kept in chain order, so this is a range
+    /// removal from its end. Returns the entries removed, in chain order.
+    pub(crate) fn disconnect_above(&self, height: u32) -> Vec<HistoryEntry> {
+        if height == u32::MAX {
+            return Vec::new();
+        }
+        self.op_returns
+            .borrow_mut()
+            .retain(|_, record| record.height <= height);
+        let mut history = self.history.borrow_mut();
+        let removed = history.split_off(&(height + 1, 0));
+        let mut utxos = self.utxos.borrow_mut();
+        let mut size = self.size.borrow_mut();
+        // Undo in reverse chain order, so a coin created and spent in the removed range is
+        // brought back by its spend before its creation removes it
+        for entry in removed.values().rev() {
+            size.transactions -= entry.raw_size();
+            self.tx_index.borrow_mut().remove(&entry.txid);
+            for (vout, _) in entry.received_outputs.iter() {
+                if let Some(out) = utxos.remove(&OutPoint {
+                    txid: entry.txid,
+                    vout: *vout,
+                }) {
+                    size.utxos -= utxo_size(&out);
+                }
+            }
+            for spend in entry.spends.iter() {
+                size.utxos += utxo_size(&spend.coin);
+                utxos.insert(spend.prevout, spend.coin.clone());
+                self.spent_by.borrow_mut().remove(&spend.prevout);
+            }
+            // The same scripts `scan_transaction` counted, including the ones the script
+            // classifier matched
+            let received_scripts = entry
+                .received_outputs
+                .iter()
+                .map(|(_, out)| out.script_pubkey.clone())
+                .collect::<Vec<_>>();
+            self.stats.borrow_mut().remove(
+                entry,
+                &received_scripts,
+                history.values().next(),
+                history.values().next_back(),
+            );
+        }
+        // Scripts first seen in the removed blocks are now first seen at their earliest
+        // remaining entry, if any
+        let mut first_seen = self.first_seen.borrow_mut();
+        let stale = first_seen
+            .iter()
+            .filter(|(_, seen)| **seen > height)
+            .map(|(script, _)| script.clone())
+            .collect::<HashSet<_>>();
+        for script in stale.iter() {
+            first_seen.remove(script);
+        }
+        if !stale.is_empty() {
+            for entry in history.values() {
+                for (_, out) in entry.received_outputs.iter() {
+                    if stale.contains(&out.script_pubkey) {
+                        first_seen
+                            .entry(out.script_pubkey.clone())
+                            .or_insert(entry.height);
+                    }
+                }
+            }
+        }
+        removed.into_values().collect()
+    }
+    /// Returns a transaction we know about, and the height it got confirmed at
+    pub(crate) fn get_transaction(&self, txid: &Txid) -> Option<(Transaction, Option<u32>)> {
+        if let Some(pending) = self.pending.borrow().get(txid) {
+            return Some((pending.tx.clone(), None));
+        }
+        let key = *self.tx_index.borrow().get(txid)?;
+        let height = key.0;
+        let tx = self.history.borrow().get(&key)?.tx.clone()?;
+        Some((tx, Some(height)))
+    }
+    /// Whether this is a confirmed transaction of ours whose raw data was pruned
+    pub(crate) fn is_pruned(&self, txid: &Txid) -> bool {
+        let Some(key) = self.tx_index.borrow().get(txid).copied() else {
+            return false;
+        };
+        self.history
+            .borrow()
+            .get(&key)
+            .map_or(false, |entry| entry.tx.is_none())
+    }
+    /// Drops the raw transactions our retention mode says we don't need anymore, given the
+    /// current tip height
+    pub(crate) fn apply_tx_retention(&self, tip_height: u32) {
+        let cutoff = match *self.tx_retention.borrow() {
+            TxRetention::Full => return,
+            TxRetention::MetadataOnly => tip_height,
+            // A transaction at the tip has one confirmation
+            TxRetention::MetadataAfter(confs) => match tip_height.checked_sub(confs) {
+                Some(height) => height + 1,
+                None => return,
+            },
+        };
+        let mut size = self.size.borrow_mut();
+        for entry in self
+            .history
+            .borrow_mut()
+            .range_mut(..=(cutoff, u32::MAX))
+            .map(|(_, entry)| entry)
+        {
+            if let Some(tx) = entry.tx.take() {
+                size.transactions -= tx.size();
+            }
+        }
+    }
+    /// Whether a pending transaction spends a coin that some confirmed transaction already
+    /// spent, meaning it can never confirm
+    pub(crate) fn is_conflicted(&self, txid: &Txid) -> bool {
+        let pending = self.pending.borrow();
+        let Some(pending) = pending.get(txid) else {
+            return false;
+        };
+        let spent_by = self.spent_by.borrow();
+        pending.tx.input.iter().any(|input| {
+            spent_by
+                .get(&input.previous_output)
+                .map_or(false, |spender| spender != txid)
+        })
+    }
+    /// Our coinbase outputs that can't be spent in the next block yet, with the height of the
+    /// first block that may spend them
+    pub(crate) fn immature_coins(&self, tip_height: u32, maturity: u32) -> HashMap<OutPoint, u32> {
+        let history = self.history.borrow();
+        let tx_index = self.tx_index.borrow();
+        self.utxos
+            .borrow()
+            .keys()
+            .filter_map(|outpoint| {
+                let key = tx_index.get(&outpoint.txid)?;
+                let mature_at = key.0 + maturity;
+                (history.get(key)?.coinbase && mature_at > tip_height + 1)
+                    .then_some((*outpoint, mature_at))
+            })
+            .collect()
+    }
+    /// The height a wallet transaction got confirmed at, if we know it
+    pub(crate) fn confirmation_height(&self, txid: &Txid) -> Option<u32> {
+        self.tx_index.borrow().get(txid).map(|(height, _)| *height)
+    }
+    /// How the keys of a multisig script were derived, for `export_utxos_psbt_ready`
+    pub(crate) fn utxo_derivation(&self, derived: &DerivedMultisig) -> UtxoDerivation {
+        let descriptors = self.descriptors.borrow();
+        let keys = derived
+            .keys
+            .iter()
+            .filter_map(|(pubkey, cosigner)| {
+                let cosigner = descriptors
+                    .get(derived.descriptor as usize)?
+                    .cosigners
+                    .get(*cosigner)?;
+                let path = cosigner
+                    .path
+                    .iter()
+                    .map(|step| format!("/{step}"))
+                    .collect::<String>();
+                Some(KeyDerivation {
+                    pubkey: *pubkey,
+                    xpub: cosigner.xpub,
+                    path: format!("m{path}/{}", derived.index),
+                })
+            })
+            .collect();
+        UtxoDerivation {
+            descriptor: derived.descriptor,
+            index: derived.index,
+            witness_script: hex::encode(derived.witness_script.as_bytes()),
+            keys,
+        }
+    }
+    /// Whether this transaction pays to us, or spends one of our coins (confirmed or not)
+    pub(crate) fn is_relevant(&self, tx: &Transaction) -> bool {
+        let address_set = self.address_set.borrow();
+        let utxos = self.utxos.borrow();
+        let pending = self.pending.borrow();
+        tx.output
+            .iter()
+            .any(|output| address_set.contains(&output.script_pubkey))
+            || tx.input.iter().any(|input| {
+                utxos.contains_key(&input.previous_output)
+                    || pending.contains_key(&input.previous_output.txid)
+            })
+    }
+    /// Picks, out of every coin spent by `block`, the ones spent by transactions that may be
+    /// ours. Transactions spending from one of those in the same block are included too.
+    /// `keys` are the `ScriptKey`s of each transaction's outputs.
+    pub(crate) fn spent_coins_for(
+        &self,
+        block: &Block,
+        keys: &[Vec<ScriptKey>],
+        inputs: &HashMap<OutPoint, TxOut>,
+    ) -> HashMap<OutPoint, TxOut> {
+        let address_set = self.address_set.borrow();
+        let utxos = self.utxos.borrow();
+        let pending = self.pending.borrow();
+        let mut relevant = HashSet::new();
+        let mut coins = HashMap::new();
+        for (tx, keys) in block.txdata.iter().zip(keys) {
+            let is_relevant = keys.iter().any(|key| address_set.contains_key(key))
+                || tx.input.iter().any(|input| {
+                    relevant.contains(&input.previous_output.txid)
+                        || utxos.contains_key(&input.previous_output)
+                        || pending.contains_key(&input.previous_output.txid)
+                });
+            if !is_relevant {
+                continue;
+            }
+            relevant.insert(tx.txid());
+            for input in tx.input.iter() {
+                if let Some(coin) = inputs.get(&input.previous_output) {
+                    coins.insert(input.previous_output, coin.clone());
+                }
+            }
+        }
+        coins
+    }
+    /// Computes the fee and size for the package containing `txid`: its unconfirmed ancestors,
+    /// itself, and its unconfirmed descendants, as far as the wallet knows about them.
+    pub(crate) fn package_fee_info(
+        &self,
+        txid: Txid,
+        target_fee_rate: f64,
+    ) -> Result<PackageFeeInfo, String> {
+        let pending = self.pending.borrow();
+        if !pending.contains_key(&txid) {
+            return Err("Transaction is not a pending wallet transaction".into());
+        }
+        let confirmed = self.tx_index.borrow();
+
+        let mut incomplete = false;
+        let mut package = HashSet::new();
+        // Ancestors: walk up through the parents we know are still unconfirmed
+        let mut to_visit = vec![txid];
+        while let Some(current) = to_visit.pop() {
+            if !package.insert(current) {
+                continue;
+            }
+            for input in pending[&current].tx.input.iter() {
+                let parent = input.previous_output.txid;
+                if pending.contains_key(&parent) {
+                    to_visit.push(parent);
+                } else if !confirmed.contains_key(&parent) {
+                    // We can't tell whether this parent is confirmed or not
+                    incomplete = true;
+                }
+            }
+        }
+        // Descendants: anything pending that spends from the target or its descendants
+        let mut to_visit = vec![txid];
+        let mut descendants = HashSet::new();
+        while let Some(current) = to_visit.pop() {
+            if !descendants.insert(current) {
+                continue;
+            }
+            let children = pending.iter().filter(|(_, child)| {
+                child
+                    .tx
+                    .input
+                    .iter()
+                    .any(|input| input.previous_output.txid == current)
+            });
+            to_visit.extend(children.map(|(child_txid, _)| *child_txid));
+        }
+        package.extend(descendants);
+
+        let mut package_fee = 0;
+        let mut package_vsize = 0;
+        for member in package.iter() {
+            let member = &pending[member];
+            match member.fee {
+                Some(fee) => package_fee += fee,
+                None => incomplete = true,
+            }
+            package_vsize += TxSizeInfo::new(&member.tx).vsize;
+        }
+        let needed = (target_fee_rate * (package_vsize + CPFP_CHILD_VSIZE) as f64).ceil() as u64;
+
+        let mut package_txids = package.into_iter().collect::<Vec<_>>();
+        package_txids.sort();
+        Ok(PackageFeeInfo {
+            txid,
+            package_txids,
+            package_fee,
+            package_vsize,
+            package_fee_rate: package_fee as f64 / package_vsize as f64,
+            target_fee_rate,
+            cpfp_fee_needed: needed.saturating_sub(package_fee),
+            incomplete,
+        })
+    }
+    /// Finds out how one of our coins, `prevout`, got spent by the input `vin` of `tx`
+    pub(crate) fn classify_spend<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        tx: &Transaction,
+        vin: usize,
+        prevout: &TxOut,
+    ) -> SpendKind {
+        let witness = &tx.input[vin].witness;
+        if let Some(watch) = self.taproot_outputs.borrow().get(&prevout.script_pubkey) {
+            return Self::classify_taproot_spend(secp, watch, witness);
+        }
+        if let Some(derived) = self.multisig_scripts.borrow().get(&prevout.script_pubkey) {
+            return self.classify_multisig_spend(secp, derived, tx, vin, prevout.value);
+        }
+        SpendKind::Other
+    }
+    /// Checks which of the cosigners signed a multisig spend. The witness looks like
+    /// `<empty> <sig1> ... <sigk> <witness_script>`, so we check every signature against every
+    /// key in the script.
+    pub(crate) fn classify_multisig_spend<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        derived: &DerivedMultisig,
+        tx: &Transaction,
+        vin: usize,
+        value: u64,
+    ) -> SpendKind {
+        let descriptors = self.descriptors.borrow();
+        let descriptor = &descriptors[derived.descriptor as usize];
+        let witness = tx.input[vin].witness.iter().collect::<Vec<_>>();
+        let mut signers = Vec::new();
+        if witness.last().copied() == Some(derived.witness_script.as_bytes()) {
+            let mut sighash_cache = SighashCache::new(tx);
+            for item in &witness[..witness.len() - 1] {
+                let Ok(sig) = EcdsaSig::from_slice(item) else {
+                    continue;
+                };
+                let Ok(sighash) = sighash_cache.segwit_signature_hash(
+                    vin,
+                    &derived.witness_script,
+                    value,
+                    sig.hash_ty,
+                ) else {
+                    continue;
+                };
+                let Ok(msg) = Message::from_slice(&sighash[..]) else {
+                    continue;
+                };
+                let signer = derived
+                    .keys
+                    .iter()
+                    .find(|(key, _)| secp.verify_ecdsa(&msg, &sig.sig, &key.inner).is_ok());
+                if let Some((_, cosigner)) = signer {
+                    signers.push(descriptor.cosigners[*cosigner].label.clone());
+                }
+            }
+        }
+        SpendKind::Multisig {
+            descriptor: derived.descriptor,
+            index: derived.index,
+            signers,
+        }
+    }
+    /// Tells whether a taproot coin was spent using the key path, or which of our leaves was
+    /// used if it was spent through the script path.
+    pub(crate) fn classify_taproot_spend<C: Verification>(
+        secp: &Secp256k1<C>,
+        watch: &TaprootWatch,
+        witness: &Witness,
+    ) -> SpendKind {
+        let mut stack = witness.iter().collect::<Vec<_>>();
+        // If there are at least two elements and the last one starts with 0x50, it's an annex
+        if stack.len() >= 2 && stack.last().map(|el| el.first()) == Some(Some(&0x50)) {
+            stack.pop();
+        }
+        if stack.len() < 2 {
+            return SpendKind::TaprootKeyPath;
+        }
+        let script = Script::from(stack[stack.len() - 2].to_vec());
+        let Ok(control_block) = ControlBlock::from_slice(stack[stack.len() - 1]) else {
+            return SpendKind::Other;
+        };
+        let is_ours = watch
+            .leaves
+            .iter()
+            .any(|(leaf, version)| *leaf == script && *version == control_block.leaf_version);
+        if !is_ours || !control_block.verify_taproot_commitment(secp, watch.output_key, &script) {
+            return SpendKind::Other;
+        }
+        SpendKind::TaprootScriptPath {
+            leaf_script: hex::encode(script.as_bytes()),
+            leaf_version: control_block.leaf_version.to_consensus(),
+        }
+    }
+}
+
+impl MultisigDescriptor {
+    /// Parses a `wsh(sortedmulti(k, KEY, ...))` descriptor, where each KEY is an xpub (or
+    /// tpub), optionally prefixed with its origin, followed by unhardened steps and ending with
+    /// a wildcard, like `[d34db33f/48h/1h/0h/2h]tpub.../0/*`. The checksum is ignored if present.
+    pub(crate) fn parse(descriptor: &str, labels: Vec<String>) -> Result<Self, String> {
+        let descriptor = descriptor.split('#').next().unwrap_or_default();
+        let descriptor = descriptor.split_whitespace().collect::<String>();
+        let inner = descriptor
+            .strip_prefix("wsh(sortedmulti(")
+            .and_then(|inner| inner.strip_suffix("))"))
+            .ok_or("Only wsh(sortedmulti(...)) descriptors are supported")?;
+
+        let mut items = inner.split(',');
+        let threshold = items
+            .next()
+            .and_then(|k| k.parse::<usize>().ok())
+            .ok_or("Invalid threshold")?;
+
+        let mut cosigners = Vec::new();
+        for (i, key) in items.enumerate() {
+            // Drop the key origin, we don't need it to derive the scripts
+            let key = key.rsplit(']').next().unwrap_or(key);
+            let mut steps = key.split('/');
+            let xpub = steps
+                .next()
+                .and_then(|xpub| ExtendedPubKey::from_str(xpub).ok())
+                .ok_or(format!("Invalid xpub for key {i}"))?;
+            let steps = steps.collect::<Vec<_>>();
+            if steps.last() != Some(&"*") {
+                return Err(format!("Key {i} must end with a /* wildcard"));
+            }
+            let path = steps[..steps.len() - 1]
+                .iter()
+                .map(|step| {
+                    step.parse::<u32>()
+                        .ok()
+                        .and_then(|step| ChildNumber::from_normal_idx(step).ok())
+                        .ok_or(format!("Invalid derivation step {step} for key {i}"))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            let label = labels
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| format!("cosigner {i}"));
+            cosigners.push(Cosigner { label, xpub, path });
+        }
+        if threshold == 0 || threshold > cosigners.len() || cosigners.len() > 20 {
+            return Err("Invalid threshold for this number of keys".into());
+        }
+        Ok(Self {
+            threshold,
+            cosigners,
+            derived: 0,
+        })
+    }
+    /// Derives the witness script at `index`, along with which cosigner owns each key
+    pub(crate) fn derive<C: Verification>(
+        &self,
+        secp: &Secp256k1<C>,
+        index: u32,
+    ) -> Result<(Script, Vec<(PublicKey, usize)>), String> {
+        let child = ChildNumber::from_normal_idx(index).map_err(|e| e.to_string())?;
+        let mut keys = self
+            .cosigners
+            .iter()
+            .enumerate()
+            .map(|(i, cosigner)| {
+                let mut path = cosigner.path.clone();
+                path.push(child);
+                let xpub = cosigner
+                    .xpub
+                    .derive_pub(secp, &path)
+                    .map_err(|e| e.to_string())?;
+                Ok((PublicKey::new(xpub.public_key), i))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        keys.sort_by_key(|(key, _)| key.inner.serialize());
+
+        let mut builder = Builder::new().push_int(self.threshold as i64);
+        for (key, _) in keys.iter() {
+            builder = builder.push_key(key);
+        }
+        let witness_script = builder
+            .push_int(keys.len() as i64)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        Ok((witness_script, keys))
+    }
+}
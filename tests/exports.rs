@@ -0,0 +1,209 @@
+//! Pins the name and signature of everything we export to JS. wasm-bindgen generates the
+//! bindings from those, so while this compiles, moving code around doesn't change the JS API.
+
+use example_libfloresta::{
+    install_panic_hook, last_panic, on_fatal, ChainView, FlorestaChain, WasmStore,
+};
+
+#[test]
+fn exports_keep_their_signatures() {
+    // Free functions
+    let _: fn() = install_panic_hook;
+    let _: unsafe fn() -> String = last_panic;
+    let _: unsafe fn(Option<js_sys::Function>) = on_fatal;
+
+    // FlorestaChain
+    let _: unsafe fn() -> FlorestaChain = FlorestaChain::new;
+    let _: unsafe fn(&FlorestaChain) -> WasmStore = FlorestaChain::store_handle;
+    let _: unsafe fn(&WasmStore, bool) -> Result<FlorestaChain, String> =
+        FlorestaChain::open_shared;
+    let _: unsafe fn(&mut FlorestaChain) -> Result<(), String> = FlorestaChain::refresh;
+    let _: unsafe fn() -> FlorestaChain = FlorestaChain::new_tracking_only;
+    let _: unsafe fn(&FlorestaChain) -> bool = FlorestaChain::is_tracking_only;
+    let _: unsafe fn(&FlorestaChain) -> bool = FlorestaChain::is_read_only;
+    let _: unsafe fn(&FlorestaChain, String, Option<u32>, Option<bool>) -> Result<bool, String> =
+        FlorestaChain::add_address;
+    let _: unsafe fn(&FlorestaChain, String, String) -> Result<String, String> =
+        FlorestaChain::import_privkey;
+    let _: unsafe fn(&FlorestaChain, String, String, u64) -> Result<String, String> =
+        FlorestaChain::sweep_key;
+    let _: unsafe fn(&FlorestaChain, u32, u64, String) -> Result<String, String> =
+        FlorestaChain::build_consolidation;
+    let _: unsafe fn(String) -> Result<String, String> = FlorestaChain::script_asm;
+    let _: unsafe fn(String, String) -> Result<String, String> = FlorestaChain::check_standardness;
+    let _: unsafe fn(String, String) -> Result<String, String> = FlorestaChain::is_reconstructible;
+    let _: unsafe fn(String) -> Result<String, String> = FlorestaChain::check_leaf_reconstruction;
+    let _: unsafe fn(String) -> Result<String, String> = FlorestaChain::tx_size_info;
+    let _: unsafe fn(String, u64) -> Result<f64, String> = FlorestaChain::fee_rate;
+    let _: unsafe fn(&FlorestaChain, String, String, String) -> Result<(), String> =
+        FlorestaChain::set_label;
+    let _: unsafe fn(&FlorestaChain, String, String) -> Result<Option<String>, String> =
+        FlorestaChain::get_label;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::export_labels;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<u32, String> = FlorestaChain::import_labels;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::list_spendable;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::add_addresses;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::import_core_descriptors;
+    let _: unsafe fn(&FlorestaChain, String, Option<bool>) -> Result<String, String> =
+        FlorestaChain::import_core_dump;
+    let _: unsafe fn(&FlorestaChain, Option<u32>) = FlorestaChain::set_max_watched_scripts;
+    let _: unsafe fn(&FlorestaChain) -> u32 = FlorestaChain::watch_set_size;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::compact_store;
+    let _: unsafe fn(&FlorestaChain, Vec<u8>) -> Result<(), String> =
+        FlorestaChain::set_store_cipher;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::verify_chainstate;
+    let _: unsafe fn(&mut FlorestaChain) -> Result<u32, String> = FlorestaChain::repair_chainstate;
+    let _: unsafe fn(&mut FlorestaChain) -> Result<u32, String> = FlorestaChain::reindex;
+    let _: unsafe fn(&mut FlorestaChain, js_sys::Function) = FlorestaChain::set_progress_callback;
+    let _: unsafe fn(&mut FlorestaChain, Option<js_sys::Function>) =
+        FlorestaChain::set_entropy_source;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> = FlorestaChain::locate_fork;
+    let _: unsafe fn(&FlorestaChain) -> u32 = FlorestaChain::verified_from_height;
+    let _: unsafe fn(&mut FlorestaChain, js_sys::Uint8Array) -> Result<u32, String> =
+        FlorestaChain::backfill_headers;
+    let _: unsafe fn(&FlorestaChain, u32, f64) = FlorestaChain::set_flush_policy;
+    let _: unsafe fn(&FlorestaChain) -> Result<(), String> = FlorestaChain::flush_store;
+    let _: unsafe fn(&FlorestaChain, u32) -> Result<String, String> =
+        FlorestaChain::export_block_index;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::export_misc_state;
+    let _: unsafe fn(&FlorestaChain, u32, u32) -> Result<String, String> =
+        FlorestaChain::get_headers;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<Option<String>, String> =
+        FlorestaChain::next_block_hash;
+    let _: unsafe fn(&FlorestaChain, String, u32) -> Result<String, String> = FlorestaChain::walk;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<i32, String> =
+        FlorestaChain::block_confirmations;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<i32, String> =
+        FlorestaChain::tx_confirmations;
+    let _: unsafe fn(&FlorestaChain, String, u32) -> Result<String, String> =
+        FlorestaChain::utxo_leaf_data;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> =
+        FlorestaChain::export_utxos_psbt_ready;
+    let _: unsafe fn(&FlorestaChain, String, u32) -> Result<u32, String> =
+        FlorestaChain::mark_reserved;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> = FlorestaChain::tx_statuses;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::get_metrics;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> =
+        FlorestaChain::estimated_state_size;
+    let _: unsafe fn(&mut FlorestaChain, usize) = FlorestaChain::set_memory_budget;
+    let _: unsafe fn(&mut FlorestaChain) = FlorestaChain::reset_metrics;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<Option<u32>, String> =
+        FlorestaChain::address_first_seen;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::address_info;
+    let _: unsafe fn(&FlorestaChain, Option<js_sys::Function>) =
+        FlorestaChain::set_script_classifier;
+    let _: unsafe fn(&FlorestaChain, u32) = FlorestaChain::set_wallet_birthday;
+    let _: unsafe fn(&FlorestaChain) -> u32 = FlorestaChain::rescan_from;
+    let _: unsafe fn(&FlorestaChain, String, u32) -> Result<(), String> =
+        FlorestaChain::import_wallet_at_height;
+    let _: unsafe fn(&mut FlorestaChain, String) -> Result<(), String> =
+        FlorestaChain::rescan_block;
+    let _: unsafe fn(&mut FlorestaChain, Option<u32>) -> Result<u32, String> =
+        FlorestaChain::start_rescan;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::rescan_status;
+    let _: unsafe fn(&FlorestaChain) -> Result<u32, String> = FlorestaChain::resume_rescan;
+    let _: unsafe fn(&FlorestaChain, String, String) -> Result<String, String> =
+        FlorestaChain::add_taproot_output;
+    let _: unsafe fn(&FlorestaChain, String, String, u32) -> Result<u32, String> =
+        FlorestaChain::add_multisig_descriptor;
+    let _: unsafe fn(&FlorestaChain, bool) -> Result<String, String> = FlorestaChain::audit_wallet;
+    let _: unsafe fn(&FlorestaChain, u32) -> Result<String, String> =
+        FlorestaChain::next_unused_address;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::derivation_status;
+    let _: unsafe fn(&FlorestaChain, String, String) -> Result<(), String> =
+        FlorestaChain::add_pending_transaction;
+    let _: unsafe fn(&FlorestaChain, String, f64) -> Result<String, String> =
+        FlorestaChain::package_fee_info;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::get_transaction;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::export_spv_proof;
+    let _: unsafe fn(String, String, Option<String>, Option<String>) -> Result<String, String> =
+        FlorestaChain::verify_spv_proof;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<(), String> =
+        FlorestaChain::set_tx_retention;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::get_op_returns;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<(), String> =
+        FlorestaChain::watch_op_return_prefix;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::wallet_stats;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::utxo_set_info;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::verify_proofs_batch;
+    let _: unsafe fn(&FlorestaChain) -> Result<ChainView, String> = FlorestaChain::snapshot_view;
+    let _: unsafe fn(&FlorestaChain, Option<String>) -> Result<String, String> =
+        FlorestaChain::get_wallet_history;
+    let _: unsafe fn(&mut FlorestaChain, bool) = FlorestaChain::set_privacy_analysis;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::privacy_report;
+    let _: unsafe fn(
+        String,
+        u32,
+        String,
+        Option<String>,
+        Option<String>,
+        Option<bool>,
+    ) -> Result<FlorestaChain, String> = FlorestaChain::build_chain_from;
+    let _: unsafe fn(&FlorestaChain) -> u32 = FlorestaChain::show_height;
+    let _: unsafe fn(&FlorestaChain) -> bool = FlorestaChain::show_ibd;
+    let _: unsafe fn(&FlorestaChain) -> String = FlorestaChain::show_network;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::snapshot_provenance;
+    let _: unsafe fn(&FlorestaChain) -> Option<u32> = FlorestaChain::assumed_valid_below;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::assumed_base;
+    let _: unsafe fn(&mut FlorestaChain, Option<js_sys::Function>) =
+        FlorestaChain::set_maturity_callback;
+    let _: unsafe fn(&mut FlorestaChain, u32) = FlorestaChain::set_target_height;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::sync_remaining;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::chain_params;
+    let _: unsafe fn(&FlorestaChain) -> u64 = FlorestaChain::show_difficulty;
+    let _: unsafe fn(&FlorestaChain) -> String = FlorestaChain::show_target;
+    let _: unsafe fn(&FlorestaChain) -> String = FlorestaChain::return_tip;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::get_random_address;
+    let _: unsafe fn(&FlorestaChain) -> String = FlorestaChain::get_our_transactions;
+    let _: unsafe fn(&mut FlorestaChain, String, Option<bool>) -> Result<(), String> =
+        FlorestaChain::accept_block;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::rejection_log;
+    let _: unsafe fn(&mut FlorestaChain, Option<u32>, Option<u32>) =
+        FlorestaChain::set_event_retention;
+    let _: unsafe fn(&mut FlorestaChain, u32) = FlorestaChain::set_rejection_expiry;
+    let _: unsafe fn(&mut FlorestaChain, u32) = FlorestaChain::set_max_reorg_depth;
+    let _: unsafe fn(&mut FlorestaChain, Option<js_sys::Function>) =
+        FlorestaChain::set_deep_reorg_callback;
+    let _: unsafe fn(&mut FlorestaChain, String) -> Result<(), String> = FlorestaChain::force_reorg;
+    let _: unsafe fn(&mut FlorestaChain) -> Result<String, String> =
+        FlorestaChain::drain_new_headers;
+    let _: unsafe fn(&mut FlorestaChain) -> Vec<u8> = FlorestaChain::take_header_buffer;
+    let _: unsafe fn(&mut FlorestaChain, bool) = FlorestaChain::record_accumulator_deltas;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::get_accumulator_delta;
+    let _: unsafe fn(&mut FlorestaChain, bool) = FlorestaChain::record_coinbases;
+    let _: unsafe fn(&FlorestaChain, String) -> Result<String, String> =
+        FlorestaChain::coinbase_info;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::last_block_timings;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> =
+        FlorestaChain::hash_source_discrepancies;
+    let _: unsafe fn(&FlorestaChain, u32) -> Result<String, String> =
+        FlorestaChain::verify_hash_table;
+    let _: unsafe fn(&mut FlorestaChain, js_sys::Uint8Array, String) -> Result<(), String> =
+        FlorestaChain::set_block_hashes;
+    let _: unsafe fn(&mut FlorestaChain, bool) = FlorestaChain::set_ibd;
+    let _: unsafe fn(&mut FlorestaChain) = FlorestaChain::toggle_ibd;
+    let _: unsafe fn(&mut FlorestaChain, Option<js_sys::Function>) =
+        FlorestaChain::set_wallet_reorg_callback;
+    let _: unsafe fn(&FlorestaChain) -> Result<String, String> = FlorestaChain::sync_report;
+    let _: unsafe fn(&mut FlorestaChain, Option<js_sys::Function>) =
+        FlorestaChain::set_ibd_callback;
+
+    // ChainView
+    let _: unsafe fn(&ChainView) -> u32 = ChainView::show_height;
+    let _: unsafe fn(&ChainView) -> String = ChainView::return_tip;
+    let _: unsafe fn(&ChainView) -> bool = ChainView::show_ibd;
+    let _: unsafe fn(&ChainView) -> bool = ChainView::is_tracking_only;
+    let _: unsafe fn(&ChainView) -> String = ChainView::show_roots;
+    let _: unsafe fn(&ChainView) -> u64 = ChainView::show_balance;
+    let _: unsafe fn(&ChainView) -> u64 = ChainView::show_immature_balance;
+    let _: unsafe fn(&ChainView) -> usize = ChainView::show_utxo_count;
+    let _: unsafe fn(&ChainView) -> usize = ChainView::show_tx_count;
+}